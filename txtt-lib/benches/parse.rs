@@ -0,0 +1,57 @@
+//! Benchmarks for parsing templates of increasing size.
+//!
+//! The throughput reported for each size should stay roughly the same,
+//! because parsing is linear in the length of the input.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use txtt_lib::template::Template;
+
+// Sizes of the generated templates in bytes
+const SIZES: [usize; 4] = [64 << 10, 512 << 10, 2 << 20, 8 << 20];
+
+// Repeat a snippet of template source until the template is at least `size` bytes long.
+fn generate(snippet: &str, size: usize) -> String {
+    let mut template = String::from("locale: de-DE\n");
+    while template.len() < size {
+        template.push_str(snippet);
+    }
+    template
+}
+
+fn bench_parse(c: &mut Criterion, name: &str, snippet: &str) {
+    let mut group = c.benchmark_group(name);
+    group.sample_size(10);
+    for size in SIZES {
+        let template = generate(snippet, size);
+        group.throughput(Throughput::Bytes(template.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &template, |b, t| {
+            b.iter(|| Template::parse(t).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn parse_letters(c: &mut Criterion) {
+    bench_parse(
+        c,
+        "parse/letters",
+        "${Anrede} {name:{fullname:Paul Atreides}},\n\
+         {nachricht}\n\
+         Mit freundlichen Grüßen, $Me ($Day, $DayNum. $Month $Year)\n\n",
+    );
+}
+
+fn parse_text(c: &mut Criterion) {
+    bench_parse(
+        c,
+        "parse/text",
+        "Lorem ipsum dolor sit amet, consectetur adipiscing elit: sed do eiusmod.\n",
+    );
+}
+
+// A template without any new lines, so every element is on the same line.
+fn parse_single_line(c: &mut Criterion) {
+    bench_parse(c, "parse/single-line", "{key} text ${option:default} $constant ");
+}
+
+criterion_group!(benches, parse_letters, parse_text, parse_single_line);
+criterion_main!(benches);
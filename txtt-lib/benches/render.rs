@@ -0,0 +1,72 @@
+//! Benchmarks for rendering parsed templates.
+//!
+//! The templates are parsed once outside of the measurement, so only
+//! the resolution of the content and the writing of the output count.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use txtt_lib::template::Template;
+use txtt_lib::{ContentState, VolatileContent};
+
+// Number of times the snippet of each template is repeated
+const REPEATS: [usize; 3] = [16, 256, 4096];
+
+// Every repetition of the snippet contains this many elements.
+const ELEMENTS: usize = 4;
+
+// Generate a template and its content where every repetition of the snippet
+// uses its own identifiers, so the number of required elements grows with it.
+fn distinct(repeats: usize) -> (String, VolatileContent, ContentState) {
+    let mut template = String::new();
+    let mut vc = VolatileContent::new();
+    let mut cs = ContentState::new();
+    for n in 0..repeats {
+        template.push_str(&format!(
+            "Dear {{name{n}}}, ${{greeting{n}:{{fallback{n}:Hi}}}} from $Me{n}.\n"
+        ));
+        vc.map_key(&format!("name{n}"), "Paul");
+        vc.map_choice(&format!("greeting{n}"), "formal");
+        cs.map_option(
+            &format!("greeting{n}"),
+            ("formal".to_owned(), "Good evening".to_owned()),
+        );
+        cs.map_constant(&format!("Me{n}"), "Jessica");
+    }
+    (template, vc, cs)
+}
+
+// Generate a template which repeats the same elements over and over.
+fn repeated(repeats: usize) -> (String, VolatileContent, ContentState) {
+    let template = "Dear {name}, ${greeting:{fallback:Hi}} from $Me.\n".repeat(repeats);
+    let mut vc = VolatileContent::new();
+    vc.map_key("name", "Paul");
+    let mut cs = ContentState::new();
+    cs.map_constant("Me", "Jessica");
+    (template, vc, cs)
+}
+
+fn bench_render(
+    c: &mut Criterion,
+    name: &str,
+    generate: fn(usize) -> (String, VolatileContent, ContentState),
+) {
+    let mut group = c.benchmark_group(name);
+    for repeats in REPEATS {
+        let (source, vc, cs) = generate(repeats);
+        let template = Template::parse(&source).unwrap();
+        group.throughput(Throughput::Elements((repeats * ELEMENTS) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(repeats), &template, |b, t| {
+            b.iter(|| t.render(&vc, &cs).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn render_distinct(c: &mut Criterion) {
+    bench_render(c, "render/distinct", distinct);
+}
+
+fn render_repeated(c: &mut Criterion) {
+    bench_render(c, "render/repeated", repeated);
+}
+
+criterion_group!(benches, render_distinct, render_repeated);
+criterion_main!(benches);
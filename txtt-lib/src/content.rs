@@ -1,15 +1,38 @@
 #[cfg(feature = "dyn")]
 mod meta;
+pub mod ast;
+mod compile;
+mod context;
+pub mod date;
+#[cfg(feature = "env")]
+mod env;
+mod matcher;
+mod mock;
 mod parse;
+mod print;
+pub mod provider;
 mod scan;
-#[cfg(feature = "dyn")]
-use meta::MetaExt;
-pub use parse::UserError;
-use scan::Scanner;
+pub mod source_map;
+pub mod syntax;
+pub mod trace;
+pub use compile::{CompiledTokens, Resolved};
+#[cfg(feature = "serde")]
+pub use compile::CompileError;
+pub use context::{Clock, DynContext, DynamicNames, EnvAccess};
+pub use provider::DynamicProvider;
+pub use matcher::{MatchError, TextMatch};
+pub use parse::{is_ident, Symbol, UserError};
+pub use print::PrintError;
+use parse::DYN_SIGIL;
+use source_map::Origin;
+use syntax::{Span, SyntaxTree};
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use chrono_tz::Tz;
+use log::warn;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use unic_locale::{locale, Locale};
 
 /// Map identifiers to content
@@ -104,16 +127,6 @@ macro_rules! choice {
 #[cfg(test)]
 pub(crate) use choice;
 
-// Type containing ALL required content to  fill out a template
-#[derive(Debug)]
-pub struct FullContent(TypeMap<IdentMap<Content>>);
-
-impl FullContent {
-    pub fn get(&self, idx: ContentIndex) -> &Content {
-        &self.0[&idx.0][&idx.1]
-    }
-}
-
 // Required content
 #[derive(Debug, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -151,6 +164,33 @@ impl RequiredContent {
         };
     }
 
+    /// Get the requirement for the element at `idx` if the element is required.
+    pub fn get(&self, idx: &ContentIndex) -> Option<&ContentRequirement> {
+        self.0.get(&idx.0)?.get(&idx.1)
+    }
+
+    /// Iterate over all required elements and their requirements in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (ContentIndex, &ContentRequirement)> {
+        self.0.iter().flat_map(|(content_type, entries)| {
+            entries
+                .iter()
+                .map(|(ident, content)| (ContentIndex::new(*content_type, ident), content))
+        })
+    }
+
+    /// Iterate over all required elements of the type `content_type`
+    /// and their requirements in no particular order.
+    pub fn entries(
+        &self,
+        content_type: ContentType,
+    ) -> impl Iterator<Item = (&str, &ContentRequirement)> {
+        self.0
+            .get(&content_type)
+            .into_iter()
+            .flatten()
+            .map(|(ident, content)| (ident.as_str(), content))
+    }
+
     pub fn add_constants(&mut self, mut constants: IdentMap<Content>) {
         if let Some(entries) = self.0.get_mut(&ContentType::Constant) {
             // Move every piece of content for each required identifier into
@@ -202,21 +242,17 @@ impl RequiredContent {
         }
     }
 
-    /// Evaluate all dynamic elements in `self`. At the current stage
-    /// this method will evaluate all meta constants.
-    /// This method will not do anything, if dynamic elements are diabled
-    /// through the "dyn" flag.
-    pub fn eval_dyn(&mut self) {
-        #[cfg(feature = "dyn")]
+    /// Evaluate all dynamic elements in `self` using the providers of the
+    /// context `ctx`. The built-in meta constants are only evaluated
+    /// if dynamic elements are enabled through the "dyn" flag.
+    pub fn eval_dyn(&mut self, ctx: &DynContext) {
         if let Some(required) = self.0.get_mut(&ContentType::Constant) {
-            // For each entry, check if the entrie's identifier is the
-            // identifier of a meta element. If it is, replace the current
-            // content with the content of the meta element.
+            // For each entry, check if some provider provides the entrie's
+            // identifier. If one does, replace the current content with
+            // the content of the dynamic element.
             for (ident, content_value) in required {
-                if let Some(meta) = ident.as_meta() {
-                    // Fill in the meta value.
-                    let meta_content = Content::from(meta);
-                    *content_value = ContentRequirement::Literal(meta_content);
+                if let Some(dyn_content) = provider::resolve(ident, ctx) {
+                    *content_value = ContentRequirement::Literal(dyn_content);
                 }
             }
         }
@@ -274,56 +310,40 @@ impl Default for RequiredContent {
     }
 }
 
-impl TryInto<FullContent> for RequiredContent {
-    type Error = FillOutError;
-
-    fn try_into(self) -> Result<FullContent, Self::Error> {
-        fn validate_content(
-            idx: ContentIndex, // ContentIndex of current element; always passing this is kinda a waste
-            content: &ContentRequirement,
-            map: &TypeMap<IdentMap<ContentRequirement>>,
-        ) -> Result<Content, FillOutError> {
-            match content {
-                ContentRequirement::None => Err(FillOutError::MissingElement(idx)),
-                ContentRequirement::Literal(its_lit) => {
-                    let its_lit = its_lit.clone();
-                    match its_lit.is_empty() {
-                        true => Err(FillOutError::EmptyContent(idx)),
-                        false => Ok(its_lit), // <- only `Ok` path is returning a literal
-                    }
-                }
-                ContentRequirement::Default(default_idx) => {
-                    let default_idx = default_idx.clone();
-                    let content_opt = match map.get(&default_idx.0) {
-                        Some(entries) => entries.get(&default_idx.1),
-                        None => return Err(FillOutError::MissingDefaultType(default_idx)),
-                    };
-
-                    match content_opt {
-                        Some(content) => validate_content(default_idx, content, map),
-                        None => Err(FillOutError::MissingDefault(default_idx)),
-                    }
-                }
-            }
-        }
-
-        let mut full_content = HashMap::new();
-
-        for (token_type, entries) in &self.0 {
-            let mut full_type = HashMap::new();
-            for (ident, content) in entries {
-                let idx = ContentIndex::new(*token_type, ident);
+// Get the content given for the constant `ident` or the value of the
+// dynamic element `ident` if dynamic elements are evaluated in the context
+// `eval_dyn`, together with where the content comes from.
+fn given_constant<'a>(
+    ident: &str,
+    content_state: &'a ContentState,
+    eval_dyn: Option<&DynContext>,
+) -> Option<(Cow<'a, str>, Origin)> {
+    // Elements which no provider provides are regular constants,
+    // e.g. environment elements which may not be read.
+    if let Some(content) = eval_dyn.and_then(|ctx| provider::resolve(ident, ctx)) {
+        return Some((Cow::Owned(content), Origin::Dynamic));
+    }
+
+    content_state
+        .constants
+        .get(ident)
+        .map(|content| (Cow::Borrowed(content.as_str()), Origin::ContentState))
+}
 
-                match validate_content(idx, content, &self.0) {
-                    Ok(content) => full_type.insert(ident.clone(), content),
-                    Err(e) => return Err(e),
-                };
-            }
-            full_content.insert(*token_type, full_type);
-        }
+// Get the name of the constant of the content state which is not used if the
+// constant `ident` is a dynamic element, e.g. `Today` for `$Today+14d`.
+fn shadowed_constant<'i>(ident: &'i str, content_state: &ContentState) -> Option<&'i str> {
+    let shadowed = parse::bare_name(ident);
+    content_state.constants.contains_key(shadowed).then_some(shadowed)
+}
 
-        Ok(FullContent(full_content))
-    }
+// Warn that the constant `shadowed` of the content state is not used for the
+// dynamic element `ident`
+fn warn_shadowed(shadowed: &str, ident: &str) {
+    warn!(
+        "The constant {shadowed} of the content state is shadowed by the dynamic element \
+        ${ident}. Write ${DYN_SIGIL}{ident} for the dynamic element to keep them apart."
+    );
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
@@ -334,6 +354,14 @@ impl ContentIndex {
     pub fn new(content_type: ContentType, ident: &str) -> Self {
         Self(content_type, Ident::from(ident))
     }
+
+    pub const fn content_type(&self) -> ContentType {
+        self.0
+    }
+
+    pub fn ident(&self) -> &str {
+        &self.1
+    }
 }
 
 impl std::fmt::Display for ContentIndex {
@@ -342,7 +370,7 @@ impl std::fmt::Display for ContentIndex {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ContentType {
     Key,
@@ -361,78 +389,223 @@ impl std::fmt::Display for ContentType {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ContentTokens {
     tokens: Vec<ContentToken>,
+    #[cfg_attr(feature = "serde", serde(with = "serde_with::rust::display_fromstr"))]
     pub locale: Locale,
+    /// Time zone in which dynamic elements are evaluated. The local
+    /// time zone of the system is used if none is set.
+    pub time_zone: Option<Tz>,
+    // Namespaces and names of dynamic elements the tokens were parsed with,
+    // so they are printed the same way
+    namespaces: Vec<String>,
+    dynamic_names: DynamicNames,
+    // Problems found in the source the tokens were parsed from. They are
+    // serialised, so precompiled tokens report them like parsed ones.
     friendly_errors: Vec<UserError>,
+    // Spans of all elements (including defaults) in pre-order if the tokens
+    // were parsed from source. Empty otherwise. They describe the source,
+    // which is not part of the serialised tokens.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    spans: Vec<Span>,
 }
 
 impl ContentTokens {
     pub fn new() -> Self {
-        Self {
-            tokens: vec![],
-            locale: locale!("en-US"),
-            friendly_errors: vec![],
-        }
+        Self::from(locale!("en-US"))
     }
 
     pub const fn from(locale: Locale) -> Self {
         Self {
             tokens: vec![],
             locale,
+            time_zone: None,
+            namespaces: vec![],
+            dynamic_names: DynamicNames::Bare,
             friendly_errors: vec![],
+            spans: vec![],
+        }
+    }
+
+    // Check if every option of the tokens wraps a key, as the parser makes
+    // sure for tokens parsed from source
+    fn options_wrap_keys(&self) -> bool {
+        fn wraps_keys(token: &ContentToken) -> bool {
+            match token {
+                ContentToken::Key(_, Some(default)) => wraps_keys(default),
+                ContentToken::Option(key_box) => match &**key_box {
+                    ContentToken::Key(_, default) => default.as_deref().is_none_or(wraps_keys),
+                    _ => false,
+                },
+                _ => true,
+            }
+        }
+
+        self.tokens.iter().all(wraps_keys)
+    }
+
+    // Warn about every constant of the content state which is shadowed by a
+    // dynamic element of the tokens, once per dynamic element.
+    fn warn_shadowed(&self, content_state: &ContentState, ctx: &DynContext) {
+        fn constants<'t>(token: &'t ContentToken, idents: &mut HashSet<&'t str>) {
+            match token {
+                ContentToken::Constant(ident) => {
+                    idents.insert(ident);
+                }
+                ContentToken::Key(_, Some(default)) => constants(default, idents),
+                ContentToken::Option(key_box) => constants(key_box, idents),
+                _ => {}
+            }
+        }
+
+        let mut idents = HashSet::new();
+        for token in &self.tokens {
+            constants(token, &mut idents);
+        }
+        for ident in idents {
+            if let Some(shadowed) = shadowed_constant(ident, content_state) {
+                if provider::provides(ident, ctx) {
+                    warn_shadowed(shadowed, ident);
+                }
+            }
         }
     }
 
+    /// Parse the source of a template in which the constants named after one of
+    /// `namespaces` are followed by the name of a member, e.g. `$Counter.invoice`,
+    /// and in which dynamic elements are written using `names`.
+    pub fn parse_with(
+        s: &str,
+        namespaces: Vec<String>,
+        names: DynamicNames,
+    ) -> Result<Self, UserError> {
+        let mut tokens: Self = SyntaxTree::parse_with(s, &namespaces, names)?.into();
+        tokens.namespaces = namespaces;
+        tokens.dynamic_names = names;
+        Ok(tokens)
+    }
+
     // Add a friendly error to the `ContentTokens` instance
     pub fn add_friendly(&mut self, e: UserError) {
         self.friendly_errors.push(e);
     }
 
+    // Add a token which does not come from any source. The tokens
+    // then no longer match a source, so all spans are dropped.
     pub fn push(&mut self, token: ContentToken) {
+        self.spans.clear();
         self.tokens.push(token)
     }
 
-    pub fn len(&self) -> usize {
-        self.tokens.len()
-    }
-
-    // Use the content map to substitue all values in `tokens` until
-    // the entire template has been filled out.
-    pub fn fill_out(&self, content: FullContent) -> String {
-        let mut output = String::new();
-
-        // Try to add the content for `token` to `output`
-        fn fill_out_token(token: &ContentToken, content: &FullContent, output: &mut String) {
-            match token {
-                ContentToken::Text(text) => output.push_str(text),
+    pub fn last_mut(&mut self) -> Option<&mut ContentToken> {
+        self.tokens.last_mut()
+    }
+
+    // Add a token parsed from source together with the spans of the
+    // token and its defaults in pre-order.
+    pub(crate) fn push_spanned(&mut self, token: ContentToken, spans: Vec<Span>) {
+        self.tokens.push(token);
+        self.spans.extend(spans);
+    }
+
+    // Substitute every element whose content is given and keep all other
+    // elements, substituting the given content in their defaults instead.
+    // Dynamic elements provided in the context `eval_dyn` are kept, because
+    // their content is only known once the template is filled out.
+    // Adjacent text is merged, just like the parser does.
+    pub fn partially_fill(
+        &self,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+        eval_dyn: Option<&DynContext>,
+    ) -> Result<Self, FillOutError> {
+        // Get the content given for `token`. Empty content counts as missing,
+        // so empty entries of a draft do not remove any elements.
+        fn given<'a>(
+            token: &ContentToken,
+            volatile_content: &'a VolatileContent,
+            content_state: &'a ContentState,
+            eval_dyn: Option<&DynContext>,
+        ) -> Option<&'a Content> {
+            let content = match token {
+                ContentToken::Text(_) => None,
                 ContentToken::Constant(ident) => {
-                    let content = content.get(ContentIndex::new(ContentType::Constant, ident));
-                    output.push_str(content);
-                }
-                ContentToken::Key(ident, _) => {
-                    output.push_str(content.get(ContentIndex::new(ContentType::Key, ident)));
-                }
-                ContentToken::Option(key_box) => {
-                    let (ident, _) = match &**key_box {
-                        ContentToken::Key(ident, default_box) => (ident, default_box),
-                        _ => panic!(
-                            "ContentToken::Option did not contain a ContentToken::Key instance. \
-                            `parse::option` should not allow this!"
-                        ),
-                    };
-                    output.push_str(
-                        content.get(ContentIndex::new(ContentType::Option, ident.as_ref())),
-                    );
+                    if eval_dyn.is_some_and(|ctx| provider::provides(ident, ctx)) {
+                        return None;
+                    }
+                    content_state.constants.get(ident)
                 }
+                ContentToken::Key(ident, _) => volatile_content.keys.get(ident),
+                ContentToken::Option(key_box) => match &**key_box {
+                    ContentToken::Key(ident, _) => volatile_content
+                        .choices
+                        .get(ident)
+                        .and_then(|choice| content_state.options.get(ident)?.get(choice)),
+                    // Options without a key are rejected before filling.
+                    _ => None,
+                },
+            };
+            content.filter(|content| !content.is_empty())
+        }
+
+        // Keep `token` but fill its defaults
+        fn keep(
+            token: &ContentToken,
+            volatile_content: &VolatileContent,
+            content_state: &ContentState,
+            eval_dyn: Option<&DynContext>,
+        ) -> ContentToken {
+            match token {
+                ContentToken::Key(ident, default) => ContentToken::Key(
+                    ident.clone(),
+                    default.as_ref().map(|default| {
+                        Box::new(fill(default, volatile_content, content_state, eval_dyn))
+                    }),
+                ),
+                ContentToken::Option(key_box) => ContentToken::Option(Box::new(keep(
+                    key_box,
+                    volatile_content,
+                    content_state,
+                    eval_dyn,
+                ))),
+                _ => token.clone(),
+            }
+        }
+
+        fn fill(
+            token: &ContentToken,
+            volatile_content: &VolatileContent,
+            content_state: &ContentState,
+            eval_dyn: Option<&DynContext>,
+        ) -> ContentToken {
+            match given(token, volatile_content, content_state, eval_dyn) {
+                Some(content) => ContentToken::Text(content.clone()),
+                None => keep(token, volatile_content, content_state, eval_dyn),
             }
         }
 
+        if !self.options_wrap_keys() {
+            return Err(FillOutError::OptionWithoutKey);
+        }
+        if let Some(ctx) = eval_dyn {
+            self.warn_shadowed(content_state, ctx);
+        }
+        let mut filled = Self::from(self.locale.clone());
+        filled.namespaces = self.namespaces.clone();
+        filled.dynamic_names = self.dynamic_names;
+        filled.time_zone = self.time_zone;
         for token in &self.tokens {
-            fill_out_token(token, &content, &mut output);
+            match (
+                filled.tokens.last_mut(),
+                fill(token, volatile_content, content_state, eval_dyn),
+            ) {
+                (Some(ContentToken::Text(last)), ContentToken::Text(text)) => last.push_str(&text),
+                (_, token) => filled.tokens.push(token),
+            }
         }
 
-        output
+        Ok(filled)
     }
 
     // Return a half-empty `RequiredContent` instance containing the identifiers and
@@ -494,13 +667,23 @@ impl ContentTokens {
     }
 }
 
+// Two lists of tokens are equal if they contain the same tokens and have the
+// same locale and time zone. Friendly errors are ignored because they only
+// describe how the tokens were parsed.
+impl PartialEq for ContentTokens {
+    fn eq(&self, other: &Self) -> bool {
+        self.tokens == other.tokens
+            && self.locale == other.locale
+            && self.time_zone == other.time_zone
+    }
+}
+
 impl std::str::FromStr for ContentTokens {
     type Err = UserError;
 
     // Attempt to parse the given string into a `ContentTokens` instance
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut scanner = Scanner::new(s);
-        parse::template(&mut scanner)
+        Self::parse_with(s, vec![], DynamicNames::default())
     }
 }
 
@@ -515,9 +698,12 @@ pub enum FillOutError {
     MissingDefaultType(ContentIndex),
     #[error("The identifier of a requested default {0} does not exitst")]
     MissingDefault(ContentIndex),
+    #[error("An option does not wrap a key")]
+    OptionWithoutKey,
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ContentToken {
     Text(String),
     Key(Ident, Option<Box<ContentToken>>),
@@ -627,6 +813,15 @@ mod tests {
         }
     }
 
+    #[test]
+    fn options_without_keys_are_not_filled_partially() {
+        let mut tokens = ContentTokens::new();
+        let default = ContentToken::Option(Box::new(ContentToken::Constant("Me".into())));
+        tokens.push(ContentToken::Key("name".into(), Some(Box::new(default))));
+        let result = tokens.partially_fill(&VolatileContent::new(), &ContentState::new(), None);
+        assert!(matches!(result, Err(FillOutError::OptionWithoutKey)));
+    }
+
     #[test]
     fn templates_are_parsed_correctly() {
         // Lenghts of literal text and idents in decreased so tests are more consice
@@ -710,4 +905,17 @@ mod tests {
             map
         }
     }
+
+    #[cfg(feature = "binary")]
+    #[test]
+    fn friendly_errors_are_serialised() {
+        let tokens: ContentTokens = "locale: nope\n{name}".parse().unwrap();
+        let decoded: ContentTokens =
+            bincode::deserialize(&bincode::serialize(&tokens).unwrap()).unwrap();
+        let messages = |tokens: &ContentTokens| -> Vec<String> {
+            tokens.friendly_errors.iter().map(ToString::to_string).collect()
+        };
+        assert!(!messages(&tokens).is_empty());
+        assert_eq!(messages(&decoded), messages(&tokens));
+    }
 }
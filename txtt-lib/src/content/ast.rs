@@ -0,0 +1,272 @@
+//! Read-only view of the elements of a template.
+//!
+//! The view is independent of the internal representation of
+//! templates, so analyses can be written against it without
+//! depending on how templates are stored. Elements are either
+//! iterated over directly using `Template::elements` or traversed
+//! (including all defaults) with a `Visitor`.
+use super::syntax::Span;
+use super::{ContentIndex, ContentToken, ContentTokens, ContentType};
+
+/// The different kinds of elements a template is made of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ElementKind {
+    Text,
+    Key,
+    Option,
+    Constant,
+}
+
+impl ElementKind {
+    /// The type of content the element needs to be filled out. Text does not need any.
+    pub const fn content_type(self) -> Option<ContentType> {
+        match self {
+            Self::Text => None,
+            Self::Key => Some(ContentType::Key),
+            Self::Option => Some(ContentType::Option),
+            Self::Constant => Some(ContentType::Constant),
+        }
+    }
+}
+
+/// A single element of a template, which is either at the
+/// top level of the template or the default of another element.
+#[derive(Debug, Clone, Copy)]
+pub struct Element<'a> {
+    token: &'a ContentToken,
+    // Spans of all elements of the template in pre-order and the
+    // position of this element in that order.
+    spans: &'a [Span],
+    pos: usize,
+}
+
+impl<'a> Element<'a> {
+    pub const fn kind(&self) -> ElementKind {
+        match self.token {
+            ContentToken::Text(_) => ElementKind::Text,
+            ContentToken::Key(..) => ElementKind::Key,
+            ContentToken::Option(_) => ElementKind::Option,
+            ContentToken::Constant(_) => ElementKind::Constant,
+        }
+    }
+
+    /// The literal of a text element.
+    pub fn text(&self) -> Option<&'a str> {
+        match self.token {
+            ContentToken::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    /// The identifier of a key, option or constant.
+    pub fn ident(&self) -> Option<&'a str> {
+        match self.token {
+            ContentToken::Text(_) => None,
+            ContentToken::Key(ident, _) | ContentToken::Constant(ident) => Some(ident),
+            ContentToken::Option(key) => match &**key {
+                ContentToken::Key(ident, _) => Some(ident),
+                _ => None,
+            },
+        }
+    }
+
+    /// The index under which content for this element is looked up.
+    pub fn index(&self) -> Option<ContentIndex> {
+        let content_type = self.kind().content_type()?;
+        Some(ContentIndex::new(content_type, self.ident()?))
+    }
+
+    /// The default of a key or an option.
+    pub fn default(&self) -> Option<Element<'a>> {
+        let default = match self.token {
+            ContentToken::Key(_, default) => default.as_deref()?,
+            ContentToken::Option(key) => match &**key {
+                ContentToken::Key(_, default) => default.as_deref()?,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        // In pre-order, the default directly follows its element.
+        Some(Element {
+            token: default,
+            spans: self.spans,
+            pos: self.pos + 1,
+        })
+    }
+
+    /// The range of bytes of the element in the source of the template.
+    /// `None` if the template was not parsed from source.
+    pub fn span(&self) -> Option<Span> {
+        self.spans.get(self.pos).copied()
+    }
+
+    // Number of elements this element is made of (itself and all of its defaults)
+    fn size(&self) -> usize {
+        1 + self.default().map_or(0, |default| default.size())
+    }
+}
+
+/// Iterator over the top-level elements of a template.
+#[derive(Debug, Clone)]
+pub struct Elements<'a> {
+    tokens: std::slice::Iter<'a, ContentToken>,
+    spans: &'a [Span],
+    pos: usize,
+}
+
+impl<'a> Iterator for Elements<'a> {
+    type Item = Element<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let element = Element {
+            token: self.tokens.next()?,
+            spans: self.spans,
+            pos: self.pos,
+        };
+        self.pos += element.size();
+        Some(element)
+    }
+}
+
+impl ContentTokens {
+    pub fn elements(&self) -> Elements<'_> {
+        Elements {
+            tokens: self.tokens.iter(),
+            spans: &self.spans,
+            pos: 0,
+        }
+    }
+}
+
+/// Traversal of all elements in a template.
+///
+/// Each method is called for the elements of the corresponding kind.
+/// By default, the methods for keys and options continue with the
+/// default of the element. When overriding them, call `walk_default`
+/// to keep visiting the defaults.
+pub trait Visitor<'a> {
+    fn visit_element(&mut self, element: Element<'a>) {
+        walk_element(self, element);
+    }
+
+    fn visit_text(&mut self, _element: Element<'a>) {}
+
+    fn visit_key(&mut self, element: Element<'a>) {
+        walk_default(self, element);
+    }
+
+    fn visit_option(&mut self, element: Element<'a>) {
+        walk_default(self, element);
+    }
+
+    fn visit_constant(&mut self, _element: Element<'a>) {}
+}
+
+/// Visit all elements in `elements` and their defaults.
+pub fn walk<'a, V>(visitor: &mut V, elements: Elements<'a>)
+where
+    V: Visitor<'a> + ?Sized,
+{
+    for element in elements {
+        visitor.visit_element(element);
+    }
+}
+
+/// Call the method of `visitor` for the kind of `element`.
+pub fn walk_element<'a, V>(visitor: &mut V, element: Element<'a>)
+where
+    V: Visitor<'a> + ?Sized,
+{
+    match element.kind() {
+        ElementKind::Text => visitor.visit_text(element),
+        ElementKind::Key => visitor.visit_key(element),
+        ElementKind::Option => visitor.visit_option(element),
+        ElementKind::Constant => visitor.visit_constant(element),
+    }
+}
+
+/// Visit the default of `element` if it has one.
+pub fn walk_default<'a, V>(visitor: &mut V, element: Element<'a>)
+where
+    V: Visitor<'a> + ?Sized,
+{
+    if let Some(default) = element.default() {
+        visitor.visit_element(default);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::template::Template;
+
+    // Collects the identifiers of all constants.
+    #[derive(Default)]
+    struct Constants<'a>(Vec<&'a str>);
+
+    impl<'a> Visitor<'a> for Constants<'a> {
+        fn visit_constant(&mut self, element: Element<'a>) {
+            self.0.extend(element.ident());
+        }
+    }
+
+    #[test]
+    fn visitors_reach_defaults() {
+        let template = Template::parse("$Me {key:${opt:$Fallback}} $Other").unwrap();
+        let mut constants = Constants::default();
+        walk(&mut constants, template.elements());
+        assert_eq!(constants.0, vec!["Me", "Fallback", "Other"]);
+    }
+
+    #[test]
+    fn elements_have_kinds_idents_and_defaults() {
+        let template = Template::parse("Hi {name:${greeting:Paul}}").unwrap();
+        let elements: Vec<Element> = template.elements().collect();
+        assert_eq!(elements.len(), 2);
+
+        assert_eq!(elements[0].kind(), ElementKind::Text);
+        assert_eq!(elements[0].text(), Some("Hi "));
+        assert_eq!(elements[0].ident(), None);
+        assert_eq!(elements[0].index(), None);
+
+        let key = elements[1];
+        assert_eq!(key.kind(), ElementKind::Key);
+        assert_eq!(key.index(), Some(ContentIndex::new(ContentType::Key, "name")));
+        let option = key.default().unwrap();
+        assert_eq!(option.kind(), ElementKind::Option);
+        assert_eq!(option.ident(), Some("greeting"));
+        let text = option.default().unwrap();
+        assert_eq!(text.text(), Some("Paul"));
+        assert!(text.default().is_none());
+    }
+
+    #[test]
+    fn spans_point_into_the_source() {
+        let source = "locale: de-DE\n{a:{b:x}} $c ${d} e";
+        let template = Template::parse(source).unwrap();
+
+        // Collects the source of every element.
+        struct Sources<'s>(&'s str, Vec<&'s str>);
+        impl<'a, 's> Visitor<'a> for Sources<'s> {
+            fn visit_element(&mut self, element: Element<'a>) {
+                let span = element.span().unwrap();
+                self.1.push(&self.0[span.start..span.end]);
+                walk_element(self, element);
+            }
+        }
+
+        let mut sources = Sources(source, vec![]);
+        walk(&mut sources, template.elements());
+        assert_eq!(
+            sources.1,
+            vec!["{a:{b:x}}", "{b:x}", "x", " ", "$c", " ", "${d}", " e"]
+        );
+    }
+
+    #[test]
+    fn tokens_without_source_have_no_spans() {
+        let mut tokens = ContentTokens::new();
+        tokens.push(ContentToken::Key("name".into(), None));
+        assert!(tokens.elements().all(|element| element.span().is_none()));
+    }
+}
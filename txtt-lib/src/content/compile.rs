@@ -0,0 +1,490 @@
+//! Templates compiled into dense slots.
+//!
+//! Every required element gets its own slot number when a template is
+//! compiled, and defaults refer to other slots by their number. Rendering
+//! then walks arrays instead of looking up `ContentIndex`es in nested
+//! hash maps, and no content is copied unless a dynamic element creates it.
+use super::provider;
+use super::source_map::Origin;
+use super::trace::{Candidate, Resolution, Trace};
+use super::{
+    given_constant, shadowed_constant, warn_shadowed, DynContext, ContentIndex, ContentRequirement,
+    ContentState, ContentToken, ContentTokens, ContentType, FillOutError, RequiredContent,
+    VolatileContent,
+};
+use std::borrow::Cow;
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::sync::Once;
+
+// Piece of the output: either text or the content of a slot
+#[derive(Debug, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    Slot(usize),
+}
+
+// Requirement of a slot where defaults are given as slot numbers
+#[derive(Debug, PartialEq, Eq)]
+enum SlotRequirement {
+    Literal(String),
+    Default(usize),
+    None,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Slot {
+    idx: ContentIndex,
+    requirement: SlotRequirement,
+}
+
+// Content given for a slot together with its origin
+struct Given<'a> {
+    content: Cow<'a, str>,
+    origin: Origin,
+}
+
+// Content given for the slots, which is only looked up once a slot is
+// resolved, so dynamic elements are only evaluated if their content is
+// used, e.g. a counter in the default of a key which is given.
+struct GivenContent<'a, 'c> {
+    volatile_content: &'a VolatileContent,
+    content_state: &'a ContentState,
+    eval_dyn: Option<&'c DynContext<'c>>,
+    slots: Vec<OnceCell<Option<Given<'a>>>>,
+}
+
+/// Information on an element whose content is written.
+#[derive(Debug, Clone, Copy)]
+pub struct Resolved<'b> {
+    /// The element of the template at this place.
+    pub element: &'b ContentIndex,
+    /// The element whose content is used. This is not `element`
+    /// if the content comes from a default.
+    pub source: &'b ContentIndex,
+    pub origin: Origin,
+}
+
+/// Reasons why tokens and requirements which do not belong together,
+/// e.g. because they were decoded from corrupted data, can not be compiled.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum CompileError {
+    #[error("The default {0} is not an element of the template")]
+    UnknownDefault(ContentIndex),
+    #[error("An option does not wrap a key")]
+    OptionWithoutKey,
+}
+
+/// Tokens and requirements of a template compiled into slots.
+#[derive(Debug)]
+pub struct CompiledTokens {
+    slots: Vec<Slot>,
+    pieces: Vec<Piece>,
+    // Whether the constant of a slot was reported to be shadowed by a dynamic
+    // element already, so the warning is not repeated every time it is rendered
+    shadow_warnings: Vec<Once>,
+}
+
+impl CompiledTokens {
+    /// Compile `tokens` using the requirements drafted from them.
+    /// Slots are numbered in the order the elements first appear in.
+    /// Fails if a requirement refers to a default which is not in `tokens`.
+    pub fn new(tokens: &ContentTokens, required: &RequiredContent) -> Result<Self, CompileError> {
+        // Get the number of the slot for the element `token`, creating it if needed.
+        // The defaults of `token` get their slots as well.
+        fn intern(
+            token: &ContentToken,
+            numbers: &mut HashMap<ContentIndex, usize>,
+        ) -> Result<Option<usize>, CompileError> {
+            let (idx, default) = match token {
+                ContentToken::Text(_) => return Ok(None),
+                ContentToken::Constant(ident) => {
+                    (ContentIndex::new(ContentType::Constant, ident), None)
+                }
+                ContentToken::Key(ident, default) => {
+                    (ContentIndex::new(ContentType::Key, ident), default.as_ref())
+                }
+                ContentToken::Option(key_box) => match &**key_box {
+                    ContentToken::Key(ident, default) => (
+                        ContentIndex::new(ContentType::Option, ident),
+                        default.as_ref(),
+                    ),
+                    _ => return Err(CompileError::OptionWithoutKey),
+                },
+            };
+            let next = numbers.len();
+            let slot = *numbers.entry(idx).or_insert(next);
+            if let Some(default) = default {
+                intern(default, numbers)?;
+            }
+            Ok(Some(slot))
+        }
+
+        let mut numbers = HashMap::new();
+        let pieces = tokens
+            .tokens
+            .iter()
+            .map(|token| match token {
+                ContentToken::Text(text) => Ok(Piece::Text(text.clone())),
+                _ => intern(token, &mut numbers).map(|slot| Piece::Slot(slot.unwrap())),
+            })
+            .collect::<Result<_, _>>()?;
+
+        let mut indices: Vec<(&ContentIndex, &usize)> = numbers.iter().collect();
+        indices.sort_unstable_by_key(|(_, slot)| **slot);
+        let slots: Vec<Slot> = indices
+            .into_iter()
+            .map(|(idx, _)| {
+                let requirement = match required.get(idx) {
+                    Some(ContentRequirement::Literal(content)) => {
+                        SlotRequirement::Literal(content.clone())
+                    }
+                    Some(ContentRequirement::Default(default)) => match numbers.get(default) {
+                        Some(slot) => SlotRequirement::Default(*slot),
+                        None => return Err(CompileError::UnknownDefault(default.clone())),
+                    },
+                    Some(ContentRequirement::None) | None => SlotRequirement::None,
+                };
+                Ok(Slot {
+                    idx: idx.clone(),
+                    requirement,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        let shadow_warnings = slots.iter().map(|_| Once::new()).collect();
+        Ok(Self {
+            slots,
+            pieces,
+            shadow_warnings,
+        })
+    }
+
+    /// Fill out the template and pass every piece of the output to `output` in order,
+    /// together with information on the element it was written for, if any.
+    /// Content from `volatile_content` and `content_state` takes precedence over the
+    /// defaults in the template and dynamic elements are evaluated in the context
+    /// `eval_dyn` if it is set.
+    /// Unless `stream` is set, all elements which are written are resolved before the
+    /// first piece is written and all other elements, e.g. the defaults of given keys,
+    /// have to have content as well. Dynamic elements are only evaluated if they are
+    /// written, so the content of other dynamic elements is not checked.
+    pub fn fill_out_with<E: From<FillOutError>>(
+        &self,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+        eval_dyn: Option<&DynContext>,
+        stream: bool,
+        mut output: impl FnMut(&str, Option<Resolved>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let given = GivenContent::new(volatile_content, content_state, eval_dyn, self.slots.len());
+        if !stream {
+            for piece in &self.pieces {
+                if let Piece::Slot(slot) = piece {
+                    self.resolve(&given, *slot)?;
+                }
+            }
+            for slot in 0..self.slots.len() {
+                self.check(&given, slot)?;
+            }
+        }
+
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => output(text, None)?,
+                Piece::Slot(slot) => {
+                    let (content, resolved) = self.resolve(&given, *slot)?;
+                    output(content, Some(resolved))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Trace how the content of every element is found, without failing on missing
+    /// or empty content. The arguments are the same as for `fill_out_with`.
+    pub fn trace(
+        &self,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+        eval_dyn: Option<&DynContext>,
+    ) -> Trace {
+        let given = GivenContent::new(volatile_content, content_state, eval_dyn, self.slots.len());
+        let resolutions = (0..self.slots.len())
+            .map(|slot| self.trace_slot(&given, content_state, slot))
+            .collect();
+        Trace::new(resolutions)
+    }
+
+    // Follow the defaults of `slot` like `resolve` does, but keep going after
+    // the content was found to collect the content it shadows.
+    fn trace_slot(
+        &self,
+        given: &GivenContent,
+        content_state: &ContentState,
+        slot: usize,
+    ) -> Resolution {
+        let mut chain = vec![];
+        let mut used: Option<Candidate> = None;
+        let mut shadowed = vec![];
+        let mut visited = vec![false; self.slots.len()];
+        let mut current = Some(slot);
+        while let Some(at) = current.filter(|at| !visited[*at]) {
+            visited[at] = true;
+            let Slot { idx, requirement } = &self.slots[at];
+            if used.is_none() {
+                chain.push(idx.clone());
+            }
+
+            let candidate = |content: &str, origin| Candidate {
+                element: idx.clone(),
+                content: content.to_owned(),
+                origin,
+            };
+            let given = self.given(given, at);
+            // A dynamic element shadows a constant of the same name.
+            let constant = given
+                .filter(|given| given.origin == Origin::Dynamic)
+                .and_then(|_| content_state.constants.get(idx.ident()));
+            let literal = match requirement {
+                SlotRequirement::Literal(content) => Some(content),
+                _ => None,
+            };
+            let candidates = [
+                given.map(|given| candidate(&given.content, given.origin)),
+                constant.map(|content| candidate(content, Origin::ContentState)),
+                literal.map(|content| candidate(content, Origin::Default)),
+            ];
+            for candidate in candidates.into_iter().flatten() {
+                match used {
+                    None => used = Some(candidate),
+                    Some(_) => shadowed.push(candidate),
+                }
+            }
+
+            current = match requirement {
+                SlotRequirement::Default(default) => Some(*default),
+                _ => None,
+            };
+        }
+
+        Resolution {
+            element: self.slots[slot].idx.clone(),
+            chain,
+            used,
+            shadowed,
+        }
+    }
+
+    // Find the content given for `slot`. A constant of the content state
+    // which is shadowed by a dynamic element is only reported the first time.
+    fn given<'g, 'a>(
+        &self,
+        given: &'g GivenContent<'a, '_>,
+        slot: usize,
+    ) -> Option<&'g Given<'a>> {
+        let GivenContent {
+            volatile_content,
+            content_state,
+            eval_dyn,
+            slots,
+        } = given;
+        let borrowed = |content: &'a String| Cow::Borrowed(content.as_str());
+        slots[slot]
+            .get_or_init(|| {
+                let ident = self.slots[slot].idx.ident();
+                let (content, origin) = match self.slots[slot].idx.content_type() {
+                    ContentType::Constant => {
+                        let (content, origin) = given_constant(ident, content_state, *eval_dyn)?;
+                        let shadowed = shadowed_constant(ident, content_state);
+                        if let (Origin::Dynamic, Some(shadowed)) = (origin, shadowed) {
+                            self.shadow_warnings[slot]
+                                .call_once(|| warn_shadowed(shadowed, ident));
+                        }
+                        (content, origin)
+                    }
+                    ContentType::Option => {
+                        let choice = volatile_content.choices.get(ident)?;
+                        let content = content_state.options.get(ident)?.get(choice)?;
+                        (borrowed(content), Origin::ContentState)
+                    }
+                    ContentType::Key => {
+                        let content = volatile_content.keys.get(ident)?;
+                        (borrowed(content), Origin::UserInput)
+                    }
+                };
+                Some(Given { content, origin })
+            })
+            .as_ref()
+    }
+
+    // Follow the chain of defaults starting at `slot` until some content is found.
+    fn resolve<'b>(
+        &'b self,
+        given: &'b GivenContent,
+        slot: usize,
+    ) -> Result<(&'b str, Resolved<'b>), FillOutError> {
+        let mut current = slot;
+        // A chain of defaults can only be longer than the number of
+        // slots if it is a cycle (e.g. `{a:{a}}`) without any content.
+        for _ in 0..=self.slots.len() {
+            let requirement = &self.slots[current].requirement;
+            let (content, origin) = match (self.given(given, current), requirement) {
+                (Some(given), _) => (given.content.as_ref(), given.origin),
+                (None, SlotRequirement::Literal(content)) => (content.as_str(), Origin::Default),
+                (None, SlotRequirement::Default(default)) => {
+                    current = *default;
+                    continue;
+                }
+                (None, SlotRequirement::None) => break,
+            };
+            let resolved = Resolved {
+                element: &self.slots[slot].idx,
+                source: &self.slots[current].idx,
+                origin,
+            };
+            return match content.is_empty() {
+                true => Err(FillOutError::EmptyContent(resolved.source.clone())),
+                false => Ok((content, resolved)),
+            };
+        }
+
+        Err(FillOutError::MissingElement(
+            self.slots[current].idx.clone(),
+        ))
+    }
+
+    // Check that the chain of defaults starting at `slot` leads to content like
+    // `resolve` does, but without evaluating dynamic elements which are not
+    // evaluated yet. Their content is unknown, so it is never empty.
+    fn check(&self, given: &GivenContent, slot: usize) -> Result<(), FillOutError> {
+        let mut current = slot;
+        for _ in 0..=self.slots.len() {
+            let requirement = &self.slots[current].requirement;
+            let content = match (self.available(given, current), requirement) {
+                (Some(content), _) => content,
+                (None, SlotRequirement::Literal(content)) => Some(content.as_str()),
+                (None, SlotRequirement::Default(default)) => {
+                    current = *default;
+                    continue;
+                }
+                (None, SlotRequirement::None) => break,
+            };
+            return match content.is_some_and(str::is_empty) {
+                true => Err(FillOutError::EmptyContent(self.slots[current].idx.clone())),
+                false => Ok(()),
+            };
+        }
+
+        Err(FillOutError::MissingElement(
+            self.slots[current].idx.clone(),
+        ))
+    }
+
+    // Find the content given for `slot` like `given` does, but only if it is
+    // known without evaluating a dynamic element. The content of dynamic elements
+    // which are not evaluated yet is `Some(None)`.
+    fn available<'g>(&self, given: &'g GivenContent, slot: usize) -> Option<Option<&'g str>> {
+        let evaluated = given.slots[slot].get().is_some();
+        let idx = &self.slots[slot].idx;
+        let dynamic = idx.content_type() == ContentType::Constant
+            && given
+                .eval_dyn
+                .is_some_and(|ctx| provider::provides(idx.ident(), ctx));
+        match evaluated || !dynamic {
+            true => self.given(given, slot).map(|given| Some(given.content.as_ref())),
+            false => Some(None),
+        }
+    }
+}
+
+impl<'a, 'c> GivenContent<'a, 'c> {
+    fn new(
+        volatile_content: &'a VolatileContent,
+        content_state: &'a ContentState,
+        eval_dyn: Option<&'c DynContext<'c>>,
+        slots: usize,
+    ) -> Self {
+        Self {
+            volatile_content,
+            content_state,
+            eval_dyn,
+            slots: (0..slots).map(|_| OnceCell::new()).collect(),
+        }
+    }
+}
+
+// Compiled tokens are equal if their slots and pieces are, no matter
+// which warnings they reported.
+impl PartialEq for CompiledTokens {
+    fn eq(&self, other: &Self) -> bool {
+        self.slots == other.slots && self.pieces == other.pieces
+    }
+}
+
+impl Eq for CompiledTokens {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(template: &str) -> CompiledTokens {
+        let tokens: ContentTokens = template.parse().unwrap();
+        CompiledTokens::new(&tokens, &tokens.draft()).unwrap()
+    }
+
+    #[test]
+    fn elements_are_interned_into_slots() {
+        let compiled = compile("{name} ${opt:{name}} $Me {name}");
+        let idents: Vec<&str> = compiled.slots.iter().map(|slot| slot.idx.ident()).collect();
+        assert_eq!(idents, vec!["name", "opt", "Me"]);
+        assert_eq!(compiled.slots[1].requirement, SlotRequirement::Default(0));
+        assert_eq!(
+            compiled.pieces,
+            vec![
+                Piece::Slot(0),
+                Piece::Text(" ".into()),
+                Piece::Slot(1),
+                Piece::Text(" ".into()),
+                Piece::Slot(2),
+                Piece::Text(" ".into()),
+                Piece::Slot(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn cycles_without_content_are_missing() {
+        let compiled = compile("{a:{a}}");
+        let result = compiled.fill_out_with(
+            &VolatileContent::new(),
+            &ContentState::new(),
+            None,
+            false,
+            |_, _| Ok::<(), FillOutError>(()),
+        );
+        assert!(matches!(result, Err(FillOutError::MissingElement(_))));
+    }
+
+    #[test]
+    fn defaults_which_are_not_written_have_to_be_given() {
+        let compiled = compile("{a:{b}}");
+        let mut vc = VolatileContent::new();
+        vc.map_key("a", "A");
+        let fill_out = |vc: &VolatileContent, stream| {
+            compiled.fill_out_with(vc, &ContentState::new(), None, stream, |_, _| {
+                Ok::<(), FillOutError>(())
+            })
+        };
+        let b = ContentIndex::new(ContentType::Key, "b");
+        let result = fill_out(&vc, false);
+        assert!(matches!(result, Err(FillOutError::MissingElement(idx)) if idx == b));
+        // Streamed output only checks the elements which are written.
+        assert!(fill_out(&vc, true).is_ok());
+
+        vc.map_key("b", "");
+        let result = fill_out(&vc, false);
+        assert!(matches!(result, Err(FillOutError::EmptyContent(idx)) if idx == b));
+    }
+}
@@ -0,0 +1,107 @@
+//! Context in which dynamic elements are evaluated.
+use chrono::{DateTime, Utc};
+use super::DynamicProvider;
+use chrono_tz::Tz;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use unic_locale::Locale;
+
+/// Source of the current time for dynamic elements.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Clock {
+    /// The time of the system when the template is filled out.
+    #[default]
+    System,
+    /// A fixed point in time, e.g. to fill out a template exactly like it was filled out before.
+    Fixed(DateTime<Utc>),
+}
+
+impl Clock {
+    pub fn now(&self) -> DateTime<Utc> {
+        match self {
+            Self::System => Utc::now(),
+            Self::Fixed(now) => *now,
+        }
+    }
+}
+
+/// How dynamic elements are told apart from regular constants.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum DynamicNames {
+    /// Dynamic elements are written with or without their sigil, e.g. `$@Year`
+    /// or `$Year`, like in templates written before the sigil existed. A dynamic
+    /// element written without its sigil shadows a constant of the same name.
+    #[default]
+    Bare,
+    /// Only elements written with the sigil (e.g. `$@Year`) are dynamic,
+    /// so `$Year` is a regular constant.
+    Namespaced,
+}
+
+/// What environment elements (e.g. `$Env.USER` or `$Hostname`) may read.
+/// Templates can only read the environment elements which are allowed,
+/// all other environment elements are treated as regular constants.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EnvAccess {
+    /// Identifiers of the environment elements templates may read, e.g. `Env.USER`.
+    pub allowed: Vec<String>,
+    /// Path of the file the template was read from for `$TemplateName` and `$TemplatePath`
+    pub template_path: Option<PathBuf>,
+}
+
+impl EnvAccess {
+    pub fn allows(&self, ident: &str) -> bool {
+        self.allowed.iter().any(|allowed| allowed == ident)
+    }
+}
+
+// Access of contexts which are not given any
+static NO_ENV: EnvAccess = EnvAccess {
+    allowed: Vec::new(),
+    template_path: None,
+};
+
+/// Everything dynamic elements depend on. All dynamic elements of
+/// a template are evaluated in the same context, so they agree on
+/// the current time even if filling out the template takes a while.
+#[derive(Debug, Clone, Copy)]
+pub struct DynContext<'a> {
+    /// Locale in which names of months and days are written
+    pub locale: &'a Locale,
+    /// Time zone in which dates and times are written. The local
+    /// time zone of the system is used if it is not set.
+    pub time_zone: Option<Tz>,
+    pub now: DateTime<Utc>,
+    /// What environment elements may read. Nothing is allowed by default.
+    pub env: &'a EnvAccess,
+    /// Providers of dynamic elements which are asked after the built-in ones.
+    pub providers: &'a [Box<dyn DynamicProvider>],
+    pub names: DynamicNames,
+}
+
+impl<'a> DynContext<'a> {
+    pub fn new(locale: &'a Locale, time_zone: Option<Tz>, clock: &Clock) -> Self {
+        Self {
+            locale,
+            time_zone,
+            now: clock.now(),
+            env: &NO_ENV,
+            providers: &[],
+            names: DynamicNames::default(),
+        }
+    }
+
+    pub fn with_env(self, env: &'a EnvAccess) -> Self {
+        Self { env, ..self }
+    }
+
+    pub fn with_providers(self, providers: &'a [Box<dyn DynamicProvider>]) -> Self {
+        Self { providers, ..self }
+    }
+
+    pub fn with_names(self, names: DynamicNames) -> Self {
+        Self { names, ..self }
+    }
+}
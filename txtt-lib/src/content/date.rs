@@ -0,0 +1,314 @@
+//! Dates relative to the current date, e.g. `Today+14d{%d.%m.%Y}`.
+//!
+//! A date expression starts with a base (`Date`, `Today`, `Next<Weekday>` or
+//! `Previous<Weekday>`) which is followed by any number of offsets in days,
+//! weeks or months (e.g. `+14d`, `-2w` or `+1m`) and a format in braces.
+//! The format is optional for all bases except `Date`. Date expressions are
+//! the identifiers of the constants which are evaluated as dates. They are only
+//! read if the "dyn" feature is enabled and, without the sigil of dynamic
+//! elements, only if dynamic elements may be written without it.
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDateTime, Offset as _, TimeZone, Weekday};
+
+/// Format used if a date expression has no format: the date in the locale.
+pub const DEFAULT_FORMAT: &str = "%x";
+
+// Largest number of digits of an offset
+const MAX_OFFSET_DIGITS: usize = 4;
+/// Largest number of offsets of a date expression. Together with the number
+/// of digits of an offset, this keeps every date within the range of dates
+/// chrono supports, e.g. 16 offsets of 9999 months add up to 13332 years.
+pub const MAX_OFFSETS: usize = 16;
+
+/// The date an expression starts from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateBase {
+    /// The current date and time (`Date` and `Today`)
+    Current,
+    /// The next given day of the week after the current date
+    Next(Weekday),
+    /// The last given day of the week before the current date
+    Previous(Weekday),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offset {
+    Days(i64),
+    Weeks(i64),
+    Months(i64),
+}
+
+/// A date relative to the current date, written using a format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DateExpr {
+    pub base: DateBase,
+    pub offsets: Vec<Offset>,
+    pub format: Option<String>,
+}
+
+impl DateExpr {
+    /// Parse the identifier of a date constant. Returns `None` if `ident`
+    /// is not a valid date expression.
+    pub fn parse(ident: &str) -> Option<Self> {
+        let base_len = ident
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(ident.len());
+        let name = &ident[..base_len];
+        let base = base(name)?;
+
+        let mut rest = &ident[base_len..];
+        let mut offsets = vec![];
+        while let Some(len) = offset_len(rest) {
+            offsets.push(parse_offset(&rest[..len])?);
+            rest = &rest[len..];
+        }
+        if offsets.len() > MAX_OFFSETS {
+            return None;
+        }
+
+        let format = match rest {
+            "" => None,
+            _ => Some(
+                rest.strip_prefix('{')?
+                    .strip_suffix('}')
+                    .filter(|format| format.starts_with('%'))
+                    .filter(|format| !format.contains(['{', '}', '$']))
+                    .filter(|format| is_date_format(format))?
+                    .to_owned(),
+            ),
+        };
+        // `Date` alone is a regular constant.
+        if name == "Date" && format.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            base,
+            offsets,
+            format,
+        })
+    }
+
+    pub fn format(&self) -> &str {
+        self.format.as_deref().unwrap_or(DEFAULT_FORMAT)
+    }
+
+    /// The date described by `self` if the current date is `now`.
+    /// The time of the day is kept. Offsets which would leave the range
+    /// of dates chrono supports are ignored, like in `add_months`.
+    pub fn apply<Z: TimeZone>(&self, now: DateTime<Z>) -> DateTime<Z> {
+        let weekday = |day: Weekday| i64::from(day.num_days_from_monday());
+        let today = weekday(now.weekday());
+        let days = match self.base {
+            DateBase::Current => 0,
+            // A week ahead or back if the current day is the given day.
+            DateBase::Next(day) => 7 - (today - weekday(day) + 7) % 7,
+            DateBase::Previous(day) => -(7 - (weekday(day) - today + 7) % 7),
+        };
+
+        let add = |local: NaiveDateTime, duration| local.checked_add_signed(duration).unwrap_or(local);
+        let mut local = add(now.naive_local(), Duration::days(days));
+        for offset in &self.offsets {
+            local = match *offset {
+                Offset::Days(days) => add(local, Duration::days(days)),
+                Offset::Weeks(weeks) => add(local, Duration::weeks(weeks)),
+                Offset::Months(months) => add_months(local, months),
+            };
+        }
+        from_local(&now, local)
+    }
+}
+
+// Get the base of a date expression from its name
+fn base(name: &str) -> Option<DateBase> {
+    if name == "Date" || name == "Today" {
+        return Some(DateBase::Current);
+    }
+    if let Some(day) = name.strip_prefix("Next") {
+        return weekday(day).map(DateBase::Next);
+    }
+    weekday(name.strip_prefix("Previous")?).map(DateBase::Previous)
+}
+
+/// Check if `name` is the base of a date expression, e.g. `Today` or `NextMonday`.
+pub fn is_base(name: &str) -> bool {
+    base(name).is_some()
+}
+
+fn weekday(name: &str) -> Option<Weekday> {
+    Some(match name {
+        "Monday" => Weekday::Mon,
+        "Tuesday" => Weekday::Tue,
+        "Wednesday" => Weekday::Wed,
+        "Thursday" => Weekday::Thu,
+        "Friday" => Weekday::Fri,
+        "Saturday" => Weekday::Sat,
+        "Sunday" => Weekday::Sun,
+        _ => return None,
+    })
+}
+
+/// Length of the offset at the start of `s`, if `s` starts with one. An offset
+/// directly followed by a letter or digit is not an offset, so in `Today+1mile`
+/// only `Today` is part of the expression.
+pub fn offset_len(s: &str) -> Option<usize> {
+    let rest = s.strip_prefix(['+', '-'])?;
+    let digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    if digits == 0 || digits > MAX_OFFSET_DIGITS {
+        return None;
+    }
+    let rest = &rest[digits..];
+    if !rest.starts_with(['d', 'w', 'm'])
+        || rest[1..].starts_with(|c: char| c.is_ascii_alphanumeric())
+    {
+        return None;
+    }
+    Some(digits + 2)
+}
+
+fn parse_offset(s: &str) -> Option<Offset> {
+    let (number, unit) = s.split_at(s.len() - 1);
+    let number: i64 = number.parse().ok()?;
+    Some(match unit {
+        "d" => Offset::Days(number),
+        "w" => Offset::Weeks(number),
+        "m" => Offset::Months(number),
+        _ => return None,
+    })
+}
+
+/// Check if chrono can write dates using `format`.
+pub fn is_date_format(format: &str) -> bool {
+    StrftimeItems::new(format).all(|item| item != Item::Error)
+}
+
+// Add months to a date. Days which do not exist in the resulting month
+// become the last day of the month, e.g. 31st of January + 1 month.
+fn add_months(local: NaiveDateTime, months: i64) -> NaiveDateTime {
+    let abs = Months::new(months.unsigned_abs().try_into().unwrap_or(u32::MAX));
+    let result = match months >= 0 {
+        true => local.checked_add_months(abs),
+        false => local.checked_sub_months(abs),
+    };
+    result.unwrap_or(local)
+}
+
+// Convert a local date and time back into the time zone of `like`. A local
+// time which is skipped by a daylight saving time change keeps the offset of `like`,
+// a local time which has no time in UTC (at the end of the dates chrono supports) none.
+fn from_local<Z: TimeZone>(like: &DateTime<Z>, local: NaiveDateTime) -> DateTime<Z> {
+    let time_zone = like.timezone();
+    time_zone
+        .from_local_datetime(&local)
+        .earliest()
+        .unwrap_or_else(|| {
+            let offset = Duration::seconds(like.offset().fix().local_minus_utc().into());
+            time_zone.from_utc_datetime(&local.checked_sub_signed(offset).unwrap_or(local))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn date_expressions_are_parsed() {
+        let cases = [
+            ("Today", Some((DateBase::Current, vec![], None))),
+            (
+                "Today+14d-2w+1m{%F}",
+                Some((
+                    DateBase::Current,
+                    vec![Offset::Days(14), Offset::Weeks(-2), Offset::Months(1)],
+                    Some("%F"),
+                )),
+            ),
+            (
+                "NextMonday",
+                Some((DateBase::Next(Weekday::Mon), vec![], None)),
+            ),
+            (
+                "PreviousFriday-1w",
+                Some((
+                    DateBase::Previous(Weekday::Fri),
+                    vec![Offset::Weeks(-1)],
+                    None,
+                )),
+            ),
+            (
+                "Date{%d.%m.%Y}",
+                Some((DateBase::Current, vec![], Some("%d.%m.%Y"))),
+            ),
+            ("Date", None),
+            ("Date+1d", None),
+            ("Tomorrow", None),
+            ("NextMonth", None),
+            ("Today+1y", None),
+            ("Today+12345d", None),
+            ("Today{%Q}", None),
+        ];
+        for (ident, expected) in cases {
+            let expected = expected.map(|(base, offsets, format)| DateExpr {
+                base,
+                offsets,
+                format: format.map(str::to_owned),
+            });
+            assert_eq!(DateExpr::parse(ident), expected, "Identifier {ident}");
+        }
+    }
+
+    #[test]
+    fn offsets_end_at_their_unit() {
+        assert_eq!(offset_len("+14d"), Some(4));
+        assert_eq!(offset_len("-2w, bye"), Some(3));
+        assert_eq!(offset_len("+1m{%F}"), Some(3));
+        assert_eq!(offset_len("+1mile"), None);
+        assert_eq!(offset_len("+d"), None);
+        assert_eq!(offset_len("14d"), None);
+    }
+
+    #[test]
+    fn dates_are_moved() {
+        // Wednesday, 31st of January 2024
+        let now = Utc.with_ymd_and_hms(2024, 1, 31, 10, 0, 0).unwrap();
+        let cases = [
+            ("Today+14d", "2024-02-14"),
+            ("Today-1w", "2024-01-24"),
+            ("Today+1m", "2024-02-29"),
+            ("Today-2m", "2023-11-30"),
+            ("NextMonday", "2024-02-05"),
+            ("NextWednesday", "2024-02-07"),
+            ("PreviousFriday", "2024-01-26"),
+            ("PreviousWednesday", "2024-01-24"),
+            ("NextFriday+1w", "2024-02-09"),
+        ];
+        for (ident, expected) in cases {
+            let date = DateExpr::parse(ident).unwrap().apply(now);
+            assert_eq!(
+                date.format("%F").to_string(),
+                expected,
+                "Identifier {ident}"
+            );
+        }
+    }
+
+    #[test]
+    fn dates_stay_in_range() {
+        let far = format!("Today{}", "+9999w".repeat(MAX_OFFSETS));
+        let now = Utc.with_ymd_and_hms(9999, 12, 31, 23, 59, 59).unwrap();
+        assert_eq!(DateExpr::parse(&far).unwrap().apply(now).year(), 13066);
+        assert_eq!(DateExpr::parse(&format!("{far}+1d")), None);
+        // Offsets beyond the dates chrono supports are ignored.
+        let now = DateTime::<Utc>::MAX_UTC;
+        assert_eq!(DateExpr::parse("Today+1d").unwrap().apply(now), now);
+        // A local time after the last time in UTC keeps its offset from UTC.
+        let west = chrono::FixedOffset::west_opt(3600).unwrap();
+        let utc = NaiveDateTime::MAX - Duration::days(1) + Duration::minutes(30);
+        let now = west.from_utc_datetime(&utc);
+        let moved = DateExpr::parse("Today+1d").unwrap().apply(now);
+        assert_eq!(moved.naive_utc(), NaiveDateTime::MAX - Duration::minutes(30));
+    }
+}
@@ -0,0 +1,140 @@
+//! Dynamic elements which read the environment a template is filled out in.
+//!
+//! Templates may only read the environment elements which are allowed
+//! by the `EnvAccess` of the context they are evaluated in.
+use super::parse::env_var_of;
+use super::{Content, DynContext};
+use std::env;
+
+pub enum EnvElement {
+    /// The value of an environment variable (e.g. `$Env.USER`)
+    Var(String),
+    /// The name of the user filling out the template.
+    User,
+    /// The name of the computer the template is filled out on.
+    Hostname,
+    /// The file name of the template (e.g. `letter.txtt`)
+    TemplateName,
+    /// The path of the template as it was given.
+    TemplatePath,
+    /// The current working directory.
+    WorkingDir,
+}
+
+impl EnvElement {
+    /// Get the value of the environment element in the context `ctx`. Returns `None`
+    /// if the element has no value, e.g. if the environment variable is not set.
+    /// Whether the element may be read has to be checked with `EnvAccess::allows`.
+    pub fn evaluate(&self, ctx: &DynContext) -> Option<Content> {
+        let template_path = ctx.env.template_path.as_deref();
+        match self {
+            EnvElement::Var(name) => env::var(name).ok(),
+            EnvElement::User => whoami::fallible::username().ok(),
+            EnvElement::Hostname => whoami::fallible::hostname().ok(),
+            EnvElement::TemplateName => template_path?
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned()),
+            EnvElement::TemplatePath => {
+                template_path.map(|path| path.to_string_lossy().into_owned())
+            }
+            EnvElement::WorkingDir => env::current_dir()
+                .ok()
+                .map(|dir| dir.to_string_lossy().into_owned()),
+        }
+        .filter(|content| !content.is_empty())
+    }
+}
+
+pub trait EnvExt {
+    /// Convert `self` into the matching `EnvElement` if `self` is the identifier
+    /// of an environment element.
+    fn as_env(&self) -> Option<EnvElement>;
+}
+
+impl<S> EnvExt for S
+where
+    S: AsRef<str>,
+{
+    fn as_env(&self) -> Option<EnvElement> {
+        Some(match self.as_ref() {
+            "User" => EnvElement::User,
+            "Hostname" => EnvElement::Hostname,
+            "TemplateName" => EnvElement::TemplateName,
+            "TemplatePath" => EnvElement::TemplatePath,
+            "WorkingDir" => EnvElement::WorkingDir,
+            ident => EnvElement::Var(env_var_of(ident)?.to_owned()),
+        })
+    }
+}
+
+/// Get the value of the environment element `ident` if it may be read in `ctx`.
+pub fn evaluate_allowed(ident: &str, ctx: &DynContext) -> Option<Content> {
+    if !ctx.env.allows(ident) {
+        return None;
+    }
+    ident.as_env()?.evaluate(ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content::{Clock, EnvAccess};
+    use std::path::PathBuf;
+    use unic_locale::Locale;
+
+    fn evaluate_with(ident: &str, env: &EnvAccess) -> Option<Content> {
+        let locale = Locale::default();
+        let ctx = DynContext::new(&locale, None, &Clock::System).with_env(env);
+        evaluate_allowed(ident, &ctx)
+    }
+
+    #[test]
+    fn idents_can_become_env() {
+        let idents = [
+            "Env.PATH",
+            "Env.MY_VAR_2",
+            "User",
+            "Hostname",
+            "TemplateName",
+            "TemplatePath",
+            "WorkingDir",
+        ];
+        for ident in idents {
+            assert!(ident.as_env().is_some(), "Identifier {ident}");
+        }
+        for ident in ["Env", "Env.", "Env.A-B", "Me"] {
+            assert!(ident.as_env().is_none(), "Identifier {ident}");
+        }
+    }
+
+    #[test]
+    fn only_allowed_elements_are_read() {
+        let path = env::var("PATH").unwrap();
+        let env = EnvAccess {
+            allowed: vec!["Env.PATH".into(), "WorkingDir".into()],
+            template_path: None,
+        };
+        assert_eq!(evaluate_with("Env.PATH", &env), Some(path));
+        assert!(evaluate_with("WorkingDir", &env).is_some());
+        assert_eq!(evaluate_with("Env.HOME", &env), None);
+        assert_eq!(evaluate_with("Env.PATH", &EnvAccess::default()), None);
+    }
+
+    #[test]
+    fn template_path_is_read() {
+        let env = EnvAccess {
+            allowed: vec!["TemplateName".into(), "TemplatePath".into()],
+            template_path: Some(PathBuf::from("letters/invoice.txtt")),
+        };
+        assert_eq!(evaluate_with("TemplateName", &env).unwrap(), "invoice.txtt");
+        assert_eq!(
+            evaluate_with("TemplatePath", &env).unwrap(),
+            "letters/invoice.txtt"
+        );
+        let unknown = EnvAccess {
+            template_path: None,
+            ..env
+        };
+        assert_eq!(evaluate_with("TemplateName", &unknown), None);
+    }
+}
@@ -0,0 +1,502 @@
+//! Matching rendered text against the tokens of a template.
+//!
+//! The matcher recovers the volatile content which was used to fill out
+//! a template from the resulting text. Text in the template has to appear
+//! in the text literally, keys capture any non-empty piece of text between
+//! those anchors and constants and choices of options are looked up in the
+//! content state. The matcher backtracks over all ways of matching the text,
+//! so it can report which elements could have had more than one value.
+//! States which are known not to match are remembered, so the text is not
+//! matched against the same tokens from the same position twice.
+//!
+//! Only the first 16 ways of matching a text are looked at, so elements which
+//! could only have another value in a later way are not reported as ambiguous.
+use super::provider;
+use super::{
+    ContentIndex, ContentState, ContentToken, ContentTokens, ContentType, DynContext,
+    VolatileContent,
+};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+// Number of ways of matching a text after which the matcher stops looking for more.
+// Ambiguities are only detected among these matches, as the documentation of
+// `ContentTokens::match_text` and `Template::match_text` states.
+const MAX_MATCHES: usize = 16;
+// Number of steps after which the matcher gives up, in case even
+// remembering failed states does not keep the matcher fast.
+const MAX_STEPS: usize = 1_000_000;
+
+/// Volatile content recovered from a text.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TextMatch {
+    /// Content which fills out the template to exactly the matched text.
+    pub content: VolatileContent,
+    /// Elements which could have had more than one value,
+    /// e.g. two keys which are not separated by any text.
+    pub ambiguous: Vec<ContentIndex>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MatchError {
+    #[error("The text can not be produced by filling out the template")]
+    NoMatch,
+    #[error("Matching the text against the template took too many steps")]
+    TooComplex,
+    #[error("An option does not wrap a key")]
+    OptionWithoutKey,
+}
+
+// Content chosen for the elements while matching. Options without a
+// choice use their default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Bindings<'a> {
+    keys: HashMap<&'a str, &'a str>,
+    choices: HashMap<&'a str, Option<&'a str>>,
+}
+
+// Tokens which are left to match. If they are a suffix of the tokens of the
+// template, `suffix` is the index of the first of them in the template.
+#[derive(Clone, Copy)]
+struct Rest<'a, 'r> {
+    tokens: &'r [&'a ContentToken],
+    suffix: Option<usize>,
+}
+
+impl<'a, 'r> Rest<'a, 'r> {
+    fn split_first(self) -> Option<(&'a ContentToken, Self)> {
+        let (token, tail) = self.tokens.split_first()?;
+        let tail = Self {
+            tokens: tail,
+            suffix: self.suffix.map(|idx| idx + 1),
+        };
+        Some((*token, tail))
+    }
+}
+
+// State of the matcher in a suffix of the tokens of the template: the index
+// of the suffix, the position in the text and the content bound to the keys
+// and options in the suffix. Content bound to other elements does not change
+// whether the suffix matches.
+type State<'a> = (
+    usize,
+    usize,
+    Vec<Option<&'a str>>,
+    Vec<Option<Option<&'a str>>>,
+);
+
+struct Matcher<'a, 't> {
+    text: &'a str,
+    content_state: &'a ContentState,
+    // Context in which the dynamic elements would be evaluated, if they are
+    eval_dyn: Option<&'t DynContext<'t>>,
+    // Identifiers of the keys and options in every suffix of `tokens`
+    elements: Vec<(Vec<&'a str>, Vec<&'a str>)>,
+    failed: HashSet<State<'a>>,
+    steps: usize,
+    matches: Vec<Bindings<'a>>,
+}
+
+impl<'a, 't> Matcher<'a, 't> {
+    fn new(
+        text: &'a str,
+        content_state: &'a ContentState,
+        eval_dyn: Option<&'t DynContext<'t>>,
+        tokens: &'t [&'a ContentToken],
+    ) -> Self {
+        // Collect the identifiers of the keys and options in `token` and its defaults
+        fn collect<'a>(token: &'a ContentToken, elements: &mut (Vec<&'a str>, Vec<&'a str>)) {
+            match token {
+                ContentToken::Key(ident, default) => {
+                    elements.0.push(ident);
+                    if let Some(default) = default {
+                        collect(default, elements);
+                    }
+                }
+                ContentToken::Option(key_box) => {
+                    if let ContentToken::Key(ident, default) = &**key_box {
+                        elements.1.push(ident);
+                        if let Some(default) = default {
+                            collect(default, elements);
+                        }
+                    }
+                }
+                ContentToken::Text(_) | ContentToken::Constant(_) => {}
+            }
+        }
+
+        let mut elements = vec![(vec![], vec![]); tokens.len() + 1];
+        for (idx, token) in tokens.iter().enumerate().rev() {
+            let mut suffix = elements[idx + 1].clone();
+            collect(token, &mut suffix);
+            suffix.0.sort_unstable();
+            suffix.0.dedup();
+            suffix.1.sort_unstable();
+            suffix.1.dedup();
+            elements[idx] = suffix;
+        }
+
+        Self {
+            text,
+            content_state,
+            eval_dyn,
+            elements,
+            failed: HashSet::new(),
+            steps: 0,
+            matches: vec![],
+        }
+    }
+
+    // Get the state of the matcher if `rest` is a suffix of the tokens of the template
+    fn state(&self, rest: Rest<'a, '_>, pos: usize, bindings: &Bindings<'a>) -> Option<State<'a>> {
+        let idx = rest.suffix?;
+        let (keys, options) = &self.elements[idx];
+        let keys = keys
+            .iter()
+            .map(|ident| bindings.keys.get(ident).copied())
+            .collect();
+        let options = options
+            .iter()
+            .map(|ident| bindings.choices.get(ident).copied())
+            .collect();
+        Some((idx, pos, keys, options))
+    }
+
+    // Match the tokens in `rest` against the text starting at `pos` and record
+    // the bindings of every way to match the entire text.
+    fn match_tokens(&mut self, rest: Rest<'a, '_>, pos: usize, bindings: &mut Bindings<'a>) {
+        if self.matches.len() >= MAX_MATCHES || self.steps >= MAX_STEPS {
+            return;
+        }
+        self.steps += 1;
+        let (token, tail) = match rest.split_first() {
+            Some(split) => split,
+            None => {
+                if pos == self.text.len() {
+                    self.matches.push(bindings.clone());
+                }
+                return;
+            }
+        };
+
+        let state = self.state(rest, pos, bindings);
+        if state
+            .as_ref()
+            .is_some_and(|state| self.failed.contains(state))
+        {
+            return;
+        }
+        let matches = self.matches.len();
+        self.match_token(token, tail, pos, bindings);
+        // States the matcher gave up in are not known to fail.
+        if let Some(state) =
+            state.filter(|_| self.matches.len() == matches && self.steps < MAX_STEPS)
+        {
+            self.failed.insert(state);
+        }
+    }
+
+    fn match_token(
+        &mut self,
+        token: &'a ContentToken,
+        tail: Rest<'a, '_>,
+        pos: usize,
+        bindings: &mut Bindings<'a>,
+    ) {
+        match token {
+            ContentToken::Text(text) => self.match_literal(text, tail, pos, bindings),
+            ContentToken::Constant(ident) => {
+                if self.eval_dyn.is_some_and(|ctx| provider::provides(ident, ctx)) {
+                    // The content of dynamic elements is not known, so any text matches.
+                    for end in ends(self.text, pos, tail.tokens) {
+                        self.match_tokens(tail, end, bindings);
+                    }
+                    return;
+                }
+                match self.content_state.constants.get(ident) {
+                    Some(content) => self.match_literal(content, tail, pos, bindings),
+                    // The content state lacks the constant, so any text matches.
+                    None => {
+                        for end in ends(self.text, pos, tail.tokens) {
+                            self.match_tokens(tail, end, bindings);
+                        }
+                    }
+                }
+            }
+            ContentToken::Key(ident, _) => {
+                if let Some(content) = bindings.keys.get(ident.as_str()) {
+                    return self.match_literal(content, tail, pos, bindings);
+                }
+                // Capturing the text covers the default as well, because
+                // giving the key its default content renders the same text.
+                for end in ends(self.text, pos, tail.tokens) {
+                    bindings.keys.insert(ident, &self.text[pos..end]);
+                    self.match_tokens(tail, end, bindings);
+                    bindings.keys.remove(ident.as_str());
+                }
+            }
+            ContentToken::Option(key_box) => {
+                // Options without a key are rejected before matching.
+                let ContentToken::Key(ident, default) = &**key_box else {
+                    return;
+                };
+                let ident = ident.as_str();
+                let choices = self.content_state.options.get(ident);
+                match bindings.choices.get(ident) {
+                    Some(Some(choice)) => {
+                        let content = &choices.unwrap()[*choice];
+                        self.match_literal(content, tail, pos, bindings);
+                    }
+                    Some(None) => self.match_default(default.as_deref(), tail, pos, bindings),
+                    None => {
+                        // Try the choices in a fixed order, so the result does not
+                        // depend on the order of the hash map.
+                        let mut choices: Vec<_> = choices.into_iter().flatten().collect();
+                        choices.sort_unstable();
+                        for (choice, content) in choices {
+                            bindings.choices.insert(ident, Some(choice));
+                            self.match_literal(content, tail, pos, bindings);
+                        }
+                        bindings.choices.insert(ident, None);
+                        self.match_default(default.as_deref(), tail, pos, bindings);
+                        bindings.choices.remove(ident);
+                    }
+                }
+            }
+        }
+    }
+
+    fn match_literal(
+        &mut self,
+        literal: &str,
+        tail: Rest<'a, '_>,
+        pos: usize,
+        bindings: &mut Bindings<'a>,
+    ) {
+        if !literal.is_empty() && self.text[pos..].starts_with(literal) {
+            self.match_tokens(tail, pos + literal.len(), bindings);
+        }
+    }
+
+    // Match the default of an option which has no choice
+    fn match_default(
+        &mut self,
+        default: Option<&'a ContentToken>,
+        tail: Rest<'a, '_>,
+        pos: usize,
+        bindings: &mut Bindings<'a>,
+    ) {
+        if let Some(default) = default {
+            let mut tokens = vec![default];
+            tokens.extend_from_slice(tail.tokens);
+            let rest = Rest {
+                tokens: &tokens,
+                suffix: None,
+            };
+            self.match_tokens(rest, pos, bindings);
+        }
+    }
+}
+
+// Possible ends of a non-empty piece of `text` starting at `pos`. If the next
+// token is text, the piece has to end right in front of this text.
+fn ends<'a>(
+    text: &'a str,
+    pos: usize,
+    tail: &[&'a ContentToken],
+) -> impl Iterator<Item = usize> + 'a {
+    let anchor = match tail.first() {
+        Some(ContentToken::Text(anchor)) => Some(anchor.as_str()),
+        _ => None,
+    };
+    text[pos..]
+        .char_indices()
+        .skip(1)
+        .map(move |(idx, _)| pos + idx)
+        .chain(std::iter::once(text.len()))
+        .filter(move |end| anchor.is_none_or(|anchor| text[*end..].starts_with(anchor)))
+}
+
+impl ContentTokens {
+    /// Recover the volatile content which fills out the tokens to `text`.
+    /// Dynamic elements provided in the context `eval_dyn` match any text.
+    /// Ambiguities are only looked for among the first 16 ways of matching `text`.
+    pub fn match_text(
+        &self,
+        text: &str,
+        content_state: &ContentState,
+        eval_dyn: Option<&DynContext>,
+    ) -> Result<TextMatch, MatchError> {
+        if !self.options_wrap_keys() {
+            return Err(MatchError::OptionWithoutKey);
+        }
+        if let Some(ctx) = eval_dyn {
+            self.warn_shadowed(content_state, ctx);
+        }
+        let tokens: Vec<&ContentToken> = self.tokens.iter().collect();
+        let mut matcher = Matcher::new(text, content_state, eval_dyn, &tokens);
+        let rest = Rest {
+            tokens: &tokens,
+            suffix: Some(0),
+        };
+        matcher.match_tokens(rest, 0, &mut Bindings::default());
+
+        let first = match matcher.matches.first() {
+            Some(first) => first,
+            None if matcher.steps >= MAX_STEPS => return Err(MatchError::TooComplex),
+            None => return Err(MatchError::NoMatch),
+        };
+        let mut ambiguous = BTreeSet::new();
+        for other in &matcher.matches[1..] {
+            for ident in first.keys.keys().chain(other.keys.keys()) {
+                if first.keys.get(ident) != other.keys.get(ident) {
+                    ambiguous.insert((ContentType::Key, *ident));
+                }
+            }
+            for ident in first.choices.keys().chain(other.choices.keys()) {
+                if first.choices.get(ident) != other.choices.get(ident) {
+                    ambiguous.insert((ContentType::Option, *ident));
+                }
+            }
+        }
+
+        let mut content = VolatileContent::new();
+        for (ident, key) in &first.keys {
+            content.map_key(ident, key);
+        }
+        for (ident, choice) in &first.choices {
+            if let Some(choice) = choice {
+                content.map_choice(ident, choice);
+            }
+        }
+        let ambiguous = ambiguous
+            .into_iter()
+            .map(|(content_type, ident)| ContentIndex::new(content_type, ident))
+            .collect();
+
+        Ok(TextMatch { content, ambiguous })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content::choice;
+
+    fn content_state() -> ContentState {
+        let mut cs = ContentState::new();
+        cs.map_constant("Me", "Paul");
+        cs.map_option("greeting", choice!("formal", "Dear"));
+        cs.map_option("greeting", choice!("casual", "Hi"));
+        cs
+    }
+
+    fn match_text(template: &str, text: &str) -> Result<TextMatch, MatchError> {
+        let tokens: ContentTokens = template.parse().unwrap();
+        tokens.match_text(text, &content_state(), None)
+    }
+
+    #[test]
+    fn content_is_recovered() {
+        let result = match_text(
+            "${greeting} {name},\n{message}\n$Me",
+            "Hi Jessica,\nthe spice must flow\nPaul",
+        )
+        .unwrap();
+        let mut expected = VolatileContent::new();
+        expected.map_choice("greeting", "casual");
+        expected.map_key("name", "Jessica");
+        expected.map_key("message", "the spice must flow");
+        assert_eq!(result.content, expected);
+        assert!(result.ambiguous.is_empty());
+    }
+
+    #[test]
+    fn repeated_elements_have_the_same_content() {
+        let result = match_text("{a}-{a}-{b}", "x-y-x-y-z").unwrap();
+        assert_eq!(result.content.keys["a"], "x-y");
+        assert_eq!(result.content.keys["b"], "z");
+        assert!(result.ambiguous.is_empty());
+    }
+
+    #[test]
+    fn defaults_of_options_are_matched() {
+        let result = match_text("${greeting:{salutation}} there", "Hello there").unwrap();
+        assert!(result.content.choices.is_empty());
+        assert_eq!(result.content.keys["salutation"], "Hello");
+    }
+
+    #[test]
+    fn ambiguities_are_reported() {
+        let result = match_text("{first}{last} and $Me", "PaulAtreides and Paul").unwrap();
+        assert_eq!(
+            result.ambiguous,
+            vec![
+                ContentIndex::new(ContentType::Key, "first"),
+                ContentIndex::new(ContentType::Key, "last"),
+            ]
+        );
+        // A key might just as well contain the content of an option.
+        let result = match_text("{name:${greeting}}", "Dear").unwrap();
+        assert!(result.ambiguous.is_empty());
+        let result = match_text("{a} ${greeting:{b}}", "x Dear").unwrap();
+        assert_eq!(
+            result.ambiguous,
+            vec![
+                ContentIndex::new(ContentType::Key, "b"),
+                ContentIndex::new(ContentType::Option, "greeting"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unrelated_text_does_not_match() {
+        let cases = [
+            ("Hello {name}", "Goodbye Paul"),
+            ("{name}!", "!"),
+            ("${greeting} {name}", "Yo Paul"),
+            ("$Me", "Leto"),
+            ("{a}-{a}", "x-y"),
+        ];
+        for (template, text) in cases {
+            assert_eq!(
+                match_text(template, text),
+                Err(MatchError::NoMatch),
+                "{template}"
+            );
+        }
+    }
+
+    #[test]
+    fn options_without_keys_are_rejected() {
+        let mut tokens = ContentTokens::new();
+        tokens.push(ContentToken::Option(Box::new(ContentToken::Text("x".into()))));
+        let result = tokens.match_text("x", &content_state(), None);
+        assert_eq!(result, Err(MatchError::OptionWithoutKey));
+    }
+
+    #[test]
+    fn long_unrelated_text_fails_quickly() {
+        // Without remembering failed states, every way of splitting the
+        // words among the keys would be tried before failing.
+        let text = vec!["word"; 160].join(" ");
+        let tokens: ContentTokens = "{a} {b} {c} {d} {e}.".parse().unwrap();
+        let tokens: Vec<&ContentToken> = tokens.tokens.iter().collect();
+        let cs = content_state();
+        let mut matcher = Matcher::new(&text, &cs, None, &tokens);
+        let rest = Rest {
+            tokens: &tokens,
+            suffix: Some(0),
+        };
+        matcher.match_tokens(rest, 0, &mut Bindings::default());
+        assert!(matcher.matches.is_empty());
+        // Every key starts at one of 160 words and ends at one of 160 words.
+        assert!(matcher.steps < 5 * 160 * 160, "{} steps", matcher.steps);
+
+        let result = match_text("{a} {b} {c} {d} {e}.", &format!("{text}.")).unwrap();
+        assert_eq!(result.content.keys["a"], "word");
+        assert!(result.ambiguous.contains(&ContentIndex::new(ContentType::Key, "e")));
+    }
+}
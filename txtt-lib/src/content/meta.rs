@@ -1,6 +1,12 @@
-use super::Content;
-use chrono::Utc;
+use super::date::DateExpr;
+use super::parse::random_range_of;
+use super::{Clock, Content, DynContext};
+use chrono::{DateTime, Local, TimeZone};
+#[cfg(test)]
 use lazy_static::lazy_static;
+use rand::Rng;
+use std::fmt::Display;
+use unic_locale::Locale;
 
 pub enum Meta {
     /// The current year (e.g. 2023)
@@ -13,8 +19,25 @@ pub enum Meta {
     MonthAbbr,
     /// The number of the current day in the month (01-31).
     DayNum,
-    /// The number of the current week in the year.
+    /// The number of the current week in the year where the first week starts
+    /// with the first Sunday of the year.
     Week,
+    /// The ISO 8601 week number where weeks start on Monday and the first week
+    /// contains the 4th of January (e.g. 01).
+    IsoWeek,
+    /// The year the ISO 8601 week belongs to, which differs from the current year
+    /// in the first and last days of some years.
+    IsoYear,
+    /// The current day of the year where the 1st of January is 001.
+    DayOfYear,
+    /// The current quarter of the year from 1 to 4.
+    Quarter,
+    /// The number of seconds since 1970-01-01 00:00:00 UTC.
+    Timestamp,
+    /// A random UUID (version 4), e.g. `67e55044-10b1-426f-9247-bb680e5fe0c8`.
+    Uuid,
+    /// A random number between the two bounds, including them.
+    Random(u64, u64),
     /// The name of the current day.
     DayName,
     /// The name of the current day abbreviated to three letters.
@@ -25,29 +48,76 @@ pub enum Meta {
     Minute,
     /// The current second of the minute where the first second is 00 and the last second is 59.
     Second,
+    /// A date relative to the current date written using a chrono format
+    /// string, e.g. `Today+14d{%d.%m.%Y}`.
+    Date(DateExpr),
 }
 
-impl From<Meta> for Content {
-    /// Get the value of the meta element as a string.
-    fn from(from: Meta) -> Self {
-        let time_date_fmt = |s| Utc::now().format(s).to_string();
+impl Meta {
+    /// Get the value of the meta element as a string at the time of `ctx` in
+    /// its time zone, writing the names of months and days in its locale.
+    pub fn evaluate(&self, ctx: &DynContext) -> Content {
+        let locale = chrono_locale(ctx.locale);
+        match ctx.time_zone {
+            Some(time_zone) => self.evaluate_at(ctx.now.with_timezone(&time_zone), locale),
+            None => self.evaluate_at(ctx.now.with_timezone(&Local), locale),
+        }
+    }
+
+    fn evaluate_at<Z: TimeZone>(&self, now: DateTime<Z>, locale: chrono::Locale) -> Content
+    where
+        Z::Offset: Display,
+    {
+        let fmt = |date: &DateTime<Z>, s| date.format_localized(s, locale).to_string();
+        let time_date_fmt = |s| fmt(&now, s);
 
-        match from {
+        match self {
             Meta::Year => time_date_fmt("%Y"),
             Meta::MonthNum => time_date_fmt("%m"),
             Meta::MonthName => time_date_fmt("%B"),
             Meta::MonthAbbr => time_date_fmt("%b"),
             Meta::DayNum => time_date_fmt("%d"),
             Meta::Week => time_date_fmt("%U"),
+            Meta::IsoWeek => time_date_fmt("%V"),
+            Meta::IsoYear => time_date_fmt("%G"),
+            Meta::DayOfYear => time_date_fmt("%j"),
+            Meta::Quarter => time_date_fmt("%q"),
+            Meta::Timestamp => now.timestamp().to_string(),
+            Meta::Uuid => uuid::Uuid::new_v4().to_string(),
+            Meta::Random(min, max) => rand::thread_rng().gen_range(*min..=*max).to_string(),
             Meta::DayName => time_date_fmt("%A"),
             Meta::DayAbbr => time_date_fmt("%a"),
             Meta::Hour => time_date_fmt("%H"),
             Meta::Minute => time_date_fmt("%M"),
             Meta::Second => time_date_fmt("%S"),
+            Meta::Date(expr) => fmt(&expr.apply(now.clone()), expr.format()),
         }
     }
 }
 
+impl From<Meta> for Content {
+    /// Get the current value of the meta element as a string using English names.
+    fn from(from: Meta) -> Self {
+        from.evaluate(&DynContext::new(&Locale::default(), None, &Clock::System))
+    }
+}
+
+// Find the locale chrono uses for the names of months and days. A locale
+// without a region uses the region named like its language (e.g. `de_DE`
+// for `de`) and unknown locales fall back to English names.
+fn chrono_locale(locale: &Locale) -> chrono::Locale {
+    let language = locale.id.language.as_str();
+    let names = [
+        locale.id.region.map(|region| format!("{language}_{region}")),
+        Some(format!("{language}_{}", language.to_uppercase())),
+    ];
+    names
+        .into_iter()
+        .flatten()
+        .find_map(|name| chrono::Locale::try_from(name.as_str()).ok())
+        .unwrap_or(chrono::Locale::POSIX)
+}
+
 pub trait MetaExt {
     /// Convert `self` into the matching `Meta` instance if `self` has a meta value.
     fn as_meta(&self) -> Option<Meta>;
@@ -65,31 +135,56 @@ where
             "MonthAbbr" | "MonthShort" => Meta::MonthAbbr,
             "DayNum" => Meta::DayNum,
             "Week" => Meta::Week,
+            "IsoWeek" => Meta::IsoWeek,
+            "IsoYear" => Meta::IsoYear,
+            "DayOfYear" => Meta::DayOfYear,
+            "Quarter" => Meta::Quarter,
+            "Timestamp" => Meta::Timestamp,
+            "Uuid" => Meta::Uuid,
             "Day" | "DayName" => Meta::DayName,
             "DayAbbr" | "DayShort" => Meta::DayAbbr,
             "Hour" => Meta::Hour,
             "Minute" => Meta::Minute,
             "Second" => Meta::Second,
-            _ => {
-                return None;
-            }
+            ident => match random_range_of(ident) {
+                Some((min, max)) => Meta::Random(min, max),
+                None => Meta::Date(DateExpr::parse(ident)?),
+            },
         })
     }
 }
 
-// Lists of the identifiers of all meta elements.
-pub const META_DATE_TIME: [&str; 5] = ["Year", "Week", "Hour", "Minute", "Second"];
-pub const META_MONTH: [&str; 5] = ["MonthNum", "Month", "MonthName", "MonthAbbr", "MonthShort"];
-pub const META_DAY: [&str; 5] = ["DayNum", "Day", "DayName", "DayAbbr", "DayShort"];
+// Lists of the identifiers of all meta elements, only read by the tests.
+#[cfg(test)]
+const META_DATE_TIME: [&str; 5] = ["Year", "Week", "Hour", "Minute", "Second"];
+#[cfg(test)]
+const META_CALENDAR: [&str; 5] = ["IsoWeek", "IsoYear", "DayOfYear", "Quarter", "Timestamp"];
+#[cfg(test)]
+const META_MONTH: [&str; 5] = ["MonthNum", "Month", "MonthName", "MonthAbbr", "MonthShort"];
+#[cfg(test)]
+const META_DAY: [&str; 5] = ["DayNum", "Day", "DayName", "DayAbbr", "DayShort"];
+// Meta elements whose value is different every time they are evaluated
+#[cfg(test)]
+const META_RANDOM: [&str; 2] = ["Uuid", "Random{1-6}"];
 // List of all meta identifiers.
+#[cfg(test)]
 lazy_static! {
-    static ref META_IDENTS: Vec<&'static str> = [META_DATE_TIME, META_MONTH, META_DAY].concat();
+    static ref META_IDENTS: Vec<&'static str> = [
+        &META_DATE_TIME[..],
+        &META_CALENDAR[..],
+        &META_MONTH[..],
+        &META_DAY[..],
+        &META_RANDOM[..],
+    ]
+    .concat();
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::content::Ident;
+    use chrono::{DateTime, TimeZone, Utc};
+    use chrono_tz::Tz;
 
     #[test]
     fn idents_can_become_meta() {
@@ -98,16 +193,20 @@ mod tests {
         }
     }
 
+    // Sunday, 5th of November 2023, one second before midnight
+    fn fixed_time() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 11, 5, 23, 59, 59).unwrap()
+    }
+
     #[test]
     fn date_time_meta_is_correct() {
-        // The string behind each meta value will be compared to this time value.
-        // It suffices to get the current time once, because the time between the
-        // initial asserts and is much less than a second, so it will not become outdated.
-        let now = Utc::now();
+        let now = fixed_time();
+        let locale = Locale::default();
+        let ctx = DynContext::new(&locale, Some(Tz::UTC), &Clock::Fixed(now));
 
         // Check that the given meta value becomes a time/date-string with the given formatting.
-        let check_meta_to_dt = |meta, fmt| {
-            let meta_str = Content::from(meta);
+        let check_meta_to_dt = |meta: Meta, fmt| {
+            let meta_str = meta.evaluate(&ctx);
             let dt_str = now.format(fmt).to_string();
 
             assert_eq!(meta_str, dt_str);
@@ -125,23 +224,175 @@ mod tests {
         check_meta_to_dt(Meta::MonthAbbr, "%b");
     }
 
+    #[test]
+    fn names_are_written_in_the_locale() {
+        // Monday, 2nd of January 2023
+        let now = Utc.with_ymd_and_hms(2023, 1, 2, 12, 0, 0).unwrap();
+        let cases = [
+            ("en-US", ["Monday", "Mon", "January", "Jan"]),
+            ("de-DE", ["Montag", "Mo", "Januar", "Jan"]),
+            ("de-AT", ["Montag", "Mo", "Jänner", "Jän"]),
+            ("fr", ["lundi", "lun.", "janvier", "janv."]),
+            ("es-ES", ["lunes", "lun", "enero", "ene"]),
+            ("xx", ["Monday", "Mon", "January", "Jan"]),
+        ];
+        for (locale, expected) in cases {
+            let locale: Locale = locale.parse().unwrap();
+            let ctx = DynContext::new(&locale, Some(Tz::UTC), &Clock::Fixed(now));
+            let names = [Meta::DayName, Meta::DayAbbr, Meta::MonthName, Meta::MonthAbbr]
+                .map(|meta| meta.evaluate(&ctx));
+            assert_eq!(names, expected, "Names in {locale}");
+            assert_eq!(Meta::DayNum.evaluate(&ctx), "02");
+        }
+    }
+
     // Test cases asserting that meta elements work as expected.
 
     use crate::content::{VolatileContent, ContentState};
-    use crate::template::helper::test_fill_out;
+    use crate::template::{CompilationSettings, Template};
+
+    // Fill out `template` with the clock fixed to `fixed_time` in the time zone
+    // `time_zone` or in the time zone of the template if it is not set
+    fn fill_out_in(template: &str, time_zone: Option<Tz>) -> String {
+        let settings = CompilationSettings {
+            clock: Clock::Fixed(fixed_time()),
+            time_zone,
+            ..Default::default()
+        };
+        Template::parse(template)
+            .unwrap()
+            .with_settings(settings)
+            .fill_out(VolatileContent::new(), ContentState::new())
+            .unwrap()
+    }
+
+    // Fill out `template` with the clock fixed to `fixed_time` in UTC
+    fn fill_out_fixed(template: &str) -> String {
+        fill_out_in(template, Some(Tz::UTC))
+    }
 
     #[test]
     fn meta_constants_are_recognised_and_evaluated() {
-        for meta_ident in META_IDENTS.iter() {
+        let locale = Locale::default();
+        let ctx = DynContext::new(&locale, Some(Tz::UTC), &Clock::Fixed(fixed_time()));
+        let idents = META_IDENTS.iter().filter(|ident| !META_RANDOM.contains(ident));
+        for meta_ident in idents {
             let meta_constant = &format!("${}", *meta_ident);
-            let expected = &Content::from(meta_ident.as_meta().unwrap());
-            test_fill_out(
-                meta_constant,
+            let expected = meta_ident.as_meta().unwrap().evaluate(&ctx);
+            assert_eq!(
+                fill_out_fixed(meta_constant),
                 expected,
-                &format!("Meta constant {meta_constant} evaluation"),
-                VolatileContent::new(),
-                ContentState::new(),
+                "Meta constant {meta_constant} evaluation"
             );
         }
     }
+
+    #[test]
+    fn meta_constants_use_the_clock() {
+        assert_eq!(
+            fill_out_fixed("$DayNum.$MonthNum.$Year $Hour:$Minute:$Second"),
+            "05.11.2023 23:59:59"
+        );
+    }
+
+    #[test]
+    fn meta_constants_use_the_template_locale() {
+        assert_eq!(fill_out_fixed("locale: de-DE\n$Day, $Month"), "Sonntag, November");
+    }
+
+    #[test]
+    fn meta_constants_use_the_time_zone() {
+        let template = "$Day $DayNum.$MonthNum. $Hour:$Minute";
+        let cases = [
+            (Tz::UTC, "Sunday 05.11. 23:59"),
+            (Tz::Europe__Berlin, "Monday 06.11. 00:59"),
+            (Tz::America__New_York, "Sunday 05.11. 18:59"),
+        ];
+        for (time_zone, expected) in cases {
+            assert_eq!(fill_out_in(template, Some(time_zone)), expected, "In {time_zone}");
+        }
+    }
+
+    #[test]
+    fn settings_take_precedence_over_the_template_time_zone() {
+        let template = "timezone: Europe/Berlin\n$DayNum $Hour";
+        assert_eq!(fill_out_in(template, None), "06 00");
+        assert_eq!(fill_out_in(template, Some(Tz::Asia__Tokyo)), "06 08");
+        assert_eq!(fill_out_in(template, Some(Tz::UTC)), "05 23");
+    }
+
+    #[test]
+    fn formatted_dates_are_evaluated() {
+        let cases = [
+            ("$Date{%d.%m.%Y}", "05.11.2023"),
+            ("$Date{%F %H:%M} (Q$Date{%q})", "2023-11-05 23:59 (Q4)"),
+            ("locale: de-DE\n$Date{%A, %-d. %B %Y}", "Sonntag, 5. November 2023"),
+            ("locale: fr-FR\n$Date{%A %-d %B}", "dimanche 5 novembre"),
+        ];
+        for (template, expected) in cases {
+            assert_eq!(fill_out_fixed(template), expected, "Template {template:?}");
+        }
+    }
+
+    #[test]
+    fn relative_dates_are_evaluated() {
+        // The fixed time is a Sunday.
+        let cases = [
+            ("$Today", "11/05/2023"),
+            ("locale: de-DE\n$Today", "05.11.2023"),
+            ("$Today+14d{%F}", "2023-11-19"),
+            ("$Today-1m+1w{%F}", "2023-10-12"),
+            ("$NextMonday{%F}", "2023-11-06"),
+            ("$NextSunday{%F}", "2023-11-12"),
+            ("$PreviousFriday{%F}", "2023-11-03"),
+            ("locale: de-DE\nFällig am $Today+2w{%A, %-d. %B}", "Fällig am Sonntag, 19. November"),
+        ];
+        for (template, expected) in cases {
+            assert_eq!(fill_out_fixed(template), expected, "Template {template:?}");
+        }
+        // The date is moved in the time zone, where it is already Monday.
+        assert_eq!(
+            fill_out_in("$NextMonday{%F}", Some(chrono_tz::Europe::Berlin)),
+            "2023-11-13"
+        );
+    }
+
+    #[test]
+    fn calendar_meta_is_correct() {
+        let locale = Locale::default();
+        let check = |now: DateTime<Utc>, expected: [&str; 6]| {
+            let ctx = DynContext::new(&locale, Some(Tz::UTC), &Clock::Fixed(now));
+            let metas = [
+                Meta::Week,
+                Meta::IsoWeek,
+                Meta::IsoYear,
+                Meta::DayOfYear,
+                Meta::Quarter,
+                Meta::Timestamp,
+            ];
+            let values: Vec<Content> = metas.iter().map(|meta| meta.evaluate(&ctx)).collect();
+            assert_eq!(values, expected, "Time {now}");
+        };
+
+        check(fixed_time(), ["45", "44", "2023", "309", "4", "1699228799"]);
+        // Friday, 1st of January 2021 belongs to the last ISO week of 2020.
+        let new_year = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        check(new_year, ["00", "53", "2020", "001", "1", "1609459200"]);
+    }
+
+    #[test]
+    fn random_meta_is_in_range() {
+        let locale = Locale::default();
+        let ctx = DynContext::new(&locale, None, &Clock::System);
+        for _ in 0..100 {
+            let number: u64 = Meta::Random(1, 6).evaluate(&ctx).parse().unwrap();
+            assert!((1..=6).contains(&number));
+        }
+        assert_eq!(Meta::Random(7, 7).evaluate(&ctx), "7");
+
+        let uuid = Meta::Uuid.evaluate(&ctx);
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(uuid.chars().nth(14), Some('4'));
+        assert_ne!(uuid, Meta::Uuid.evaluate(&ctx));
+    }
 }
@@ -0,0 +1,167 @@
+//! Plausible mock content for previewing templates.
+//!
+//! The content is generated with a small pseudo random number generator
+//! whose output only depends on the seed and the identifier of each element,
+//! so the same seed always gives the same content, across platforms and
+//! versions, and adding an element to a template does not change the
+//! content generated for the other elements.
+use super::{ContentState, ContentType, RequiredContent, VolatileContent};
+
+const LOREM: [&str; 32] = [
+    "lorem",
+    "ipsum",
+    "dolor",
+    "sit",
+    "amet",
+    "consectetur",
+    "adipiscing",
+    "elit",
+    "sed",
+    "do",
+    "eiusmod",
+    "tempor",
+    "incididunt",
+    "ut",
+    "labore",
+    "et",
+    "dolore",
+    "magna",
+    "aliqua",
+    "enim",
+    "ad",
+    "minim",
+    "veniam",
+    "quis",
+    "nostrud",
+    "exercitation",
+    "ullamco",
+    "laboris",
+    "nisi",
+    "aliquip",
+    "ex",
+    "commodo",
+];
+
+// Range of the number of words in the content of a key
+const MIN_WORDS: u64 = 1;
+const MAX_WORDS: u64 = 4;
+
+// SplitMix64 pseudo random number generator
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    // Create a generator for the element `ident`
+    fn new(seed: u64, ident: &str) -> Self {
+        // FNV-1a hash of the identifier
+        let hash = ident.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+        Self(seed ^ hash)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Random number in `0..n`
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+// Generate a few words of lorem ipsum starting with a capital letter.
+fn lorem(rng: &mut SplitMix64) -> String {
+    let count = MIN_WORDS + rng.below(MAX_WORDS - MIN_WORDS + 1);
+    let mut words: Vec<String> = (0..count)
+        .map(|_| LOREM[rng.below(LOREM.len() as u64) as usize].to_owned())
+        .collect();
+    if let Some(first) = words.first_mut() {
+        first[..1].make_ascii_uppercase();
+    }
+    words.join(" ")
+}
+
+impl RequiredContent {
+    /// Generate volatile content for all required elements, e.g. to preview a template.
+    /// Every key gets a few words of lorem ipsum and every option gets a random choice
+    /// from `content_state`. Options without any choices are left out. The same `seed`
+    /// always generates the same content.
+    pub fn mock_volatile_content(
+        &self,
+        content_state: &ContentState,
+        seed: u64,
+    ) -> VolatileContent {
+        let mut vc = VolatileContent::new();
+        for (ident, _) in self.entries(ContentType::Key) {
+            let mut rng = SplitMix64::new(seed, ident);
+            vc.map_key(ident, &lorem(&mut rng));
+        }
+        for (ident, _) in self.entries(ContentType::Option) {
+            let mut choices: Vec<&String> = match content_state.options.get(ident) {
+                Some(choices) if !choices.is_empty() => choices.keys().collect(),
+                _ => continue,
+            };
+            // Sort the choices, so the result does not depend on the order of the hash map.
+            choices.sort_unstable();
+            let mut rng = SplitMix64::new(seed, ident);
+            let choice = choices[rng.below(choices.len() as u64) as usize];
+            vc.map_choice(ident, choice);
+        }
+
+        vc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content::{choice, ContentTokens};
+
+    fn required(template: &str) -> RequiredContent {
+        template.parse::<ContentTokens>().unwrap().draft()
+    }
+
+    fn content_state() -> ContentState {
+        let mut cs = ContentState::new();
+        for choice in ["a", "b", "c", "d"] {
+            cs.map_option("greeting", choice!(choice, choice.to_uppercase()));
+        }
+        cs
+    }
+
+    #[test]
+    fn mock_content_is_reproducible() {
+        let required = required("${greeting} {name}, {message:Hi} ${missing:bye}");
+        let cs = content_state();
+        let vc = required.mock_volatile_content(&cs, 42);
+        assert_eq!(vc, required.mock_volatile_content(&cs, 42));
+
+        assert_eq!(vc.keys.len(), 2);
+        assert!(vc.keys.values().all(|content| !content.is_empty()));
+        assert!(["a", "b", "c", "d"].contains(&vc.choices["greeting"].as_str()));
+        // There are no choices for the option `missing`, so its default is used.
+        assert!(!vc.choices.contains_key("missing"));
+    }
+
+    #[test]
+    fn mock_content_depends_on_the_seed() {
+        let required = required("{a} {b} {c} ${greeting}");
+        let cs = content_state();
+        let contents: Vec<_> = (0..8)
+            .map(|seed| required.mock_volatile_content(&cs, seed))
+            .collect();
+        assert!(contents.iter().any(|vc| *vc != contents[0]));
+    }
+
+    #[test]
+    fn mock_content_does_not_depend_on_other_elements() {
+        let cs = content_state();
+        let small = required("{name}").mock_volatile_content(&cs, 7);
+        let large = required("{name} {other} ${greeting}").mock_volatile_content(&cs, 7);
+        assert_eq!(small.keys["name"], large.keys["name"]);
+    }
+}
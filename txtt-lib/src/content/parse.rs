@@ -1,201 +1,247 @@
-use super::scan::{Action, ScanError, Scanner};
-use crate::content::{ContentToken, ContentTokens, Ident};
+use super::scan::{ScanError, Scanner};
+use super::syntax::{
+    ConstantNode, DefaultNode, KeyNode, LocaleNode, OptionNode, Span, SyntaxNode, SyntaxTree,
+    TimeZoneNode,
+};
+use super::date::{self, DateExpr};
+use super::DynamicNames;
+use chrono_tz::Tz;
 use log::debug;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
-use unic_locale::Locale;
 
-// template ::= <locale>? <item>+
-pub fn template(scanner: &mut Scanner) -> Result<ContentTokens, UserError> {
+const LOCALE_KEYWORD: &str = "locale";
+const TIME_ZONE_KEYWORD: &str = "timezone";
+
+// template ::= <locale>? <timezone>? <item>+
+pub fn template<'a>(scanner: &mut Scanner<'a>) -> Result<SyntaxTree<'a>, UserError> {
     debug!("Starting template");
 
-    let mut tokens = match locale(scanner) {
-        Ok(locale) => ContentTokens::from(locale),
+    let mut tree = match locale(scanner) {
+        Ok(header) => SyntaxTree::new(scanner.source(), Some(header)),
         Err(e) => {
-            let mut tokens = ContentTokens::new();
-            tokens.add_friendly(e); // Only raise a waring in case parsing the locale failed.
-                                    // The warning is rasied, because one might have tried to
-                                    // set the locale but failed. If the error was silent, the
-                                    // user would not know about the fact that en-US will be used.
-            tokens
+            let mut tree = SyntaxTree::new(scanner.source(), None);
+            tree.add_friendly(e); // Only raise a waring in case parsing the locale failed.
+                                  // The warning is rasied, because one might have tried to
+                                  // set the locale but failed. If the error was silent, the
+                                  // user would not know about the fact that en-US will be used.
+            tree
         }
     };
+    // The time zone setting is only tried if its keyword is found, so
+    // templates without it do not get another friendly error.
+    if scanner.peek_str(TIME_ZONE_KEYWORD) {
+        match time_zone(scanner) {
+            Ok(header) => tree.set_time_zone(header),
+            Err(e) => tree.add_friendly(e),
+        }
+    }
 
     let e = loop {
         match item(scanner) {
-            Ok(token) => tokens.push(token),
+            Ok(node) => tree.push(node),
             Err(e) => break e,
         }
     };
 
-    if tokens.len() > 0 && scanner.at_end() {
-        Ok(tokens)
+    if !tree.items().is_empty() && scanner.at_end() {
+        Ok(tree)
     } else {
         Err(e)
     }
 }
 
 // <item> ::= <key> | <option> | <constant> | <text>
-pub fn item(scanner: &mut Scanner) -> Result<ContentToken, UserError> {
-    scanner.begin();
-    let sequence = scanner.scan_seq(|sequence| match sequence {
-        "${" => Some(Action::Return),
-        "$" => Some(Action::Require('{')),
-        "{" => Some(Action::Return),
-        _ => Some(Action::Return),
-    });
-    scanner.abort();
-
-    debug!("Sequence: {:?}", &sequence);
-
-    match sequence {
-        Ok(sequence) => match sequence.as_str() {
-            "${" => match option(scanner) {
-                Ok(token) => Ok(token),
-                Err(e) => Err(e),
-            },
-            "$" => match constant(scanner) {
-                Ok(token) => Ok(token),
-                Err(e) => Err(e),
-            },
-            "{" => match key(scanner) {
-                Ok(token) => Ok(token),
-                Err(e) => Err(e),
-            },
-            _ => match text(scanner) {
-                Ok(text) => Ok(ContentToken::Text(text)),
-                Err(e) => Err(e),
-            },
-        },
-        Err(e) => Err(UserError {
-            parse_error: ParseError::LexicalError(e),
+pub fn item(scanner: &mut Scanner) -> Result<SyntaxNode, UserError> {
+    // Peeking is enough to decide which element comes next, so
+    // the scanner never has to back up before scanning it.
+    if scanner.peek_str("${") {
+        return option(scanner).map(SyntaxNode::Option);
+    }
+
+    match scanner.peek() {
+        Some('$') => constant(scanner).map(SyntaxNode::Constant),
+        Some('{') => key(scanner).map(SyntaxNode::Key),
+        Some(_) => text(scanner).map(SyntaxNode::Text),
+        None => Err(UserError {
+            parse_error: ParseError::LexicalError(ScanError::UnexpectedEndOfInput(
+                scanner.position(),
+            )),
             context: ContextMsg::EmptyInput,
             possible: PossibleMsg::None,
         }),
     }
 }
 
-pub fn locale(scanner: &mut Scanner) -> Result<Locale, UserError> {
-    debug!("Starting locale");
+// Spans of a setting in the header of a template, e.g. `locale: de-DE\n`
+struct SettingSpans {
+    keyword: Span,
+    ws_before: Span,
+    colon: Span,
+    ws_after: Span,
+    value: Span,
+    newline: Span,
+}
+
+// <setting> ::= <keyword> <ws>? ":" <ws>? <chars> "\n"
+// Scan a setting starting with `keyword` and convert its value using `parse`.
+fn setting<'a, T>(
+    scanner: &mut Scanner<'a>,
+    keyword: &str,
+    parse: impl FnOnce(&'a str) -> Result<T, UserError>,
+) -> Result<(SettingSpans, T), UserError> {
     scanner.begin();
-    // Locale keyword
-    const LOCALE_KEYWORD: &str = "locale";
-    if let Err(e) = scanner.take_str(LOCALE_KEYWORD) {
-        debug!("Didn't find locale keyword");
+    let start = scanner.pos();
+    if let Err(e) = scanner.take_str(keyword) {
+        debug!("Didn't find {keyword} keyword");
         let e = UserError {
             parse_error: ParseError::LexicalError(e),
-            context: ContextMsg::InvalidContainedIn(format!("keyword {LOCALE_KEYWORD}")),
-            possible: PossibleMsg::DidYouMean(LOCALE_KEYWORD.to_owned()),
+            context: ContextMsg::InvalidContainedIn(format!("keyword {keyword}")),
+            possible: PossibleMsg::DidYouMean(keyword.to_owned()),
         };
         return Err(e);
     }
+    let keyword_span = Span::new(start, scanner.pos());
+    // Scan any number if whitespace characters. Nothing will
+    // happen is none are encountered.
     let ws = |scanner: &mut Scanner| {
-        // Scan any number if whitespace characters. Nothing will
-        // happen is none are encountered.
-        scanner.begin();
-        if scanner
-            .scan(|symbol| match symbol {
-                any if any.is_whitespace() => Some(Action::Request),
-                _ => None,
-            })
-            .is_ok()
-        {
-            // The new scan layer at the start of this closure will not
-            // be commit or destroyed if the callback was successful.
-            // We commit here to continue after the last whitespace character.
-            scanner.commit();
-        }
+        let start = scanner.pos();
+        scanner.skip(char::is_whitespace);
+        Span::new(start, scanner.pos())
     };
 
     // Colon delimiter with optional whitespace on both sides
-    ws(scanner);
+    let ws_before = ws(scanner);
+    let start = scanner.pos();
     if let Err(e) = scanner.take(':') {
-        debug!("Failed to finish locale setting (Missing Colon)");
+        debug!("Failed to finish {keyword} setting (Missing Colon)");
         let e = UserError {
             parse_error: ParseError::LexicalError(e),
-            context: ContextMsg::InvalidContainedIn("locale setting".to_owned()),
-            possible: PossibleMsg::DidYouForget(
-                "to add a colon between the locale keyword and literal".to_owned(),
-            ),
+            context: ContextMsg::InvalidContainedIn(format!("{keyword} setting")),
+            possible: PossibleMsg::DidYouForget(format!(
+                "to add a colon between the {keyword} keyword and literal"
+            )),
         };
         return Err(e);
     };
-    ws(scanner);
+    let colon = Span::new(start, scanner.pos());
+    let ws_after = ws(scanner);
 
-    // Locale literal
+    // Value literal
+    let start = scanner.pos();
     let input = match chars(scanner) {
         Ok(chars) => chars,
         Err(e) => {
-            debug!("Didn't find potential locale");
+            debug!("Didn't find potential {keyword}");
             scanner.abort();
             return Err(e);
         }
     };
-    let locale = match unic_locale::parser::parse_locale(input) {
-        Ok(locale) => locale,
+    let value_span = Span::new(start, scanner.pos());
+    let value = match parse(input) {
+        Ok(value) => value,
         Err(e) => {
-            debug!("Found locale is invalid");
+            debug!("Found {keyword} is invalid");
             scanner.abort();
-            let e = UserError {
-                parse_error: ParseError::LocaleError(e),
-                context: ContextMsg::InvalidContainedIn("locale".to_owned()),
-                possible: PossibleMsg::None,
-            };
             return Err(e);
         }
     };
     // Terminating new-line character
+    let start = scanner.pos();
     if let Err(e) = scanner.take('\n') {
-        debug!("Failed to finish locale (Missing '\\n')");
+        debug!("Failed to finish {keyword} (Missing '\\n')");
         let e = UserError {
             parse_error: ParseError::LexicalError(e),
-            context: ContextMsg::InvalidClosingOf("locale".to_owned()),
-            possible: PossibleMsg::DidYouForget("a new line after the locale".to_owned()),
+            context: ContextMsg::InvalidClosingOf(keyword.to_owned()),
+            possible: PossibleMsg::DidYouForget(format!("a new line after the {keyword}")),
         };
         return Err(e);
     }
+    let newline = Span::new(start, scanner.pos());
     scanner.commit();
+    let spans = SettingSpans {
+        keyword: keyword_span,
+        ws_before,
+        colon,
+        ws_after,
+        value: value_span,
+        newline,
+    };
+    Ok((spans, value))
+}
+
+// <locale> ::= "locale" <ws>? ":" <ws>? <chars> "\n"
+pub fn locale(scanner: &mut Scanner) -> Result<LocaleNode, UserError> {
+    debug!("Starting locale");
+    let (spans, locale) = setting(scanner, LOCALE_KEYWORD, |input| {
+        unic_locale::parser::parse_locale(input).map_err(|e| UserError {
+            parse_error: ParseError::LocaleError(e),
+            context: ContextMsg::InvalidContainedIn("locale".to_owned()),
+            possible: PossibleMsg::None,
+        })
+    })?;
     debug!("Successfully finished locale");
-    Ok(locale)
+    Ok(LocaleNode {
+        keyword: spans.keyword,
+        ws_before: spans.ws_before,
+        colon: spans.colon,
+        ws_after: spans.ws_after,
+        value: spans.value,
+        newline: spans.newline,
+        locale,
+    })
+}
+
+// <timezone> ::= "timezone" <ws>? ":" <ws>? <chars> "\n"
+pub fn time_zone(scanner: &mut Scanner) -> Result<TimeZoneNode, UserError> {
+    debug!("Starting time zone");
+    let (spans, time_zone) = setting(scanner, TIME_ZONE_KEYWORD, |input| {
+        input.parse::<Tz>().map_err(|_| UserError {
+            parse_error: ParseError::UnknownTimeZone(input.to_owned()),
+            context: ContextMsg::InvalidContainedIn("time zone".to_owned()),
+            possible: PossibleMsg::None,
+        })
+    })?;
+    debug!("Successfully finished time zone");
+    Ok(TimeZoneNode {
+        keyword: spans.keyword,
+        ws_before: spans.ws_before,
+        colon: spans.colon,
+        ws_after: spans.ws_after,
+        value: spans.value,
+        newline: spans.newline,
+        time_zone,
+    })
 }
 
 // <text> ::= (<chars> | <ws>)+
 // <ws>   ::= (" " | "\t" | "\n")+
 // <chars> ::= ([A-Z] | [a-z])+
-pub fn text(scanner: &mut Scanner) -> Result<String, UserError> {
+pub fn text(scanner: &mut Scanner) -> Result<Span, UserError> {
     debug!("Starting text");
     scanner.begin();
 
-    let text = match scanner.scan(|symbol| match symbol {
-        any if !any.is_terminal() => Some(Action::Request),
-        _ => None,
-    }) {
-        Ok(text) => text,
-        Err(e) => {
-            debug!("Failed to finish text ");
-            let e = UserError {
-                parse_error: ParseError::LexicalError(e),
-                context: ContextMsg::InvalidContainedIn("text section".to_owned()),
-                possible: PossibleMsg::ForbiddenAre("'{', '}' or '$'".to_owned()),
-            };
-            return Err(e);
-        }
+    let start = scanner.pos();
+    if let Err(e) = scanner.scan(|symbol| !symbol.is_terminal()) {
+        debug!("Failed to finish text ");
+        let e = UserError {
+            parse_error: ParseError::LexicalError(e),
+            context: ContextMsg::InvalidContainedIn("text section".to_owned()),
+            possible: PossibleMsg::ForbiddenAre("'{', '}' or '$'".to_owned()),
+        };
+        return Err(e);
     };
     scanner.commit();
     debug!("Successfully finished text");
-    Ok(text)
+    Ok(Span::new(start, scanner.pos()))
 }
 
 // <chars> ::= *any characters except for the terminals and whitespace*
-pub fn chars(scanner: &mut Scanner) -> Result<String, UserError> {
+pub fn chars<'a>(scanner: &mut Scanner<'a>) -> Result<&'a str, UserError> {
     debug!("Starting characters");
     scanner.begin();
 
-    let chars = match scanner.scan(|symbol| match symbol {
-        any if any.is_whitespace() => None,
-        any if !any.is_terminal() => Some(Action::Request),
-        _ => None,
-    }) {
+    let chars = match scanner.scan(|symbol| !symbol.is_whitespace() && !symbol.is_terminal()) {
         Ok(chars) => chars,
         Err(e) => {
             debug!("Failed to finish chars");
@@ -215,9 +261,10 @@ pub fn chars(scanner: &mut Scanner) -> Result<String, UserError> {
 }
 
 // key ::= "{" <ident> "}"
-pub fn key(scanner: &mut Scanner) -> Result<ContentToken, UserError> {
+pub fn key(scanner: &mut Scanner) -> Result<KeyNode, UserError> {
     debug!("Starting key");
     scanner.begin();
+    let start = scanner.pos();
     if let Err(e) = scanner.take(Terminals::LBrace.into()) {
         debug!("Failed to finish key (Missing LBrace)");
         let e = UserError {
@@ -227,6 +274,7 @@ pub fn key(scanner: &mut Scanner) -> Result<ContentToken, UserError> {
         };
         return Err(e);
     }
+    let lbrace = Span::new(start, scanner.pos());
     let ident = match ident(scanner) {
         Ok(ident) => ident,
         Err(e) => {
@@ -240,12 +288,13 @@ pub fn key(scanner: &mut Scanner) -> Result<ContentToken, UserError> {
         }
     };
     let default = match default(scanner) {
-        Ok(default) => default.map(Box::new),
+        Ok(default) => default,
         Err(e) => {
             debug!("Failed to finish key (incorrect default)");
             return Err(e);
         }
     };
+    let start = scanner.pos();
     if let Err(e) = scanner.take(Terminals::RBrace.into()) {
         debug!("Failed to finish key (Missing RBrace)");
         let e = UserError {
@@ -255,21 +304,32 @@ pub fn key(scanner: &mut Scanner) -> Result<ContentToken, UserError> {
         };
         return Err(e);
     }
+    let rbrace = Span::new(start, scanner.pos());
     scanner.commit();
     debug!("Successfully finished key");
-    Ok(ContentToken::Key(ident, default))
+    Ok(KeyNode {
+        lbrace,
+        ident,
+        default,
+        rbrace,
+    })
 }
 
 // <default> ::= ":" <item>
-pub fn default(scanner: &mut Scanner) -> Result<Option<ContentToken>, UserError> {
+pub fn default(scanner: &mut Scanner) -> Result<Option<DefaultNode>, UserError> {
     debug!("Starting default");
-    scanner.begin();
-    if scanner.take(':').is_err() {
+    if scanner.peek() != Some(':') {
         debug!("Failed to finish default (Missing colon)");
         return Ok(None);
     }
-    let token = match item(scanner) {
-        Ok(token) => token,
+    scanner.begin();
+    let start = scanner.pos();
+    if let Err(e) = scanner.take(':') {
+        return Err(ParseError::LexicalError(e).into());
+    }
+    let colon = Span::new(start, scanner.pos());
+    let node = match item(scanner) {
+        Ok(node) => node,
         Err(mut e) => {
             debug!("Failed to finish default (incorrect item)");
             e.context = ContextMsg::InvalidContainedIn("default for key".to_owned());
@@ -278,31 +338,35 @@ pub fn default(scanner: &mut Scanner) -> Result<Option<ContentToken>, UserError>
     };
     scanner.commit();
     debug!("Successfully finished default");
-    Ok(Some(token))
+    Ok(Some(DefaultNode {
+        colon,
+        item: Box::new(node),
+    }))
 }
 
 // <ident> ::= (<char> | [0-9])+
 // <char> ::= ([A-Z] | [a-z])
-pub fn ident(scanner: &mut Scanner) -> Result<Ident, ParseError> {
+pub fn ident(scanner: &mut Scanner) -> Result<Span, ParseError> {
     debug!("Starting ident");
-    let ident = match scanner.scan(|symbol| match symbol as u8 {
-        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => Some(Action::Request),
-        _ => None,
-    }) {
-        Ok(ident) => ident,
-        Err(e) => {
-            debug!("Failed to finish ident");
-            return Err(ParseError::LexicalError(e));
-        }
+    let start = scanner.pos();
+    if let Err(e) = scanner.scan(|symbol| symbol.is_ident()) {
+        debug!("Failed to finish ident");
+        return Err(ParseError::LexicalError(e));
     };
     debug!("Successfully finished ident");
-    Ok(ident) // No `Ident::from` required because `Ident` is the same as `String`
+    Ok(Span::new(start, scanner.pos()))
+}
+
+// Check if `s` is an identifier which `ident` would accept
+pub fn is_ident(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|symbol| symbol.is_ident())
 }
 
 // <option> ::= "$" <key>
-pub fn option(scanner: &mut Scanner) -> Result<ContentToken, UserError> {
+pub fn option(scanner: &mut Scanner) -> Result<OptionNode, UserError> {
     debug!("Starting options");
     scanner.begin();
+    let start = scanner.pos();
     if let Err(e) = scanner.take(Terminals::Cash.into()) {
         debug!("Failed to finish options (Missing Cash)");
         let e = UserError {
@@ -312,8 +376,9 @@ pub fn option(scanner: &mut Scanner) -> Result<ContentToken, UserError> {
         };
         return Err(e);
     }
+    let cash = Span::new(start, scanner.pos());
     let key = match key(scanner) {
-        Ok(ident) => ident,
+        Ok(key) => key,
         Err(mut e) => {
             debug!("Failed to finish options (incorrect ident)");
             e.context = ContextMsg::InvalidContainedIn("identifier of option".to_owned());
@@ -322,14 +387,14 @@ pub fn option(scanner: &mut Scanner) -> Result<ContentToken, UserError> {
     };
     scanner.commit();
     debug!("Successfully finished option");
-    Ok(ContentToken::Option(Box::new(key)))
+    Ok(OptionNode { cash, key })
 }
 
-// <constant> ::= "$" <ident>
-pub fn constant(scanner: &mut Scanner) -> Result<ContentToken, UserError> {
+// <constant> ::= "$" "@"? <ident>
+pub fn constant(scanner: &mut Scanner) -> Result<ConstantNode, UserError> {
     debug!("Starting constant");
-    debug!("Scanner is at: {}", scanner.current_char().unwrap());
     scanner.begin();
+    let start = scanner.pos();
     if let Err(e) = scanner.take(Terminals::Cash.into()) {
         debug!("Failed to finish constant (Missing Cash)");
         let e = UserError {
@@ -339,7 +404,18 @@ pub fn constant(scanner: &mut Scanner) -> Result<ContentToken, UserError> {
         };
         return Err(e);
     }
-    let ident = match ident(scanner) {
+    let cash = Span::new(start, scanner.pos());
+    // The sigil of dynamic elements is part of the identifier.
+    let sigil_start = scanner.pos();
+    let sigil = scanner.peek_str(DYN_SIGIL);
+    if sigil {
+        scanner.take_str(DYN_SIGIL).map_err(|e| UserError {
+            parse_error: ParseError::LexicalError(e),
+            context: ContextMsg::InvalidContainedIn("identifer of constant".to_owned()),
+            possible: PossibleMsg::None,
+        })?;
+    }
+    let mut ident = match ident(scanner) {
         Ok(ident) => ident,
         Err(e) => {
             debug!("Failed to finish constant (incorrect ident)");
@@ -351,9 +427,272 @@ pub fn constant(scanner: &mut Scanner) -> Result<ContentToken, UserError> {
             return Err(e);
         }
     };
+    let name = &scanner.source()[ident.start..ident.end];
+    ident.start = sigil_start;
+    // Date expressions and random numbers are only read where a dynamic element
+    // may be written, so they do not change what follows a regular constant.
+    let dynamic = cfg!(feature = "dyn")
+        && (sigil || scanner.dynamic_names() == DynamicNames::Bare);
+    if dynamic && date::is_base(name) {
+        ident = date_expression(scanner, ident)?;
+    } else if dynamic && name == RANDOM_IDENT {
+        ident = random_range(scanner, ident)?;
+    } else if is_namespace(scanner, name) {
+        ident = member(scanner, ident)?;
+    }
     scanner.commit();
     debug!("Successfully finished constant");
-    Ok(ContentToken::Constant(ident))
+    Ok(ConstantNode { cash, ident })
+}
+
+// <date_expression> ::= (("+" | "-") <digit>+ ("d" | "w" | "m"))* <date_format>?
+// Scan the offsets and the format behind the base of a date expression, which
+// become part of the identifier of the constant. Keys can not start with `%`
+// and offsets have to end with their unit, so the meaning of templates which
+// do not use date expressions does not change.
+// Returns `base` if there are neither offsets nor a format or if they do not
+// form a valid date expression.
+fn date_expression(scanner: &mut Scanner, base: Span) -> Result<Span, UserError> {
+    debug!("Starting date expression");
+    scanner.begin();
+    let mut offsets = 0;
+    loop {
+        let rest = &scanner.source()[scanner.pos()..];
+        match date::offset_len(rest) {
+            Some(_) if offsets == date::MAX_OFFSETS => {
+                debug!("Found too many date offsets");
+                let e = UserError {
+                    parse_error: ParseError::TooManyDateOffsets,
+                    context: ContextMsg::InvalidContainedIn("date expression".to_owned()),
+                    possible: PossibleMsg::None,
+                };
+                return Err(e);
+            }
+            // The offset was found in the source, so taking it can not fail.
+            Some(len) => scanner.take_str(&rest[..len]).map_err(|e| UserError {
+                parse_error: ParseError::LexicalError(e),
+                context: ContextMsg::InvalidContainedIn("date offset".to_owned()),
+                possible: PossibleMsg::None,
+            })?,
+            None => break,
+        }
+        offsets += 1;
+    }
+    if scanner.peek_str("{%") {
+        date_format(scanner)?;
+    }
+
+    let expression = Span::new(base.start, scanner.pos());
+    let source = &scanner.source()[expression.start..expression.end];
+    if DateExpr::parse(without_sigil(source)).is_none() {
+        // E.g. `$Date+1d` without a format is a constant followed by text.
+        debug!("Date expression is incomplete");
+        scanner.abort();
+        return Ok(base);
+    }
+    scanner.commit();
+    debug!("Successfully finished date expression");
+    Ok(expression)
+}
+
+/// Check if `$ident` directly followed by `text` is parsed as the constant `ident`
+/// in a template using `namespaces` and `names`. With an empty `text`, this checks
+/// if `ident` is the identifier of a constant.
+pub fn is_constant_before(
+    ident: &str,
+    text: &str,
+    namespaces: &[String],
+    names: DynamicNames,
+) -> bool {
+    let source = format!("${ident}{text}");
+    let mut scanner = Scanner::with_namespaces(&source, namespaces).with_names(names);
+    match constant(&mut scanner) {
+        Ok(node) => &source[node.ident.start..node.ident.end] == ident,
+        Err(_) => false,
+    }
+}
+
+/// Check if `text` at the start of a template without a time zone setting is
+/// parsed as the time zone setting, e.g. `timezone: Europe/Berlin\n`.
+pub fn is_time_zone_setting(text: &str) -> bool {
+    let mut scanner = Scanner::new(text);
+    scanner.peek_str(TIME_ZONE_KEYWORD) && time_zone(&mut scanner).is_ok()
+}
+
+/// Sigil in front of the identifiers of dynamic elements, e.g. `$@Year`.
+pub const DYN_SIGIL: &str = "@";
+
+/// Get the name of a dynamic element without its sigil, e.g. `Year` for `@Year`.
+pub fn without_sigil(ident: &str) -> &str {
+    ident.strip_prefix(DYN_SIGIL).unwrap_or(ident)
+}
+
+/// Get the name of the constant which the dynamic element `ident` replaces if
+/// it is written without its sigil, e.g. `Today` for `Today+14d`, whose offsets
+/// would otherwise be text behind the constant `Today`.
+pub fn bare_name(ident: &str) -> &str {
+    let end = ident
+        .find(|c: char| !c.is_ascii_alphanumeric())
+        .unwrap_or(ident.len());
+    let name = &ident[..end];
+    match date::is_base(name) || name == RANDOM_IDENT {
+        true => name,
+        false => ident,
+    }
+}
+
+#[cfg(feature = "env")]
+const ENV_IDENT: &str = "Env";
+
+// Check if the identifier of the constant `name` is followed by the name of one
+// of its members, e.g. an environment variable in `$Env.USER`. Besides `Env`,
+// which is only a namespace if the "env" feature is enabled, the namespaces are
+// declared by the providers of dynamic elements, e.g. `Counter` for `$Counter.invoice`.
+fn is_namespace(scanner: &Scanner, name: &str) -> bool {
+    #[cfg(feature = "env")]
+    if name == ENV_IDENT {
+        return true;
+    }
+    scanner.namespaces().iter().any(|namespace| namespace == name)
+}
+
+// <member> ::= "." (<char> | <digit> | "_")+
+// Scan the name of a member of a namespace, which becomes part of the identifier
+// of the constant. Returns `base` if there is no name, so `$Env.` is still a
+// constant followed by text.
+fn member(scanner: &mut Scanner, base: Span) -> Result<Span, UserError> {
+    let rest = &scanner.source()[scanner.pos()..];
+    let len = match rest.strip_prefix('.') {
+        Some(name) => 1 + name.find(|c| !is_member_char(c)).unwrap_or(name.len()),
+        None => return Ok(base),
+    };
+    if len == 1 {
+        return Ok(base);
+    }
+    debug!("Starting member");
+    // The name was found in the source, so taking it can not fail.
+    scanner.take_str(&rest[..len]).map_err(|e| UserError {
+        parse_error: ParseError::LexicalError(e),
+        context: ContextMsg::InvalidContainedIn("member name".to_owned()),
+        possible: PossibleMsg::None,
+    })?;
+    debug!("Successfully finished member");
+    Ok(Span::new(base.start, scanner.pos()))
+}
+
+fn is_member_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Get the name of the environment variable in an identifier like `Env.USER`.
+#[cfg(feature = "env")]
+pub fn env_var_of(ident: &str) -> Option<&str> {
+    ident
+        .strip_prefix(ENV_IDENT)?
+        .strip_prefix('.')
+        .filter(|name| !name.is_empty() && name.chars().all(is_member_char))
+}
+
+const RANDOM_IDENT: &str = "Random";
+
+// <random_range> ::= "{" <digit>+ "-" <digit>+ "}"
+// Scan the range of a random number, which becomes part of the identifier
+// of the constant. Returns `base` if there is no range, so `$Random{name}`
+// is still a constant followed by a key.
+fn random_range(scanner: &mut Scanner, base: Span) -> Result<Span, UserError> {
+    let rest = &scanner.source()[scanner.pos()..];
+    let Some(len) = range_len(rest) else {
+        return Ok(base);
+    };
+    let Some((min, max)) = range(&rest[..len]) else {
+        return Ok(base);
+    };
+    debug!("Starting random range");
+    if min > max {
+        debug!("Found random range is empty");
+        let e = UserError {
+            parse_error: ParseError::EmptyRandomRange(min, max),
+            context: ContextMsg::InvalidContainedIn("random range".to_owned()),
+            possible: PossibleMsg::None,
+        };
+        return Err(e);
+    }
+    // The range was found in the source, so taking it can not fail.
+    scanner.take_str(&rest[..len]).map_err(|e| UserError {
+        parse_error: ParseError::LexicalError(e),
+        context: ContextMsg::InvalidContainedIn("random range".to_owned()),
+        possible: PossibleMsg::None,
+    })?;
+    debug!("Successfully finished random range");
+    Ok(Span::new(base.start, scanner.pos()))
+}
+
+// Get the length of a range like `{1-6}` at the start of `s`.
+// Only the digits of the range are looked at, not the rest of the source.
+fn range_len(s: &str) -> Option<usize> {
+    let digits = |s: &str| s.bytes().take_while(u8::is_ascii_digit).count();
+    let rest = s.strip_prefix('{')?;
+    let min = digits(rest);
+    let rest = rest[min..].strip_prefix('-')?;
+    let max = digits(rest);
+    rest[max..].starts_with('}').then_some(min + max + 3)
+}
+
+// Get the bounds of a range like `{1-6}`
+fn range(s: &str) -> Option<(u64, u64)> {
+    let (min, max) = s.strip_prefix('{')?.strip_suffix('}')?.split_once('-')?;
+    let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    if !digits(min) || !digits(max) {
+        return None;
+    }
+    Some((min.parse().ok()?, max.parse().ok()?))
+}
+
+/// Get the bounds of the range in the identifier of a random number, e.g. `Random{1-6}`.
+#[cfg(feature = "dyn")]
+pub fn random_range_of(ident: &str) -> Option<(u64, u64)> {
+    range(ident.strip_prefix(RANDOM_IDENT)?).filter(|(min, max)| min <= max)
+}
+
+// <date_format> ::= "{" "%" <chars and whitespace> "}"
+// Scan the format of a date expression and make sure chrono can use it.
+fn date_format(scanner: &mut Scanner) -> Result<Span, UserError> {
+    debug!("Starting date format");
+    scanner.begin();
+    let start = scanner.pos();
+    let lexical_error = |e| UserError {
+        parse_error: ParseError::LexicalError(e),
+        context: ContextMsg::InvalidContainedIn("date format".to_owned()),
+        possible: PossibleMsg::ForbiddenAre("'{', '}' or '$'".to_owned()),
+    };
+    scanner
+        .take(Terminals::LBrace.into())
+        .map_err(lexical_error)?;
+    let format = scanner
+        .scan(|symbol| !symbol.is_terminal())
+        .map_err(lexical_error)?;
+    if let Err(e) = scanner.take(Terminals::RBrace.into()) {
+        debug!("Failed to finish date format (Missing RBrace)");
+        let e = UserError {
+            parse_error: ParseError::LexicalError(e),
+            context: ContextMsg::InvalidClosingOf("date format".to_owned()),
+            possible: PossibleMsg::DidYouForget("'}'".to_owned()),
+        };
+        return Err(e);
+    }
+    if !date::is_date_format(format) {
+        debug!("Found date format is invalid");
+        scanner.abort();
+        let e = UserError {
+            parse_error: ParseError::InvalidDateFormat(format.to_owned()),
+            context: ContextMsg::InvalidContainedIn("date format".to_owned()),
+            possible: PossibleMsg::None,
+        };
+        return Err(e);
+    }
+    scanner.commit();
+    debug!("Successfully finished date format");
+    Ok(Span::new(start, scanner.pos()))
 }
 
 // Terminal-symbol representation
@@ -374,15 +713,18 @@ impl From<Terminals> for char {
 // Trait which can be implementend on any potential terminal or non-terminal symbol
 pub trait Symbol {
     fn is_terminal(&self) -> bool;
-    fn is_non_terminal(&self) -> bool {
-        !self.is_terminal()
-    }
+    // Check if the symbol may be part of an identifier
+    fn is_ident(&self) -> bool;
 }
 
 impl Symbol for char {
     fn is_terminal(&self) -> bool {
         matches!(self, '{' | '}' | '$')
     }
+
+    fn is_ident(&self) -> bool {
+        self.is_ascii_alphanumeric()
+    }
 }
 
 #[derive(Debug)]
@@ -485,14 +827,61 @@ pub enum ParseError {
     #[error(transparent)]
     LexicalError(#[from] ScanError),
     #[error(transparent)]
-    #[cfg_attr(feature = "serde", serde(skip_serializing, skip_deserializing))]
+    #[cfg_attr(feature = "serde", serde(with = "locale_error"))]
     LocaleError(#[from] unic_locale::parser::ParserError),
+    #[error("Unknown time zone {0:?}")]
+    UnknownTimeZone(String),
+    #[error("Invalid date format {0:?}")]
+    InvalidDateFormat(String),
+    #[error("The random range from {0} to {1} is empty")]
+    EmptyRandomRange(u64, u64),
+    #[error("A date expression has more than {} offsets", date::MAX_OFFSETS)]
+    TooManyDateOffsets,
+}
+
+// The errors of the locale parser can not be serialised, so only the part
+// of the locale which is invalid is. An invalid language identifier in an extension is read back as an
+// invalid language.
+#[cfg(feature = "serde")]
+mod locale_error {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use unic_locale::parser::ParserError;
+
+    #[derive(Serialize, Deserialize)]
+    enum Kind {
+        Language,
+        Subtag,
+        Extension,
+    }
+
+    pub fn serialize<S: Serializer>(e: &ParserError, serializer: S) -> Result<S::Ok, S::Error> {
+        let kind = match e {
+            ParserError::InvalidLanguage | ParserError::LangIdError(_) => Kind::Language,
+            ParserError::InvalidSubtag => Kind::Subtag,
+            ParserError::InvalidExtension => Kind::Extension,
+        };
+        kind.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ParserError, D::Error> {
+        Ok(match Kind::deserialize(deserializer)? {
+            Kind::Language => ParserError::InvalidLanguage,
+            Kind::Subtag => ParserError::InvalidSubtag,
+            Kind::Extension => ParserError::InvalidExtension,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use once_cell::sync::Lazy;
+    use crate::content::ContentTokens;
+    use unic_locale::Locale;
+
+    // `template` with the lifetime of the syntax tree erased so it fits the test helpers
+    fn template(scanner: &mut Scanner) -> Result<(), UserError> {
+        super::template(scanner).map(drop)
+    }
 
     mod correct {
         use super::*;
@@ -515,10 +904,11 @@ mod tests {
             ];
             for (variant, case, locale_str) in cases {
                 let mut scanner = Scanner::new(variant);
-                let locale_result = locale(&mut scanner).expect(&format!(
-                    "Valid locale setting was falsely rejected. Case: {}",
-                    case
-                ));
+                let locale_result = locale(&mut scanner)
+                    .unwrap_or_else(|_| {
+                        panic!("Valid locale setting was falsely rejected. Case: {}", case)
+                    })
+                    .locale;
                 let locale_expected: Locale = locale_str.parse().unwrap();
                 assert_eq!(
                     locale_result, locale_expected,
@@ -533,9 +923,242 @@ mod tests {
             assert_eq!(expected_locale, content_tokens.locale);
         }
 
+        #[test]
+        #[cfg(feature = "dyn")]
+        fn date_formats_are_accepted() {
+            use crate::content::ContentToken;
+
+            let cases = vec![
+                ("$Date{%d.%m.%Y}", vec![ContentToken::Constant("Date{%d.%m.%Y}".into())]),
+                (
+                    "$Date{%A, %-d. %B} {x:$Date{%F}}",
+                    vec![
+                        ContentToken::Constant("Date{%A, %-d. %B}".into()),
+                        ContentToken::Text(" ".into()),
+                        ContentToken::Key(
+                            "x".into(),
+                            Some(Box::new(ContentToken::Constant("Date{%F}".into()))),
+                        ),
+                    ],
+                ),
+                // Keys behind a constant named `Date` keep their meaning.
+                (
+                    "$Date{name}",
+                    vec![
+                        ContentToken::Constant("Date".into()),
+                        ContentToken::Key("name".into(), None),
+                    ],
+                ),
+            ];
+            for (template, expected) in cases {
+                let content_tokens: ContentTokens = template.parse().unwrap();
+                let mut expected_tokens = ContentTokens::new();
+                for token in expected {
+                    expected_tokens.push(token);
+                }
+                assert_eq!(content_tokens, expected_tokens, "Template {template:?}");
+            }
+        }
+
+        #[test]
+        #[cfg(feature = "dyn")]
+        fn date_expressions_are_accepted() {
+            use crate::content::ContentToken;
+
+            let cases = vec![
+                ("$Today+14d{%d.%m.%Y}", vec![ContentToken::Constant("Today+14d{%d.%m.%Y}".into())]),
+                (
+                    "Due $NextMonday-1w+2d, $Date+1m{%F}.",
+                    vec![
+                        ContentToken::Text("Due ".into()),
+                        ContentToken::Constant("NextMonday-1w+2d".into()),
+                        ContentToken::Text(", ".into()),
+                        ContentToken::Constant("Date+1m{%F}".into()),
+                        ContentToken::Text(".".into()),
+                    ],
+                ),
+                // Text which is not an offset is not part of the expression.
+                (
+                    "$Today+1mile",
+                    vec![
+                        ContentToken::Constant("Today".into()),
+                        ContentToken::Text("+1mile".into()),
+                    ],
+                ),
+                (
+                    "$Date+1d",
+                    vec![
+                        ContentToken::Constant("Date".into()),
+                        ContentToken::Text("+1d".into()),
+                    ],
+                ),
+                (
+                    "$Tomorrow+1d",
+                    vec![
+                        ContentToken::Constant("Tomorrow".into()),
+                        ContentToken::Text("+1d".into()),
+                    ],
+                ),
+            ];
+            for (template, expected) in cases {
+                let content_tokens: ContentTokens = template.parse().unwrap();
+                let mut expected_tokens = ContentTokens::new();
+                for token in expected {
+                    expected_tokens.push(token);
+                }
+                assert_eq!(content_tokens, expected_tokens, "Template {template:?}");
+            }
+        }
+
+        #[test]
+        #[cfg(feature = "dyn")]
+        fn namespaced_date_expressions_require_the_sigil() {
+            use crate::content::ContentToken;
+
+            let template = "$Today+14d and $@Today+14d.";
+            let content_tokens =
+                ContentTokens::parse_with(template, vec![], DynamicNames::Namespaced).unwrap();
+            let expected = vec![
+                ContentToken::Constant("Today".into()),
+                ContentToken::Text("+14d and ".into()),
+                ContentToken::Constant("@Today+14d".into()),
+                ContentToken::Text(".".into()),
+            ];
+            assert_eq!(content_tokens.tokens, expected);
+        }
+
+        #[test]
+        fn dyn_sigils_are_accepted() {
+            use crate::content::ContentToken;
+
+            let cases = vec![
+                ("$@Year", vec![ContentToken::Constant("@Year".into())]),
+                #[cfg(feature = "dyn")]
+                (
+                    "$@Today+1w{%F}, $@Random{1-6}",
+                    vec![
+                        ContentToken::Constant("@Today+1w{%F}".into()),
+                        ContentToken::Text(", ".into()),
+                        ContentToken::Constant("@Random{1-6}".into()),
+                    ],
+                ),
+                #[cfg(feature = "env")]
+                ("$@Env.USER", vec![ContentToken::Constant("@Env.USER".into())]),
+                // The sigil can be used for any constant.
+                ("$@Me", vec![ContentToken::Constant("@Me".into())]),
+            ];
+            for (template, expected) in cases {
+                let content_tokens: ContentTokens = template.parse().unwrap();
+                let mut expected_tokens = ContentTokens::new();
+                for token in expected {
+                    expected_tokens.push(token);
+                }
+                assert_eq!(content_tokens, expected_tokens, "Template {template:?}");
+            }
+            assert!("Mail me @ $@ home".parse::<ContentTokens>().is_err());
+        }
+
+        #[test]
+        fn members_are_accepted() {
+            use crate::content::ContentToken;
+
+            let cases = vec![
+                ("$Counter.invoice", vec![ContentToken::Constant("Counter.invoice".into())]),
+                (
+                    "Hi $Counter.MY_COUNTER_1!",
+                    vec![
+                        ContentToken::Text("Hi ".into()),
+                        ContentToken::Constant("Counter.MY_COUNTER_1".into()),
+                        ContentToken::Text("!".into()),
+                    ],
+                ),
+                (
+                    "$Counter. x",
+                    vec![
+                        ContentToken::Constant("Counter".into()),
+                        ContentToken::Text(". x".into()),
+                    ],
+                ),
+                // Only declared namespaces have members.
+                (
+                    "$Invoice.Then",
+                    vec![
+                        ContentToken::Constant("Invoice".into()),
+                        ContentToken::Text(".Then".into()),
+                    ],
+                ),
+                #[cfg(feature = "env")]
+                ("$Env.USER", vec![ContentToken::Constant("Env.USER".into())]),
+                #[cfg(not(feature = "env"))]
+                (
+                    "$Env.Then",
+                    vec![
+                        ContentToken::Constant("Env".into()),
+                        ContentToken::Text(".Then".into()),
+                    ],
+                ),
+            ];
+            for (template, expected) in cases {
+                let namespaces = vec!["Counter".into()];
+                let content_tokens =
+                    ContentTokens::parse_with(template, namespaces, DynamicNames::Bare).unwrap();
+                let mut expected_tokens = ContentTokens::new();
+                for token in expected {
+                    expected_tokens.push(token);
+                }
+                assert_eq!(content_tokens, expected_tokens, "Template {template:?}");
+            }
+            // Without the namespace, the name of the member is text.
+            let content_tokens: ContentTokens = "$Counter.invoice".parse().unwrap();
+            assert_eq!(content_tokens.tokens[0], ContentToken::Constant("Counter".into()));
+        }
+
+        #[test]
+        #[cfg(feature = "dyn")]
+        fn random_ranges_are_accepted() {
+            use crate::content::ContentToken;
+
+            let cases = vec![
+                ("$Random{1-6}", vec![ContentToken::Constant("Random{1-6}".into())]),
+                ("$Random{0-0}", vec![ContentToken::Constant("Random{0-0}".into())]),
+                // Keys behind a constant named `Random` keep their meaning.
+                (
+                    "$Random{name}",
+                    vec![
+                        ContentToken::Constant("Random".into()),
+                        ContentToken::Key("name".into(), None),
+                    ],
+                ),
+            ];
+            for (template, expected) in cases {
+                let content_tokens: ContentTokens = template.parse().unwrap();
+                let mut expected_tokens = ContentTokens::new();
+                for token in expected {
+                    expected_tokens.push(token);
+                }
+                assert_eq!(content_tokens, expected_tokens, "Template {template:?}");
+            }
+        }
+
+        #[test]
+        fn time_zones_are_accepted_and_correct() {
+            let cases = vec![
+                ("locale: de-DE\ntimezone: Europe/Berlin\n$Day", Some(chrono_tz::Europe::Berlin)),
+                ("timezone :\tAmerica/Argentina/Buenos_Aires\n$Day", Some(chrono_tz::America::Argentina::Buenos_Aires)),
+                ("$Day", None),
+                // Unknown time zones and settings in the wrong order are just text.
+                ("timezone: Mars/Olympus\n$Day", None),
+                ("timezone: UTC\nlocale: de-DE\n$Day", Some(chrono_tz::UTC)),
+            ];
+            for (template, expected) in cases {
+                let content_tokens: ContentTokens = template.parse().unwrap();
+                assert_eq!(content_tokens.time_zone, expected, "Template {template:?}");
+            }
+        }
+
         #[test]
         fn defaults_are_accepted() {
-            Lazy::force(&helper::LOGGING);
+            crate::test_log::init();
             let key_defaults = vec![
                 "{name:hallo}",              // `text` default for key
                 "{name:$Me}",                // `constant` default for key
@@ -561,7 +1184,7 @@ mod tests {
             helper::test_correct_variants(ident, idents);
 
             let all_symbols = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
-            let mut scanner = Scanner::new(&all_symbols);
+            let mut scanner = Scanner::new(all_symbols);
             assert!(ident(&mut scanner).is_ok());
         }
 
@@ -573,14 +1196,14 @@ mod tests {
 
         #[test]
         fn constants_are_accepted() {
-            Lazy::force(&helper::LOGGING);
+            crate::test_log::init();
             let options = vec!["$MyName", "$myname", "$me13", "$3.141"];
             helper::test_correct_variants(constant, options);
         }
 
         #[test]
         fn templates_are_accepted() {
-            Lazy::force(&helper::LOGGING);
+            crate::test_log::init();
             let templates = vec![
                 "{key}$Constant${Option}",
                 "Sehr ${Anrede} {name}\n{nachricht}\n$Mfg\n$Sender",
@@ -624,6 +1247,46 @@ mod tests {
             helper::test_incorrect_cases(locale, cases);
         }
 
+        #[test]
+        #[cfg(feature = "dyn")]
+        fn date_formats_are_rejected() {
+            let cases = vec![
+                ("$Date{%Q}", "Unknown conversion specifications are rejected"),
+                ("$Date{%d.%m.%}", "Incomplete conversion specifications are rejected"),
+                ("$Date{%d", "Date formats require a closing brace"),
+                ("$Date{%d{x}}", "Date formats may not contain braces"),
+            ];
+            helper::test_incorrect_cases(constant, cases);
+            // An invalid format fails the whole template instead of becoming text.
+            assert!("Today: $Date{%Q}".parse::<ContentTokens>().is_err());
+        }
+
+        #[test]
+        #[cfg(feature = "dyn")]
+        fn too_many_date_offsets_are_rejected() {
+            let template = format!("Due: $Today{}", "+9999w".repeat(1500));
+            assert!(template.parse::<ContentTokens>().is_err());
+            let template = format!("Due: $Today{}", "+9999w".repeat(date::MAX_OFFSETS));
+            assert!(template.parse::<ContentTokens>().is_ok());
+        }
+
+        #[test]
+        #[cfg(feature = "dyn")]
+        fn empty_random_ranges_are_rejected() {
+            let cases = vec![("$Random{6-1}", "The lower bound may not be above the upper bound")];
+            helper::test_incorrect_cases(constant, cases);
+        }
+
+        #[test]
+        fn time_zones_are_rejected() {
+            let cases = vec![
+                ("timezone: Mars/Olympus\n", "Time zones have to be known"),
+                ("timezone: UTC", "Time zones require newline behind time zone"),
+                ("time zone: UTC\n", "Time zones require `timezone` keyword"),
+            ];
+            helper::test_incorrect_cases(time_zone, cases);
+        }
+
         #[test]
         fn keys_are_rejected() {
             let cases = vec![
@@ -686,11 +1349,6 @@ mod tests {
     mod helper {
         use super::*;
 
-        // Initialize logging for a test
-        pub static LOGGING: Lazy<()> = Lazy::new(|| {
-            env_logger::init();
-        });
-
         pub fn test_correct_variants<T, E>(
             parse_fn: fn(&mut Scanner) -> Result<T, E>,
             variants: Vec<&str>,
@@ -699,7 +1357,7 @@ mod tests {
             E: std::error::Error,
         {
             for variant in variants {
-                let mut scanner = Scanner::new(&variant);
+                let mut scanner = Scanner::new(variant);
                 assert!(parse_fn(&mut scanner).is_ok());
             }
         }
@@ -712,7 +1370,7 @@ mod tests {
             T: std::fmt::Debug, E: std::error::Error,
         {
             for (variant, case) in cases {
-                let mut scanner = Scanner::new(&variant);
+                let mut scanner = Scanner::new(variant);
                 assert!(
                     parse_fn(&mut scanner).is_ok(),
                     "A valid variant: '{}' was falsely rejected. Case: {}",
@@ -730,7 +1388,7 @@ mod tests {
             E: std::error::Error,
         {
             for (variant, case) in cases {
-                let mut scanner = Scanner::new(&variant);
+                let mut scanner = Scanner::new(variant);
                 assert!(
                     parse_fn(&mut scanner).is_err(),
                     "An invalid variant: '{}' was falsely accepted! Case: {}",
@@ -0,0 +1,345 @@
+//! Printing `ContentTokens` back to template source.
+//!
+//! Not every list of tokens can be represented as source: there is
+//! no way to escape the terminal symbols in text, and text directly
+//! behind a constant would become part of the constant's identifier or
+//! text at the start would become the time zone setting.
+//! Such lists are rejected with a `PrintError`. For every list which
+//! is printed successfully, parsing the output gives back the same list.
+use super::parse::{self, Symbol};
+use super::{ContentToken, ContentTokens, Ident};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum PrintError {
+    #[error("The text {0:?} contains one of the forbidden characters '{{', '}}' or '$'")]
+    ForbiddenInText(String),
+    #[error("A text literal is empty")]
+    EmptyText,
+    #[error("The identifier {0:?} may only contain the characters 'A'-'Z', 'a'-'z' and '0'-'9'")]
+    InvalidIdent(Ident),
+    #[error("The text {1:?} would become part of the identifier of the constant {0} in front of it")]
+    AmbiguousConstant(Ident, String),
+    #[error("The adjacent text literals {0:?} and {1:?} would become a single text literal")]
+    AdjacentText(String, String),
+    #[error("An option does not wrap a key")]
+    OptionWithoutKey,
+    #[error("The text {0:?} at the start would become the time zone setting")]
+    AmbiguousTimeZone(String),
+}
+
+impl ContentTokens {
+    /// Print the tokens as template source.
+    ///
+    /// The locale setting is always written, even if it is the default `en-US`.
+    /// The time zone setting is only written if a time zone is set.
+    pub fn to_source(&self) -> Result<String, PrintError> {
+        let mut output = String::new();
+        // Writing to a `String` can not fail.
+        let _ = writeln!(output, "locale: {}", self.locale);
+        match (self.time_zone, self.tokens.first()) {
+            (Some(time_zone), _) => {
+                let _ = writeln!(output, "timezone: {time_zone}");
+            }
+            (None, Some(ContentToken::Text(text))) if parse::is_time_zone_setting(text) => {
+                return Err(PrintError::AmbiguousTimeZone(text.clone()));
+            }
+            (None, _) => {}
+        }
+
+        let mut previous: Option<&ContentToken> = None;
+        for token in &self.tokens {
+            match (previous, token) {
+                (Some(ContentToken::Text(first)), ContentToken::Text(second)) => {
+                    return Err(PrintError::AdjacentText(first.clone(), second.clone()));
+                }
+                (Some(ContentToken::Constant(ident)), ContentToken::Text(text))
+                    if self.is_constant_before(ident, "") && !self.is_constant_before(ident, text) =>
+                {
+                    return Err(PrintError::AmbiguousConstant(ident.clone(), text.clone()));
+                }
+                _ => {}
+            }
+            print_token(token, self, &mut output)?;
+            previous = Some(token);
+        }
+
+        Ok(output)
+    }
+
+    // Check if `$ident` followed by `text` is parsed as the constant `ident`
+    // using the namespaces and names of dynamic elements the tokens were parsed with
+    fn is_constant_before(&self, ident: &str, text: &str) -> bool {
+        parse::is_constant_before(ident, text, &self.namespaces, self.dynamic_names)
+    }
+}
+
+// Print `token` of `tokens`
+fn print_token(
+    token: &ContentToken,
+    tokens: &ContentTokens,
+    output: &mut String,
+) -> Result<(), PrintError> {
+    match token {
+        ContentToken::Text(text) => {
+            if text.is_empty() {
+                return Err(PrintError::EmptyText);
+            }
+            if text.contains(|c: char| c.is_terminal()) {
+                return Err(PrintError::ForbiddenInText(text.clone()));
+            }
+            output.push_str(text);
+        }
+        ContentToken::Constant(ident) => {
+            output.push('$');
+            // The identifiers of some dynamic elements contain more than an
+            // identifier, e.g. the format of a date or the name of an environment variable.
+            match tokens.is_constant_before(ident, "") {
+                true => output.push_str(ident),
+                false => return Err(PrintError::InvalidIdent(ident.clone())),
+            }
+        }
+        ContentToken::Key(ident, default) => {
+            output.push('{');
+            print_ident(ident, output)?;
+            if let Some(default) = default {
+                output.push(':');
+                print_token(default, tokens, output)?;
+            }
+            output.push('}');
+        }
+        ContentToken::Option(key_box) => {
+            if !matches!(**key_box, ContentToken::Key(..)) {
+                return Err(PrintError::OptionWithoutKey);
+            }
+            output.push('$');
+            print_token(key_box, tokens, output)?;
+        }
+    }
+    Ok(())
+}
+
+fn print_ident(ident: &str, output: &mut String) -> Result<(), PrintError> {
+    if !parse::is_ident(ident) {
+        return Err(PrintError::InvalidIdent(ident.to_owned()));
+    }
+    output.push_str(ident);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content::DynamicNames;
+    use proptest::prelude::*;
+    use unic_locale::Locale;
+
+    fn ident() -> impl Strategy<Value = Ident> {
+        "[A-Za-z0-9]{1,8}"
+    }
+
+    fn text() -> impl Strategy<Value = String> {
+        "[^{}$]{1,12}"
+    }
+
+    // Any token which may be used as a default or as an element in a template
+    fn token() -> impl Strategy<Value = ContentToken> {
+        let leaf = prop_oneof![
+            text().prop_map(ContentToken::Text),
+            ident().prop_map(ContentToken::Constant),
+            ident().prop_map(|ident| ContentToken::Key(ident, None)),
+        ];
+        leaf.prop_recursive(4, 16, 1, |inner| {
+            let key = (ident(), prop::option::of(inner.prop_map(Box::new)))
+                .prop_map(|(ident, default)| ContentToken::Key(ident, default))
+                .boxed();
+            prop_oneof![
+                key.clone(),
+                key.prop_map(|key| ContentToken::Option(Box::new(key))),
+            ]
+        })
+    }
+
+    // Lists of tokens which can be printed.
+    fn content_tokens() -> impl Strategy<Value = ContentTokens> {
+        let locale = prop::sample::select(vec!["en-US", "de-DE", "fr-FR", "es"]);
+        let time_zone = prop::option::of(prop::sample::select(vec![
+            chrono_tz::Europe::Berlin,
+            chrono_tz::America::Argentina::Buenos_Aires,
+            chrono_tz::UTC,
+        ]));
+        let tokens = prop::collection::vec(token(), 1..12);
+        // Text which looks like a time zone setting
+        let setting = prop::option::of(Just(ContentToken::Text("timezone: UTC\n".into())));
+        (locale, time_zone, setting, tokens).prop_map(|(locale, time_zone, setting, raw)| {
+            let mut tokens = ContentTokens::from(locale.parse::<Locale>().unwrap());
+            tokens.time_zone = time_zone;
+            let mut previous: Option<ContentToken> = None;
+            for mut token in setting.into_iter().chain(raw) {
+                match (&previous, &mut token) {
+                    // Adjacent text is merged by the parser, so skip it here already.
+                    (Some(ContentToken::Text(_)), ContentToken::Text(_)) => continue,
+                    // Separate the text from a constant in front of it.
+                    (Some(ContentToken::Constant(_)), ContentToken::Text(text))
+                        if text.starts_with(|c: char| c.is_ident()) =>
+                    {
+                        text.insert(0, ' ');
+                    }
+                    _ => {}
+                }
+                tokens.push(token.clone());
+                previous = Some(token);
+            }
+            tokens
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn printed_tokens_parse_to_the_same_tokens(tokens in content_tokens()) {
+            let source = match tokens.to_source() {
+                Err(PrintError::AmbiguousTimeZone(_)) if tokens.time_zone.is_none() => return Ok(()),
+                result => result.unwrap(),
+            };
+            let parsed: ContentTokens = source.parse().unwrap();
+            prop_assert_eq!(parsed, tokens);
+        }
+
+        #[test]
+        fn printed_templates_parse_to_the_same_template(tokens in content_tokens()) {
+            use crate::template::Template;
+
+            let source = match tokens.to_source() {
+                Err(PrintError::AmbiguousTimeZone(_)) if tokens.time_zone.is_none() => return Ok(()),
+                result => result.unwrap(),
+            };
+            let template = Template::parse(&source).unwrap();
+            prop_assert_eq!(&Template::parse(&template.to_source().unwrap()).unwrap(), &template);
+        }
+    }
+
+    #[test]
+    fn templates_are_printed() {
+        let cases = vec![
+            (
+                "{name:Peter} bla ${bye:{mfg:MfG}}",
+                "locale: en-US\n{name:Peter} bla ${bye:{mfg:MfG}}",
+            ),
+            (
+                "locale :fr_FR\n$Me, ${greeting:$Hi} {key:${a:{b:$c}}}\n",
+                "locale: fr-FR\n$Me, ${greeting:$Hi} {key:${a:{b:$c}}}\n",
+            ),
+            #[cfg(feature = "dyn")]
+            (
+                "Berlin, $Date{%d.%m.%Y}h {date:$Date{%F}}",
+                "locale: en-US\nBerlin, $Date{%d.%m.%Y}h {date:$Date{%F}}",
+            ),
+            #[cfg(feature = "dyn")]
+            (
+                "Due $Today+14d-1w{%F}, $NextMonday+1m.",
+                "locale: en-US\nDue $Today+14d-1w{%F}, $NextMonday+1m.",
+            ),
+            #[cfg(feature = "dyn")]
+            (
+                "#$Random{1-100}x{ticket:$Uuid}",
+                "locale: en-US\n#$Random{1-100}x{ticket:$Uuid}",
+            ),
+            #[cfg(feature = "dyn")]
+            (
+                "$@Year-$@Random{1-9}",
+                "locale: en-US\n$@Year-$@Random{1-9}",
+            ),
+            #[cfg(feature = "env")]
+            (
+                "$Env.USER@$Hostname: $@Env.HOME.",
+                "locale: en-US\n$Env.USER@$Hostname: $@Env.HOME.",
+            ),
+        ];
+        for (template, expected) in cases {
+            let tokens: ContentTokens = template.parse().unwrap();
+            assert_eq!(tokens.to_source().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn members_of_namespaces_are_printed() {
+        let namespaces = vec!["Counter".to_owned()];
+        let tokens =
+            ContentTokens::parse_with("No. $Counter.invoice_2.", namespaces, DynamicNames::Bare)
+                .unwrap();
+        assert_eq!(
+            tokens.to_source().unwrap(),
+            "locale: en-US\nNo. $Counter.invoice_2."
+        );
+        // Without the namespace, the member can not be printed.
+        let mut tokens = ContentTokens::new();
+        tokens.push(ContentToken::Constant("Counter.invoice".into()));
+        assert_eq!(
+            tokens.to_source(),
+            Err(PrintError::InvalidIdent("Counter.invoice".into()))
+        );
+    }
+
+    #[test]
+    fn unrepresentable_tokens_are_rejected() {
+        let cases = vec![
+            (
+                vec![ContentToken::Text("a { b".into())],
+                PrintError::ForbiddenInText("a { b".into()),
+            ),
+            (vec![ContentToken::Text("".into())], PrintError::EmptyText),
+            (
+                vec![ContentToken::Key("my-name".into(), None)],
+                PrintError::InvalidIdent("my-name".into()),
+            ),
+            (
+                vec![
+                    ContentToken::Constant("Me".into()),
+                    ContentToken::Text("ssage".into()),
+                ],
+                PrintError::AmbiguousConstant("Me".into(), "ssage".into()),
+            ),
+            #[cfg(feature = "dyn")]
+            (
+                vec![
+                    ContentToken::Constant("Today".into()),
+                    ContentToken::Text("+1d".into()),
+                ],
+                PrintError::AmbiguousConstant("Today".into(), "+1d".into()),
+            ),
+            #[cfg(feature = "env")]
+            (
+                vec![
+                    ContentToken::Constant("Env.USER".into()),
+                    ContentToken::Text("_NAME".into()),
+                ],
+                PrintError::AmbiguousConstant("Env.USER".into(), "_NAME".into()),
+            ),
+            (
+                vec![
+                    ContentToken::Text("a".into()),
+                    ContentToken::Text("b".into()),
+                ],
+                PrintError::AdjacentText("a".into(), "b".into()),
+            ),
+            (
+                vec![ContentToken::Option(Box::new(ContentToken::Text("a".into())))],
+                PrintError::OptionWithoutKey,
+            ),
+            (
+                vec![ContentToken::Text("timezone: Europe/Berlin\nHi".into())],
+                PrintError::AmbiguousTimeZone("timezone: Europe/Berlin\nHi".into()),
+            ),
+        ];
+        for (list, expected) in cases {
+            let mut tokens = ContentTokens::new();
+            for token in list {
+                tokens.push(token);
+            }
+            assert_eq!(tokens.to_source(), Err(expected));
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Providers of the content of dynamic elements.
+//!
+//! A dynamic element is a constant whose content is provided when the template
+//! is filled out instead of being given in the content state. The built-in meta
+//! constants (e.g. `$Year`) and environment elements (e.g. `$Env.USER`) are
+//! provided by the built-in providers. Applications can register their own
+//! providers in `CompilationSettings` to provide elements like `$TicketNumber`.
+//! Like the meta constants, the elements of registered providers shadow
+//! constants of the content state with the same name if they are written
+//! without the sigil, which is logged as a warning.
+#[cfg(feature = "env")]
+use super::env;
+#[cfg(feature = "dyn")]
+use super::meta::MetaExt;
+use super::parse::DYN_SIGIL;
+use super::{DynContext, DynamicNames};
+use std::fmt::Debug;
+
+/// Source of the content of dynamic elements.
+pub trait DynamicProvider: Debug + Send + Sync {
+    /// Get the content of the constant `ident` in the context `ctx`. Returns `None`
+    /// if `ident` is not provided by `self`, so other providers or the content
+    /// state are asked instead.
+    fn resolve(&self, ident: &str, ctx: &DynContext) -> Option<String>;
+
+    /// Check if `self` provides the constant `ident` in the context `ctx`, e.g. to
+    /// keep it when a template is filled out partially or to check the defaults
+    /// which are not written when a template is rendered. By default, `ident` is
+    /// resolved, so providers whose elements are expensive to get or change some
+    /// state when they are resolved should check `ident` without resolving it.
+    fn provides(&self, ident: &str, ctx: &DynContext) -> bool {
+        self.resolve(ident, ctx).is_some()
+    }
+
+    /// Names of the constants whose identifier is followed by the name of one
+    /// of their members, e.g. `Counter` for `$Counter.invoice`. A template only
+    /// knows about these namespaces if it is parsed using `Template::parse_with`.
+    fn namespaces(&self) -> &[&str] {
+        &[]
+    }
+}
+
+/// Provider of the meta constants, e.g. `$Year` or `$Today+14d{%F}`.
+#[cfg(feature = "dyn")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetaProvider;
+
+#[cfg(feature = "dyn")]
+impl DynamicProvider for MetaProvider {
+    fn resolve(&self, ident: &str, ctx: &DynContext) -> Option<String> {
+        Some(ident.as_meta()?.evaluate(ctx))
+    }
+
+    fn provides(&self, ident: &str, _: &DynContext) -> bool {
+        ident.as_meta().is_some()
+    }
+}
+
+/// Provider of the environment elements the context allows to read, e.g. `$Env.USER`.
+#[cfg(feature = "env")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EnvProvider;
+
+#[cfg(feature = "env")]
+impl DynamicProvider for EnvProvider {
+    fn resolve(&self, ident: &str, ctx: &DynContext) -> Option<String> {
+        env::evaluate_allowed(ident, ctx)
+    }
+}
+
+// Providers which are always asked first, in order
+static BUILT_IN: &[&dyn DynamicProvider] = &[
+    #[cfg(feature = "dyn")]
+    &MetaProvider,
+    #[cfg(feature = "env")]
+    &EnvProvider,
+];
+
+/// Get the content of the dynamic element `ident` from the first provider
+/// which provides it. The built-in providers are asked before the providers
+/// of `ctx`, so the meta constants can not be replaced. Providers are given
+/// the name of the element without its sigil.
+pub(crate) fn resolve(ident: &str, ctx: &DynContext) -> Option<String> {
+    let name = name(ident, ctx)?;
+    providers(ctx).find_map(|provider| provider.resolve(name, ctx))
+}
+
+/// Check if any provider of `ctx` provides the dynamic element `ident`, i.e. if
+/// `resolve` would get its content, but without resolving it if possible.
+pub(crate) fn provides(ident: &str, ctx: &DynContext) -> bool {
+    name(ident, ctx)
+        .is_some_and(|name| providers(ctx).any(|provider| provider.provides(name, ctx)))
+}
+
+// Get the name of the dynamic element `ident` without its sigil, unless
+// `ident` can not be a dynamic element under the names used in `ctx`
+fn name<'i>(ident: &'i str, ctx: &DynContext) -> Option<&'i str> {
+    match ident.strip_prefix(DYN_SIGIL) {
+        Some(name) => Some(name),
+        None if ctx.names == DynamicNames::Bare => Some(ident),
+        None => None,
+    }
+}
+
+// The built-in providers followed by the providers of `ctx`
+fn providers<'c>(ctx: &'c DynContext) -> impl Iterator<Item = &'c dyn DynamicProvider> {
+    let built_in = BUILT_IN.iter().copied();
+    let registered = ctx.providers.iter().map(|provider| provider.as_ref());
+    built_in.chain(registered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content::Clock;
+    use unic_locale::Locale;
+
+    #[derive(Debug)]
+    struct Tickets;
+
+    impl DynamicProvider for Tickets {
+        fn resolve(&self, ident: &str, _: &DynContext) -> Option<String> {
+            match ident {
+                "TicketNumber" => Some("T-42".to_owned()),
+                "Year" => Some("never used".to_owned()),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn registered_providers_are_asked() {
+        let locale = Locale::default();
+        let providers: Vec<Box<dyn DynamicProvider>> = vec![Box::new(Tickets)];
+        let ctx = DynContext::new(&locale, None, &Clock::System).with_providers(&providers);
+        assert_eq!(resolve("TicketNumber", &ctx).unwrap(), "T-42");
+        assert_eq!(resolve("OnCallEngineer", &ctx), None);
+
+        let ctx = DynContext::new(&locale, None, &Clock::System);
+        assert_eq!(resolve("TicketNumber", &ctx), None);
+    }
+
+    #[test]
+    #[cfg(feature = "dyn")]
+    fn built_in_providers_are_asked_first() {
+        let locale = Locale::default();
+        let now = chrono::Utc::now();
+        let providers: Vec<Box<dyn DynamicProvider>> = vec![Box::new(Tickets)];
+        let ctx = DynContext::new(&locale, Some(chrono_tz::UTC), &Clock::Fixed(now))
+            .with_providers(&providers);
+        assert_eq!(resolve("Year", &ctx).unwrap(), now.format("%Y").to_string());
+        assert_eq!(resolve("Year", &ctx), MetaProvider.resolve("Year", &ctx));
+    }
+}
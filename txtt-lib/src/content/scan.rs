@@ -1,140 +1,205 @@
-use log::{debug, trace};
+use super::DynamicNames;
+use log::trace;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-// List of virtual cursors guaranteeing at last one cursor
+// Stack of virtual cursors guaranteeing at least one cursor.
+// Every cursor is a byte offset into the source of the scanner.
 #[derive(Debug)]
-pub struct Cursor(Vec<usize>);
+struct Cursor(Vec<usize>);
 
 impl Cursor {
-    pub fn new() -> Self {
+    fn new() -> Self {
         Self(vec![0])
     }
 
     // Add a new active virtual layer
-    pub fn add(&mut self) {
-        let current = self.0.last().unwrap();
-        self.0.push(*current);
+    fn add(&mut self) {
+        let current = self.at();
+        self.0.push(current);
     }
 
     // Delete the active layer and set the layer below
     // to it's position
-    pub fn merge(&mut self) {
+    fn merge(&mut self) {
         if self.0.len() > 1 {
             let current = self.0.pop().unwrap();
             *self.0.last_mut().unwrap() = current;
         }
     }
 
-    // Delete the active layer
-    // requires the source for the scanner so it can get the positon as lines
-    pub fn collapse(&mut self, s: &Vec<char>) -> ErrorPosition {
-        debug!("Collapsing virtual cursor layer (before: {:?})", &self);
+    // Delete the active layer and return its position
+    // together with the position of the layer below it
+    fn collapse(&mut self) -> (usize, usize) {
         if self.0.len() > 1 {
             let active = self.0.pop().unwrap();
-            let base = *self.0.last().unwrap();
-            debug!("(after: {:?})", &self);
-            ErrorPosition {
-                active,
-                base,
-                lines: s.as_lines(active),
-            }
+            (active, self.at())
         } else {
-            let active = *self.0.last().unwrap();
-            debug!("(after: {:?})", &self);
-            ErrorPosition {
-                active,
-                base: active,
-                lines: s.as_lines(active),
-            }
+            (self.at(), self.at())
         }
     }
 
     // Get the position of the active layer
-    pub fn at(&self) -> usize {
-        trace!("Used {:?}", self);
+    fn at(&self) -> usize {
         *self.0.last().unwrap()
     }
 
-    // Increase the position of the active layer
-    pub fn inc(&mut self) {
-        let current = self.0.last_mut().unwrap();
-        *current += 1;
+    // Move the active layer forward by `n` bytes
+    fn advance(&mut self, n: usize) {
+        *self.0.last_mut().unwrap() += n;
     }
 }
 
-// The scanner uses layers of virtual cursors which
-// advance in the input character by character but
+// Byte offsets of the start of every line in the source.
+// The index is built once so that error positions can be found
+// with a binary search instead of rescanning the input.
+#[derive(Debug)]
+struct LineIndex(Vec<usize>);
+
+impl LineIndex {
+    fn new(s: &str) -> Self {
+        let mut starts = vec![0];
+        starts.extend(s.match_indices('\n').map(|(idx, _)| idx + 1));
+        Self(starts)
+    }
+
+    // Convert a byte offset in `s` into the corresponding
+    // position as lines and columns.
+    // Lines and columns both start with 1 as the lowest value.
+    fn as_lines(&self, s: &str, pos: usize) -> (usize, usize) {
+        let line = self.0.partition_point(|start| *start <= pos);
+        let line_start = self.0[line - 1];
+        (line, s[line_start..pos].chars().count() + 1)
+    }
+}
+
+// The scanner works directly on the source string and
+// uses layers of virtual cursors (byte offsets) which
 // keep their respective starting positions so they
-// can go back there to restart on failures. (This might change at some point)
+// can go back there to restart on failures.
 // The current virtual cursor layer is collaped if
 // a lexical error is encountered! This means the parser
 // only needs to perform manual aborts if a syntax error
 // is raised in the parser itself.
-pub struct Scanner {
+// Lookahead is done with `peek` and `peek_str` which never
+// move the cursor, so no layer is required to decide which
+// rule to follow next.
+#[derive(Debug)]
+pub struct Scanner<'a> {
     cursor: Cursor,
-    chars: Vec<char>,
+    lines: LineIndex,
+    src: &'a str,
+    // Names of the constants whose identifier includes the name of a member
+    namespaces: &'a [String],
+    // Whether dynamic elements may be written without their sigil
+    dynamic_names: DynamicNames,
 }
 
-impl Scanner {
-    pub fn new(s: &str) -> Self {
-        debug!("New scanner from: {}", &s);
+impl<'a> Scanner<'a> {
+    pub fn new(src: &'a str) -> Self {
+        Self::with_namespaces(src, &[])
+    }
+
+    // Scanner for a template in which constants named after one of `namespaces`
+    // are followed by the name of a member, e.g. `$Counter.invoice`
+    pub fn with_namespaces(src: &'a str, namespaces: &'a [String]) -> Self {
+        trace!("New scanner over {} bytes of input", src.len());
         Self {
             cursor: Cursor::new(),
-            chars: s.chars().collect(),
+            lines: LineIndex::new(src),
+            src,
+            namespaces,
+            dynamic_names: DynamicNames::default(),
         }
     }
 
+    // Scanner for a template in which dynamic elements are written using `names`
+    pub const fn with_names(mut self, names: DynamicNames) -> Self {
+        self.dynamic_names = names;
+        self
+    }
+
+    pub const fn namespaces(&self) -> &'a [String] {
+        self.namespaces
+    }
+
+    pub const fn dynamic_names(&self) -> DynamicNames {
+        self.dynamic_names
+    }
+
     pub fn at_end(&self) -> bool {
-        if self.cursor.at() == self.chars.len() {
-            debug!("Scanner has reached the end");
-            true
-        } else {
-            debug!("Scanner has NOT reached the end");
-            false
-        }
+        self.cursor.at() == self.src.len()
+    }
+
+    // Byte offset of the active cursor layer in the source
+    pub fn pos(&self) -> usize {
+        self.cursor.at()
+    }
+
+    pub const fn source(&self) -> &'a str {
+        self.src
     }
 
     pub fn begin(&mut self) {
-        debug!("Adding virtual cursor layer");
         self.cursor.add();
     }
 
     pub fn abort(&mut self) {
-        debug!("Removing virtual cursor layer");
-        self.cursor.collapse(&self.chars);
+        self.cursor.collapse();
     }
 
     pub fn commit(&mut self) {
-        debug!("Comitting virtual cursor layer");
         self.cursor.merge();
     }
 
-    pub fn current_char(&self) -> Option<char> {
-        self.chars.get(self.cursor.at()).copied()
+    // The part of the source which has not been scanned yet
+    fn rest(&self) -> &'a str {
+        &self.src[self.cursor.at()..]
+    }
+
+    // Get the next character without scanning it
+    pub fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    // Check if the rest of the input starts with `s` without scanning it
+    pub fn peek_str(&self, s: &str) -> bool {
+        self.rest().starts_with(s)
+    }
+
+    // Get the position of the active layer without collapsing it
+    pub fn position(&self) -> ErrorPosition {
+        let pos = self.cursor.at();
+        self.error_position(pos, pos)
+    }
+
+    // Collapse the active layer and get the position at which it failed
+    fn collapse(&mut self) -> ErrorPosition {
+        let (active, base) = self.cursor.collapse();
+        self.error_position(active, base)
+    }
+
+    fn error_position(&self, active: usize, base: usize) -> ErrorPosition {
+        ErrorPosition {
+            active,
+            base,
+            lines: self.lines.as_lines(self.src, active),
+        }
     }
 
     // Scan a single character
     pub fn take(&mut self, character: char) -> Result<(), ScanError> {
-        if let Some(current) = self.current_char() {
-            if character == current {
-                self.cursor.inc();
-                debug!("Took character '{}'  succesfully", character);
+        match self.peek() {
+            Some(current) if current == character => {
+                self.cursor.advance(current.len_utf8());
                 Ok(())
-            } else {
-                let symbol = UnexpectedSymbol {
-                    found: current,
-                    expected: None,
-                    position: self.cursor.collapse(&self.chars),
-                };
-                debug!("Failed to take character: {}", &symbol);
-                Err(ScanError::UnexpectedSymbol(symbol))
             }
-        } else {
-            debug!("Failed to take character '{}': Hit end of input", character);
-            Err(ScanError::UnexpectedEndOfInput(
-                self.cursor.collapse(&self.chars),
-            ))
+            Some(current) => Err(ScanError::UnexpectedSymbol(UnexpectedSymbol {
+                found: current,
+                expected: Some(character),
+                position: self.collapse(),
+            })),
+            None => Err(ScanError::UnexpectedEndOfInput(self.collapse())),
         }
     }
 
@@ -148,144 +213,34 @@ impl Scanner {
         Ok(())
     }
 
-    // Scan character by character
-    pub fn scan(&mut self, callback: impl Fn(char) -> Option<Action>) -> Result<String, ScanError> {
-        self.scan_seq(|seq| {
-            // Unwrap because `scan_seq` always pushes a char to the sequence
-            // before evoking the callback
-            callback(seq.chars().last().unwrap())
-        })
-    }
-
-    // Scan dynamic sequences of characters
-    pub fn scan_seq(
-        &mut self,
-        callback: impl Fn(&str) -> Option<Action>,
-    ) -> Result<String, ScanError> {
-        let mut sequence = String::new();
-        let mut require = None;
-        let mut request = false;
-
-        loop {
-            match self.current_char() {
-                Some(target) => {
-                    sequence.push(target);
-                    match callback(&sequence) {
-                        Some(action) => {
-                            match action {
-                                // Continue but return ok if next iteration fails
-                                Action::Request => {
-                                    self.cursor.inc();
-                                    require = None;
-                                    request = true;
-                                    debug!("Requesting result after character '{}'", target);
-                                }
-                                // Return now
-                                Action::Return => {
-                                    self.cursor.inc();
-                                    match require {
-                                        Some(require) => {
-                                            if target == require {
-                                                debug!(
-                                                    "Returning result after character '{}'",
-                                                    target
-                                                );
-                                            } else {
-                                                debug!(
-                                                    "Failed to return result after character '{}' \
-                                                    because previous requirement was not matched. \
-                                                    Now returning sequence up to the require call",
-                                                    target
-                                                );
-                                                sequence.pop(); // Remove the new character which did not match
-                                            }
-                                        }
-                                        None => {
-                                            debug!("Returning result after character '{}'", target)
-                                        }
-                                    }
-                                    break Ok(sequence);
-                                }
-                                // Continue and return the current sequence if the next iteration
-                                // fails or does not match the given symbol
-                                Action::Require(symbol) => {
-                                    self.cursor.inc();
-                                    require = Some(symbol);
-                                    debug!(
-                                        "Requiring next character as '{}' after '{}'",
-                                        target, symbol
-                                    );
-                                }
-                            }
-                        }
-                        None => {
-                            sequence.pop(); // The last character was invalid!
-
-                            break match request {
-                                true => {
-                                    debug!("Returning result after failing to get new character on request");
-                                    Ok(sequence)
-                                }
-                                false => {
-                                    let symbol = UnexpectedSymbol {
-                                        found: target,
-                                        expected: None,
-                                        position: self.cursor.collapse(&self.chars),
-                                    };
-                                    debug!("Failed to get new character while neither requiring nor requesting: {}", &symbol);
-                                    Err(ScanError::UnexpectedSymbol(symbol))
-                                }
-                            };
-                        }
-                    }
-                }
-                None => {
-                    break match request {
-                        true => {
-                            debug!("Returning result after hitting end of input on request");
-                            Ok(sequence)
-                        }
-                        false => {
-                            debug!("Hit end of input while neither requiring nor requesting");
-                            Err(ScanError::UnexpectedEndOfInput(
-                                self.cursor.collapse(&self.chars),
-                            ))
-                        }
-                    }
-                }
-            }
+    // Scan one or more characters for which `accept` returns true and
+    // return the scanned part of the source.
+    pub fn scan(&mut self, accept: impl Fn(char) -> bool) -> Result<&'a str, ScanError> {
+        let scanned = self.skip(accept);
+        if !scanned.is_empty() {
+            return Ok(scanned);
         }
-    }
-}
 
-trait PosAsLines {
-    fn as_lines(&self, pos: usize) -> (usize, usize);
-}
-
-impl PosAsLines for Vec<char> {
-    // Convert a position in the string into the
-    // corresponding position as lines and columns.
-    // Lines and columns both start with 1 as the lowest value.
-    fn as_lines(&self, pos: usize) -> (usize, usize) {
-        let mut line = 1;
-        let mut column = 1;
-        for character in self.iter().take(pos) {
-            if *character == '\n' {
-                line += 1;
-                column = 1;
-            } else {
-                column += 1;
-            }
+        match self.peek() {
+            Some(found) => Err(ScanError::UnexpectedSymbol(UnexpectedSymbol {
+                found,
+                expected: None,
+                position: self.collapse(),
+            })),
+            None => Err(ScanError::UnexpectedEndOfInput(self.collapse())),
         }
-        (line, column)
     }
-}
 
-#[derive(Debug)]
-pub enum Action {
-    Request,       // Try to get another character but still return a success if this failed
-    Return,        // Return a success
-    Require(char), // Like request but the next character has the be `char`
+    // Scan any number of characters for which `accept` returns true and
+    // return the scanned part of the source. Nothing happens if none are encountered.
+    pub fn skip(&mut self, accept: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let len = rest
+            .find(|character| !accept(character))
+            .unwrap_or(rest.len());
+        self.cursor.advance(len);
+        &rest[..len]
+    }
 }
 
 #[derive(thiserror::Error, Debug, PartialEq, Eq, Clone, Copy)]
@@ -298,8 +253,9 @@ pub enum ScanError {
 }
 
 impl ScanError {
-    // Difference between active and base cursor position
-    // when the error was raised
+    // Number of bytes (not chars) the failed layer had scanned past its
+    // start when the error was raised, i.e. the difference between the
+    // active and base cursor position.
     pub const fn failed_after(&self) -> usize {
         let err_pos = match self {
             Self::UnexpectedSymbol(symbol) => symbol.position,
@@ -348,3 +304,59 @@ impl std::fmt::Display for ErrorPosition {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_are_lines_and_columns() {
+        let src = "ab\nc\u{e4}d\n\nef";
+        let lines = LineIndex::new(src);
+        let cases = vec![
+            (0, (1, 1)),
+            (2, (1, 3)),
+            (3, (2, 1)),
+            (6, (2, 3)), // 'ä' is two bytes but only one column
+            (8, (3, 1)),
+            (9, (4, 1)),
+            (11, (4, 3)),
+        ];
+        for (pos, expected) in cases {
+            assert_eq!(lines.as_lines(src, pos), expected, "Byte offset {pos}");
+        }
+    }
+
+    #[test]
+    fn failed_scans_collapse_the_active_layer() {
+        let mut scanner = Scanner::new("abc}");
+        scanner.begin();
+        assert_eq!(scanner.scan(|c| c.is_alphabetic()), Ok("abc"));
+        scanner.begin();
+        assert!(scanner.take('{').is_err());
+        // The layer added by the second `begin` is gone, the first one remains.
+        assert_eq!(scanner.peek(), Some('}'));
+        scanner.abort();
+        assert_eq!(scanner.peek(), Some('a'));
+    }
+
+    #[test]
+    fn failures_are_counted_in_bytes() {
+        let mut scanner = Scanner::new("\u{e4}\u{f6}x");
+        scanner.begin();
+        scanner.scan(|c| c.is_alphabetic() && !c.is_ascii()).unwrap();
+        let err = scanner.take('}').unwrap_err();
+        // Both umlauts are two bytes long.
+        assert_eq!(err.failed_after(), 4);
+    }
+
+    #[test]
+    fn peeking_does_not_move_the_cursor() {
+        let mut scanner = Scanner::new("${x}");
+        assert!(scanner.peek_str("${"));
+        assert_eq!(scanner.peek(), Some('$'));
+        assert_eq!(scanner.skip(|c| c == '$'), "$");
+        assert_eq!(scanner.skip(|c| c == '$'), "");
+        assert_eq!(scanner.peek(), Some('{'));
+    }
+}
@@ -0,0 +1,66 @@
+//! Map from the output of a filled out template to its elements.
+//!
+//! Every range of the output which was written for an element of the
+//! template is linked to this element, the element whose content was
+//! actually used (which differs if a default was used) and the origin
+//! of the content. Ranges of text from the template are not mapped.
+use super::ContentIndex;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Where the content of an element came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Origin {
+    /// Content of a key given in the volatile content
+    UserInput,
+    /// Content of a constant or of the choice for an option from the content state
+    ContentState,
+    /// Text used as a default in the template
+    Default,
+    /// Content of a dynamic element, e.g. a meta constant
+    Dynamic,
+}
+
+/// Range of bytes in the output which was written for an element.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MappedRange {
+    pub range: Range<usize>,
+    /// The element of the template at this place.
+    pub element: ContentIndex,
+    /// The element whose content was used. If it is not `element`,
+    /// the content comes from a default of `element`.
+    pub source: ContentIndex,
+    pub origin: Origin,
+}
+
+/// Ranges of the output of a template which were written for elements, in order.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SourceMap(Vec<MappedRange>);
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self(vec![])
+    }
+
+    pub fn ranges(&self) -> &[MappedRange] {
+        &self.0
+    }
+
+    /// Find the range which contains the byte at `offset` of the output, if
+    /// this byte was written for an element.
+    pub fn at(&self, offset: usize) -> Option<&MappedRange> {
+        let idx = self.0.partition_point(|mapped| mapped.range.end <= offset);
+        self.0
+            .get(idx)
+            .filter(|mapped| mapped.range.contains(&offset))
+    }
+
+    // Add a range behind all ranges in the map
+    pub(crate) fn push(&mut self, mapped: MappedRange) {
+        self.0.push(mapped);
+    }
+}
@@ -0,0 +1,444 @@
+//! Lossless concrete syntax tree of a template.
+//!
+//! In contrast to `ContentTokens`, the syntax tree keeps track of every
+//! byte of the source it was parsed from (including the exact spelling
+//! of the locale header). Writing the tree back out using `Display`
+//! always reproduces the source, while `SyntaxTree::format` prints it
+//! in its canonical formatting.
+use super::parse::{self, UserError};
+use super::scan::Scanner;
+use super::{ContentToken, ContentTokens, DynamicNames};
+use chrono_tz::Tz;
+use std::fmt::{self, Write};
+use unic_locale::Locale;
+
+/// Range of bytes in the source of a template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub const fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// Smallest span containing both `self` and `other`.
+    pub const fn to(self, other: Self) -> Self {
+        Self::new(self.start, other.end)
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// The locale header at the start of a template, e.g. `locale: de-DE\n`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocaleNode {
+    pub keyword: Span,
+    pub ws_before: Span,
+    pub colon: Span,
+    pub ws_after: Span,
+    pub value: Span,
+    pub newline: Span,
+    pub locale: Locale,
+}
+
+impl LocaleNode {
+    pub const fn span(&self) -> Span {
+        self.keyword.to(self.newline)
+    }
+
+    fn leaves(&self) -> [Span; 6] {
+        [
+            self.keyword,
+            self.ws_before,
+            self.colon,
+            self.ws_after,
+            self.value,
+            self.newline,
+        ]
+    }
+}
+
+/// The time zone setting behind the locale header, e.g. `timezone: Europe/Berlin\n`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeZoneNode {
+    pub keyword: Span,
+    pub ws_before: Span,
+    pub colon: Span,
+    pub ws_after: Span,
+    pub value: Span,
+    pub newline: Span,
+    pub time_zone: Tz,
+}
+
+impl TimeZoneNode {
+    pub const fn span(&self) -> Span {
+        self.keyword.to(self.newline)
+    }
+
+    fn leaves(&self) -> [Span; 6] {
+        [
+            self.keyword,
+            self.ws_before,
+            self.colon,
+            self.ws_after,
+            self.value,
+            self.newline,
+        ]
+    }
+}
+
+/// A key (`{name}`) with an optional default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyNode {
+    pub lbrace: Span,
+    pub ident: Span,
+    pub default: Option<DefaultNode>,
+    pub rbrace: Span,
+}
+
+/// The default of a key or an option (`:` followed by any item).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefaultNode {
+    pub colon: Span,
+    pub item: Box<SyntaxNode>,
+}
+
+/// An option (`${name}`) which is a key behind a `$` sign.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptionNode {
+    pub cash: Span,
+    pub key: KeyNode,
+}
+
+/// A constant (`$name`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstantNode {
+    pub cash: Span,
+    /// The identifier of a formatted date includes its format, e.g. `Date{%d.%m.%Y}`,
+    /// and the identifier of a dynamic element includes its sigil, e.g. `@Year`.
+    pub ident: Span,
+}
+
+/// Any item of a template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyntaxNode {
+    Text(Span),
+    Key(KeyNode),
+    Option(OptionNode),
+    Constant(ConstantNode),
+}
+
+impl SyntaxNode {
+    pub const fn span(&self) -> Span {
+        match self {
+            Self::Text(span) => *span,
+            Self::Key(key) => key.lbrace.to(key.rbrace),
+            Self::Option(option) => option.cash.to(option.key.rbrace),
+            Self::Constant(constant) => constant.cash.to(constant.ident),
+        }
+    }
+
+    // Push the spans of all pieces of source of `self` in the order they appear in.
+    fn leaves(&self, spans: &mut Vec<Span>) {
+        fn key_leaves(key: &KeyNode, spans: &mut Vec<Span>) {
+            spans.push(key.lbrace);
+            spans.push(key.ident);
+            if let Some(default) = &key.default {
+                spans.push(default.colon);
+                default.item.leaves(spans);
+            }
+            spans.push(key.rbrace);
+        }
+
+        match self {
+            Self::Text(span) => spans.push(*span),
+            Self::Key(key) => key_leaves(key, spans),
+            Self::Option(option) => {
+                spans.push(option.cash);
+                key_leaves(&option.key, spans);
+            }
+            Self::Constant(constant) => {
+                spans.push(constant.cash);
+                spans.push(constant.ident);
+            }
+        }
+    }
+}
+
+/// Lossless syntax tree of a template which borrows its source.
+#[derive(Debug)]
+pub struct SyntaxTree<'a> {
+    source: &'a str,
+    header: Option<LocaleNode>,
+    time_zone: Option<TimeZoneNode>,
+    items: Vec<SyntaxNode>,
+    friendly_errors: Vec<UserError>,
+}
+
+impl<'a> SyntaxTree<'a> {
+    /// Parse the source of a template into a syntax tree.
+    pub fn parse(source: &'a str) -> Result<Self, UserError> {
+        Self::parse_with(source, &[], DynamicNames::default())
+    }
+
+    /// Parse the source of a template in which the constants named after one
+    /// of `namespaces` are followed by the name of a member, e.g. `$Counter.invoice`,
+    /// and in which dynamic elements are written using `names`.
+    pub fn parse_with(
+        source: &'a str,
+        namespaces: &'a [String],
+        names: DynamicNames,
+    ) -> Result<Self, UserError> {
+        let mut scanner = Scanner::with_namespaces(source, namespaces).with_names(names);
+        parse::template(&mut scanner)
+    }
+
+    pub(crate) const fn new(source: &'a str, header: Option<LocaleNode>) -> Self {
+        Self {
+            source,
+            header,
+            time_zone: None,
+            items: vec![],
+            friendly_errors: vec![],
+        }
+    }
+
+    // Add a friendly error to the `SyntaxTree` instance
+    pub(crate) fn add_friendly(&mut self, e: UserError) {
+        self.friendly_errors.push(e);
+    }
+
+    pub(crate) fn set_time_zone(&mut self, node: TimeZoneNode) {
+        self.time_zone = Some(node);
+    }
+
+    pub(crate) fn push(&mut self, node: SyntaxNode) {
+        self.items.push(node);
+    }
+
+    pub const fn source(&self) -> &'a str {
+        self.source
+    }
+
+    /// The locale header, if the template has one.
+    pub const fn header(&self) -> Option<&LocaleNode> {
+        self.header.as_ref()
+    }
+
+    /// The time zone setting, if the template has one.
+    pub const fn time_zone(&self) -> Option<&TimeZoneNode> {
+        self.time_zone.as_ref()
+    }
+
+    /// All items following the header.
+    pub fn items(&self) -> &[SyntaxNode] {
+        &self.items
+    }
+
+    /// The piece of source covered by `span`.
+    pub fn text(&self, span: Span) -> &'a str {
+        &self.source[span.start..span.end]
+    }
+
+    /// Spans of all pieces of source in the order they appear in.
+    /// Together they cover the entire source without any gaps.
+    pub fn leaves(&self) -> Vec<Span> {
+        let mut spans = vec![];
+        if let Some(header) = &self.header {
+            spans.extend(header.leaves());
+        }
+        if let Some(time_zone) = &self.time_zone {
+            spans.extend(time_zone.leaves());
+        }
+        for item in &self.items {
+            item.leaves(&mut spans);
+        }
+        spans
+    }
+
+    /// Print the template in its canonical formatting.
+    ///
+    /// The locale header is written as `locale: <locale>` followed by
+    /// a new line, using the canonical spelling of the locale, and the time
+    /// zone setting is written the same way as `timezone: <time zone>`. Nothing
+    /// else is normalised: elements and their defaults can only be written in one
+    /// way, so they are kept as they are, and so is text.
+    pub fn format(&self) -> String {
+        let mut output = String::with_capacity(self.source.len());
+        if let Some(header) = &self.header {
+            // Writing to a `String` can not fail.
+            let _ = writeln!(output, "locale: {}", header.locale);
+        }
+        if let Some(time_zone) = &self.time_zone {
+            let _ = writeln!(output, "timezone: {}", time_zone.time_zone);
+        }
+        for item in &self.items {
+            self.format_node(item, &mut output);
+        }
+        output
+    }
+
+    fn format_node(&self, node: &SyntaxNode, output: &mut String) {
+        match node {
+            SyntaxNode::Text(span) => output.push_str(self.text(*span)),
+            SyntaxNode::Key(key) => self.format_key(key, output),
+            SyntaxNode::Option(option) => {
+                output.push('$');
+                self.format_key(&option.key, output);
+            }
+            SyntaxNode::Constant(constant) => {
+                output.push('$');
+                output.push_str(self.text(constant.ident));
+            }
+        }
+    }
+
+    fn format_key(&self, key: &KeyNode, output: &mut String) {
+        output.push('{');
+        output.push_str(self.text(key.ident));
+        if let Some(default) = &key.default {
+            output.push(':');
+            self.format_node(&default.item, output);
+        }
+        output.push('}');
+    }
+
+    // Convert a node into the token it represents and push the
+    // spans of the element and its defaults in pre-order.
+    fn lower(&self, node: &SyntaxNode, spans: &mut Vec<Span>) -> ContentToken {
+        spans.push(node.span());
+        let mut key = |key: &KeyNode| {
+            ContentToken::Key(
+                self.text(key.ident).to_owned(),
+                key.default
+                    .as_ref()
+                    .map(|default| Box::new(self.lower(&default.item, spans))),
+            )
+        };
+
+        match node {
+            SyntaxNode::Text(span) => ContentToken::Text(self.text(*span).to_owned()),
+            SyntaxNode::Key(node) => key(node),
+            SyntaxNode::Option(node) => ContentToken::Option(Box::new(key(&node.key))),
+            SyntaxNode::Constant(node) => ContentToken::Constant(self.text(node.ident).to_owned()),
+        }
+    }
+}
+
+/// Writes the exact source the tree was parsed from.
+impl fmt::Display for SyntaxTree<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for span in self.leaves() {
+            f.write_str(self.text(span))?;
+        }
+        Ok(())
+    }
+}
+
+impl From<SyntaxTree<'_>> for ContentTokens {
+    fn from(tree: SyntaxTree<'_>) -> Self {
+        let mut tokens = match &tree.header {
+            Some(header) => Self::from(header.locale.clone()),
+            None => Self::new(),
+        };
+        tokens.time_zone = tree.time_zone.as_ref().map(|node| node.time_zone);
+        for node in &tree.items {
+            let mut spans = vec![];
+            let token = tree.lower(node, &mut spans);
+            tokens.push_spanned(token, spans);
+        }
+        for e in tree.friendly_errors {
+            tokens.add_friendly(e);
+        }
+        tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATES: [&str; 8] = [
+        "locale \n :  \t en_us\nHello {name}!",
+        "locale:fr-FR\n{key}$Constant${Option}",
+        "S ${Anrede} {name}\n{n}\n$M\n$S",
+        "{name:Peter} bla ${bye:{mfg:MfG}}",
+        "Hallo Herr {name:${Kontake:Müller}}, ich wollte ...",
+        "locale: nope\nThe locale is just text: {key:$default}",
+        "locale:de-DE\ntimezone :\tEurope/Berlin\n$Day",
+        "timezone: Mars/Olympus\nThe time zone is just text",
+    ];
+
+    #[test]
+    fn syntax_trees_are_lossless() {
+        for template in TEMPLATES {
+            let tree = SyntaxTree::parse(template).unwrap();
+            assert_eq!(tree.to_string(), template);
+
+            // The leaves are adjacent and cover the entire source.
+            let mut end = 0;
+            for span in tree.leaves() {
+                assert_eq!(span.start, end, "Gap in the syntax tree of {template:?}");
+                end = span.end;
+            }
+            assert_eq!(end, template.len());
+        }
+    }
+
+    #[test]
+    fn templates_are_formatted() {
+        let cases = vec![
+            (
+                "locale \n :  \t en_us\nHello {name}!",
+                "locale: en-US\nHello {name}!",
+                "Locale header is normalised",
+            ),
+            (
+                "locale:fr-FR\n{key}$Constant${Option:{a:b}}",
+                "locale: fr-FR\n{key}$Constant${Option:{a:b}}",
+                "Elements are kept as they are",
+            ),
+            (
+                "locale:de_de\ntimezone :America/Argentina/Buenos_Aires\n$Hour",
+                "locale: de-DE\ntimezone: America/Argentina/Buenos_Aires\n$Hour",
+                "Time zone setting is normalised",
+            ),
+            (
+                "Some text without a header",
+                "Some text without a header",
+                "No locale header is added",
+            ),
+        ];
+        for (template, expected, case) in cases {
+            let tree = SyntaxTree::parse(template).unwrap();
+            assert_eq!(tree.format(), expected, "Test case: {case}");
+        }
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        for template in TEMPLATES {
+            let formatted = SyntaxTree::parse(template).unwrap().format();
+            let reformatted = SyntaxTree::parse(&formatted).unwrap().format();
+            assert_eq!(formatted, reformatted);
+        }
+    }
+
+    #[test]
+    fn spans_point_into_the_source() {
+        let tree = SyntaxTree::parse("a {name:$Me} b").unwrap();
+        let key = match &tree.items()[1] {
+            SyntaxNode::Key(key) => key,
+            other => panic!("Expected a key, got {other:?}"),
+        };
+        assert_eq!(tree.text(key.ident), "name");
+        assert_eq!(tree.text(tree.items()[1].span()), "{name:$Me}");
+        let default = key.default.as_ref().unwrap();
+        assert_eq!(tree.text(default.item.span()), "$Me");
+    }
+}
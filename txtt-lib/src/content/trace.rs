@@ -0,0 +1,105 @@
+//! Traces of how the elements of a template are resolved.
+//!
+//! A trace lists every element of a template together with the chain of
+//! defaults which was followed to find its content, the content which
+//! was used and any content which was available but shadowed by it.
+use super::source_map::Origin;
+use super::{ContentIndex, ContentType};
+use std::fmt;
+
+/// Content available for an element.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    /// The element the content is available for.
+    pub element: ContentIndex,
+    pub content: String,
+    pub origin: Origin,
+}
+
+/// How a single element is resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolution {
+    pub element: ContentIndex,
+    /// Elements followed through defaults, starting with `element` and
+    /// ending with the element whose content is used or the last one tried.
+    pub chain: Vec<ContentIndex>,
+    /// The content which is used, if any.
+    pub used: Option<Candidate>,
+    /// Content which was available further down the chain but not used.
+    pub shadowed: Vec<Candidate>,
+}
+
+/// Resolutions of all elements of a template in the order they first appear in.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Trace(Vec<Resolution>);
+
+impl Trace {
+    pub(crate) fn new(resolutions: Vec<Resolution>) -> Self {
+        Self(resolutions)
+    }
+
+    pub fn resolutions(&self) -> &[Resolution] {
+        &self.0
+    }
+
+    /// Get the resolution of the element `idx`.
+    pub fn get(&self, idx: &ContentIndex) -> Option<&Resolution> {
+        self.0.iter().find(|resolution| &resolution.element == idx)
+    }
+}
+
+// Write an element as it is written in template source
+fn write_element(f: &mut fmt::Formatter, idx: &ContentIndex) -> fmt::Result {
+    match idx.content_type() {
+        ContentType::Key => write!(f, "{{{}}}", idx.ident()),
+        ContentType::Option => write!(f, "${{{}}}", idx.ident()),
+        ContentType::Constant => write!(f, "${}", idx.ident()),
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} (", self.content)?;
+        match (self.origin, self.element.content_type()) {
+            (Origin::UserInput, _) => f.write_str("key ")?,
+            (Origin::ContentState, ContentType::Option) => f.write_str("choice for ")?,
+            (Origin::ContentState, _) => f.write_str("constant ")?,
+            (Origin::Default, _) => f.write_str("default literal of ")?,
+            (Origin::Dynamic, _) => f.write_str("dynamic element ")?,
+        }
+        write_element(f, &self.element)?;
+        f.write_str(")")
+    }
+}
+
+/// Writes the chain, the used content and the shadowed content of the element.
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_element(f, &self.element)?;
+        f.write_str("\n  chain: ")?;
+        for (i, idx) in self.chain.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" -> ")?;
+            }
+            write_element(f, idx)?;
+        }
+        match &self.used {
+            Some(used) if used.content.is_empty() => write!(f, "\n  value: empty {used}")?,
+            Some(used) => write!(f, "\n  value: {used}")?,
+            None => f.write_str("\n  value: missing")?,
+        }
+        for shadowed in &self.shadowed {
+            write!(f, "\n  shadowed: {shadowed}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for resolution in &self.0 {
+            writeln!(f, "{resolution}")?;
+        }
+        Ok(())
+    }
+}
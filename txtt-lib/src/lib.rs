@@ -1,3 +1,8 @@
 mod content;
 pub use content::{VolatileContent, ContentState};
+pub use content::{ast, date, provider, source_map, syntax, trace};
+pub use content::{ContentIndex, ContentRequirement, ContentType, RequiredContent};
+pub use content::{Clock, DynContext, DynamicNames, DynamicProvider, EnvAccess};
 pub mod template;
+#[cfg(test)]
+mod test_log;
@@ -1,19 +1,91 @@
+#[cfg(feature = "binary")]
+mod binary;
+mod builder;
+mod variants;
+#[cfg(feature = "binary")]
+pub use binary::{BinaryError, FORMAT_VERSION};
+pub use builder::{BuildError, TemplateBuilder};
+pub use crate::content::{MatchError, TextMatch};
+pub use variants::{Variant, Variants};
+
 use crate::content::*;
+use crate::source_map::{MappedRange, SourceMap};
+use crate::trace::Trace;
+use std::{fmt, io};
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "SerializedTemplate"))]
 pub struct Template {
     tokens: ContentTokens,
+    // The requirements and the compiled tokens are derived from the
+    // tokens, so they are derived again instead of being serialised.
+    #[cfg_attr(feature = "serde", serde(skip_serializing))]
     required: RequiredContent,
+    #[cfg_attr(feature = "serde", serde(skip_serializing))]
+    compiled: CompiledTokens,
+}
+
+// Serialised fields of a `Template`
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct SerializedTemplate {
+    tokens: ContentTokens,
+}
+
+// Serialised templates may come from anywhere, so their tokens are
+// checked while compiling them.
+#[cfg(feature = "serde")]
+impl TryFrom<SerializedTemplate> for Template {
+    type Error = CompileError;
+
+    fn try_from(from: SerializedTemplate) -> Result<Self, Self::Error> {
+        let required = from.tokens.draft();
+        let compiled = CompiledTokens::new(&from.tokens, &required)?;
+        Ok(Self {
+            tokens: from.tokens,
+            required,
+            compiled,
+        })
+    }
 }
 
 impl Template {
     /// Create a new `Template` instance by parsing the input string
     pub fn parse(s: &str) -> Result<Self, TemplateError> {
         let tokens: ContentTokens = s.parse()?;
+        Ok(Self::from_tokens(tokens))
+    }
+
+    /// Parse the input string like `parse`, but also read the members of the
+    /// namespaces declared by the providers registered in `settings`, e.g.
+    /// `$Counter.invoice` instead of `$Counter` followed by the text `.invoice`.
+    /// Without the sigil, date expressions like `$Today+14d` and random numbers
+    /// are only read if the dynamic names of `settings` are `DynamicNames::Bare`.
+    pub fn parse_with(s: &str, settings: &CompilationSettings) -> Result<Self, TemplateError> {
+        let tokens =
+            ContentTokens::parse_with(s, settings.namespaces(), settings.dynamic_names)?;
+        Ok(Self::from_tokens(tokens))
+    }
+
+    // Create a new `Template` instance from tokens, compute its requirements
+    // and compile it for rendering.
+    pub(crate) fn from_tokens(tokens: ContentTokens) -> Self {
         let required = tokens.draft();
-        Ok(Self { tokens, required })
+        let compiled = CompiledTokens::new(&tokens, &required)
+            .expect("Requirements drafted from tokens always match them");
+        Self {
+            tokens,
+            required,
+            compiled,
+        }
+    }
+
+    /// Print the template as source, which parses back into the same template.
+    pub fn to_source(&self) -> Result<String, TemplateError> {
+        Ok(self.tokens.to_source()?)
     }
 
     /// Compile the template using the default settings.
@@ -22,13 +94,199 @@ impl Template {
         volatile_content: VolatileContent,
         content_state: ContentState,
     ) -> Result<String, TemplateError> {
-        // Delegate the compilation to a `TemplateWithSettings` instance
-        // with a default settings field.
-        let with_settings = TemplateWithSettings {
-            template: self,
-            settings: CompilationSettings::default(),
-        };
-        with_settings.fill_out(volatile_content, content_state)
+        self.render(&volatile_content, &content_state)
+    }
+
+    /// Compile the template using the default settings without consuming the
+    /// template or the content, so a template only needs to be parsed once
+    /// to be filled out any number of times (also from multiple threads).
+    pub fn render(
+        &self,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+    ) -> Result<String, TemplateError> {
+        self.render_string(&CompilationSettings::default(), volatile_content, content_state)
+    }
+
+    /// Substitute all elements whose content is given and return the rest of the
+    /// template as a new template, which can be filled out later or printed using
+    /// `to_source`. Elements without content are kept together with their defaults,
+    /// in which given content is substituted as well. Dynamic elements are always
+    /// kept, because they should only be evaluated when the template is filled out.
+    ///
+    /// Substituted content becomes text, so printing the new template fails if
+    /// the content contains any of the characters `{`, `}` or `$`. Printing it
+    /// also fails with `PrintError::AmbiguousConstant` if the content directly
+    /// follows a kept constant and would become part of its identifier, e.g.
+    /// `$Title{name}` with the name `Smith`, which would be read as `$TitleSmith`.
+    pub fn partially_fill(
+        &self,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+    ) -> Result<Template, TemplateError> {
+        let settings = CompilationSettings::default();
+        let ctx = settings.dyn_context(&self.tokens);
+        let tokens = self
+            .tokens
+            .partially_fill(volatile_content, content_state, ctx.as_ref())?;
+        Ok(Template::from_tokens(tokens))
+    }
+
+    /// Iterate over all variants of the template, one for every combination of
+    /// choices for its options. Options which have a default in the template
+    /// use their default as an additional alternative. Keys without a default
+    /// are filled out with a placeholder of the form `{name}`. For templates
+    /// with many variants, use `Iterator::take` or `Variants::variant`.
+    pub fn variants<'a>(&'a self, content_state: &'a ContentState) -> Variants<'a> {
+        Variants::new(self, content_state, None)
+    }
+
+    /// Fill out the template with generated content to preview it.
+    /// See `RequiredContent::mock_volatile_content`.
+    pub fn preview(
+        &self,
+        content_state: &ContentState,
+        seed: u64,
+    ) -> Result<String, TemplateError> {
+        let volatile_content = self.required.mock_volatile_content(content_state, seed);
+        self.render(&volatile_content, content_state)
+    }
+
+    /// Recover the volatile content which fills out the template to `text` using
+    /// `content_state`. Dynamic elements and constants missing from the content
+    /// state match any text. If the text can be matched in more than one way, the
+    /// elements which differ between the matches are reported as ambiguous. Only
+    /// the first 16 ways are compared, so an element which only differs in a later
+    /// way is not reported.
+    pub fn match_text(
+        &self,
+        text: &str,
+        content_state: &ContentState,
+    ) -> Result<TextMatch, TemplateError> {
+        let settings = CompilationSettings::default();
+        let ctx = settings.dyn_context(&self.tokens);
+        Ok(self.tokens.match_text(text, content_state, ctx.as_ref())?)
+    }
+
+    /// Compile the template using the default settings like `render` and also
+    /// return a map from the ranges of the output to the elements they were
+    /// written for. See `source_map::SourceMap`.
+    pub fn render_with_source_map(
+        &self,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+    ) -> Result<(String, SourceMap), TemplateError> {
+        self.render_mapped(&CompilationSettings::default(), volatile_content, content_state)
+    }
+
+    /// Trace how every element of the template is resolved using the default
+    /// settings: the chain of defaults followed, the content used and the
+    /// content shadowed by it. Missing or empty content is part of the trace
+    /// instead of an error.
+    pub fn explain(&self, volatile_content: &VolatileContent, content_state: &ContentState) -> Trace {
+        let settings = CompilationSettings::default();
+        let ctx = settings.dyn_context(&self.tokens);
+        self.compiled
+            .trace(volatile_content, content_state, ctx.as_ref())
+    }
+
+    /// Compile the template using the default settings and write the output
+    /// to `writer` piece by piece. See `TemplateWithSettings::render_to`.
+    pub fn render_to<W: io::Write>(
+        &self,
+        writer: &mut W,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+    ) -> Result<(), TemplateError> {
+        let settings = CompilationSettings::default();
+        self.render_with(&settings, volatile_content, content_state, |s, _| {
+            Ok(writer.write_all(s.as_bytes())?)
+        })
+    }
+
+    /// Like `render_to` but for writers implementing `fmt::Write`.
+    pub fn render_fmt<W: fmt::Write>(
+        &self,
+        writer: &mut W,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+    ) -> Result<(), TemplateError> {
+        let settings = CompilationSettings::default();
+        self.render_with(&settings, volatile_content, content_state, |s, _| {
+            Ok(writer.write_str(s)?)
+        })
+    }
+
+    // Every way of compiling a template ends up here.
+    fn render_with(
+        &self,
+        settings: &CompilationSettings,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+        output: impl FnMut(&str, Option<Resolved>) -> Result<(), TemplateError>,
+    ) -> Result<(), TemplateError> {
+        // Dynamic elements are only evaluated if they are not ignored.
+        // They are never evaluated if the "dyn" feature is disabled.
+        let ctx = settings.dyn_context(&self.tokens);
+        self.compiled.fill_out_with(
+            volatile_content,
+            content_state,
+            ctx.as_ref(),
+            settings.stream,
+            output,
+        )
+    }
+
+    // Compile the template into a `String`.
+    fn render_string(
+        &self,
+        settings: &CompilationSettings,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+    ) -> Result<String, TemplateError> {
+        let mut output = String::new();
+        self.render_with(settings, volatile_content, content_state, |s, _| {
+            output.push_str(s);
+            Ok(())
+        })?;
+        Ok(output)
+    }
+
+    // Compile the template into a `String` and map its ranges to the elements.
+    fn render_mapped(
+        &self,
+        settings: &CompilationSettings,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+    ) -> Result<(String, SourceMap), TemplateError> {
+        let mut output = String::new();
+        let mut source_map = SourceMap::new();
+        self.render_with(settings, volatile_content, content_state, |s, resolved| {
+            if let Some(resolved) = resolved {
+                source_map.push(MappedRange {
+                    range: output.len()..output.len() + s.len(),
+                    element: resolved.element.clone(),
+                    source: resolved.source.clone(),
+                    origin: resolved.origin,
+                });
+            }
+            output.push_str(s);
+            Ok(())
+        })?;
+        Ok((output, source_map))
+    }
+
+    /// The time zone set at the start of the template, if any.
+    #[inline]
+    pub const fn time_zone(&self) -> Option<&chrono_tz::Tz> {
+        self.tokens.time_zone.as_ref()
+    }
+
+    /// Iterate over the top-level elements of the template.
+    /// Use `ast::walk` to also visit all defaults.
+    #[inline]
+    pub fn elements(&self) -> ast::Elements<'_> {
+        self.tokens.elements()
     }
 
     #[inline]
@@ -52,21 +310,78 @@ pub enum TemplateError {
     UserError(#[from] UserError),
     #[error(transparent)]
     FillOutError(#[from] FillOutError),
+    #[error(transparent)]
+    PrintError(#[from] PrintError),
+    #[error(transparent)]
+    BuildError(#[from] BuildError),
+    #[error(transparent)]
+    MatchError(#[from] MatchError),
+    #[error(transparent)]
+    #[cfg_attr(feature = "serde", serde(skip_serializing, skip_deserializing))]
+    IoError(#[from] io::Error),
+    #[error(transparent)]
+    #[cfg_attr(feature = "serde", serde(skip_serializing, skip_deserializing))]
+    FmtError(#[from] fmt::Error),
+    #[cfg(feature = "binary")]
+    #[error(transparent)]
+    #[cfg_attr(feature = "serde", serde(skip_serializing, skip_deserializing))]
+    BinaryError(#[from] BinaryError),
 }
 
 /// Settings for compiling a template.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CompilationSettings {
     /// If set dynamic elements (i.e. meta constants) will be ignored
     /// and treated as regular elements.
     pub ignore_dynamics: bool,
+    /// If set the output is written while the elements are resolved instead of
+    /// resolving all elements first. Errors are then only reported for elements
+    /// which are written and part of the output may be written before an error.
+    pub stream: bool,
+    /// Source of the current time for dynamic elements, e.g. a fixed
+    /// time to fill out a template again exactly like before.
+    pub clock: Clock,
+    /// Time zone in which dynamic elements are evaluated. It takes precedence
+    /// over the time zone setting of the template. If neither is set, the local
+    /// time zone of the system is used.
+    pub time_zone: Option<chrono_tz::Tz>,
+    /// Environment elements the template may read, e.g. `$Env.USER`. They
+    /// are only evaluated if the "env" feature is enabled.
+    pub env: EnvAccess,
+    /// Providers of dynamic elements besides the built-in meta constants and
+    /// environment elements. They are asked in the order they were registered.
+    pub providers: Vec<Box<dyn DynamicProvider>>,
+    /// Whether dynamic elements may be written without their sigil, e.g. `$Year`
+    /// instead of `$@Year`. They may by default, so older templates keep working.
+    pub dynamic_names: DynamicNames,
 }
 
-impl std::default::Default for CompilationSettings {
-    fn default() -> Self {
-        Self {
-            ignore_dynamics: false,
-        }
+impl CompilationSettings {
+    /// Register `provider` to provide the content of dynamic elements, e.g. `$TicketNumber`.
+    pub fn register(&mut self, provider: impl DynamicProvider + 'static) {
+        self.providers.push(Box::new(provider));
+    }
+
+    /// Names of the namespaces declared by the registered providers.
+    /// See `DynamicProvider::namespaces`.
+    pub fn namespaces(&self) -> Vec<String> {
+        self.providers
+            .iter()
+            .flat_map(|provider| provider.namespaces())
+            .map(|namespace| (*namespace).to_owned())
+            .collect()
+    }
+
+    // The context in which the dynamic elements of `tokens` are evaluated,
+    // unless they are ignored
+    fn dyn_context<'a>(&'a self, tokens: &'a ContentTokens) -> Option<DynContext<'a>> {
+        (!self.ignore_dynamics).then(|| {
+            let time_zone = self.time_zone.or(tokens.time_zone);
+            DynContext::new(&tokens.locale, time_zone, &self.clock)
+                .with_env(&self.env)
+                .with_providers(&self.providers)
+                .with_names(self.dynamic_names)
+        })
     }
 }
 
@@ -79,28 +394,121 @@ pub struct TemplateWithSettings {
 
 impl TemplateWithSettings {
     /// Compile a template, considering the given settings.
-    /// This method is the only way to compile a template. The `Template::fill_out` method
-    /// simply creates a new `TemplateWithSettings` instance (with the default settings)
-    /// and then calls this method.
     pub fn fill_out(
         self,
         volatile_content: VolatileContent,
         content_state: ContentState,
-    ) -> Result<String, TemplateError> {        
-        let mut required = self.template.required;
-        required.add_constants(content_state.constants);
-        required.add_options(volatile_content.choices, content_state.options);
-        required.add_keys(volatile_content.keys);
-
-        let settings = self.settings;
-        if settings.ignore_dynamics == false {
-            // Evaluate all dynamic elements in the requirements.
-            // `eval_dyn` does nothing if the "dyn" feature is disabled.
-            required.eval_dyn();
-        }
+    ) -> Result<String, TemplateError> {
+        self.render(&volatile_content, &content_state)
+    }
+
+    /// Compile a template, considering the given settings, without consuming
+    /// the template or the content. See `Template::render`.
+    pub fn render(
+        &self,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+    ) -> Result<String, TemplateError> {
+        self.template
+            .render_string(&self.settings, volatile_content, content_state)
+    }
+
+    /// Fill out the template with generated content to preview it, considering
+    /// the given settings. See `Template::preview`.
+    pub fn preview(
+        &self,
+        content_state: &ContentState,
+        seed: u64,
+    ) -> Result<String, TemplateError> {
+        let volatile_content = self.template.required.mock_volatile_content(content_state, seed);
+        self.render(&volatile_content, content_state)
+    }
+
+    /// Compile a template, considering the given settings, and map the ranges
+    /// of the output to elements. See `Template::render_with_source_map`.
+    pub fn render_with_source_map(
+        &self,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+    ) -> Result<(String, SourceMap), TemplateError> {
+        self.template
+            .render_mapped(&self.settings, volatile_content, content_state)
+    }
+
+    /// Trace how every element is resolved when filling out the template with
+    /// `fill_out`, considering the given settings. See `Template::explain`.
+    pub fn explain(&self, volatile_content: &VolatileContent, content_state: &ContentState) -> Trace {
+        let ctx = self.settings.dyn_context(&self.template.tokens);
+        self.template
+            .compiled
+            .trace(volatile_content, content_state, ctx.as_ref())
+    }
+
+    /// Fill out the template partially, considering the given settings.
+    /// See `Template::partially_fill`. If dynamic elements are ignored,
+    /// they are substituted like any other element.
+    pub fn partially_fill(
+        &self,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+    ) -> Result<Template, TemplateError> {
+        let ctx = self.settings.dyn_context(&self.template.tokens);
+        let tokens = self
+            .template
+            .tokens
+            .partially_fill(volatile_content, content_state, ctx.as_ref())?;
+        Ok(Template::from_tokens(tokens))
+    }
+
+    /// Recover the volatile content, considering the given settings.
+    /// See `Template::match_text`. If dynamic elements are ignored,
+    /// they are matched like any other constant.
+    pub fn match_text(
+        &self,
+        text: &str,
+        content_state: &ContentState,
+    ) -> Result<TextMatch, TemplateError> {
+        let ctx = self.settings.dyn_context(&self.template.tokens);
+        Ok(self
+            .template
+            .tokens
+            .match_text(text, content_state, ctx.as_ref())?)
+    }
 
-        let content: FullContent = required.try_into()?;
-        Ok(self.template.tokens.fill_out(content))
+    /// Iterate over all variants of the template filled out with the given
+    /// settings. See `Template::variants`.
+    pub fn variants<'a>(&'a self, content_state: &'a ContentState) -> Variants<'a> {
+        Variants::new(&self.template, content_state, Some(&self.settings))
+    }
+
+    /// Compile a template, considering the given settings, and write the output
+    /// to `writer` piece by piece without building it in memory first.
+    /// Unless `CompilationSettings::stream` is set, all elements are resolved
+    /// before anything is written, so nothing is written if filling out fails.
+    /// Writing is done in small pieces, so `writer` should be buffered.
+    pub fn render_to<W: io::Write>(
+        &self,
+        writer: &mut W,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+    ) -> Result<(), TemplateError> {
+        self.template
+            .render_with(&self.settings, volatile_content, content_state, |s, _| {
+                Ok(writer.write_all(s.as_bytes())?)
+            })
+    }
+
+    /// Like `render_to` but for writers implementing `fmt::Write`.
+    pub fn render_fmt<W: fmt::Write>(
+        &self,
+        writer: &mut W,
+        volatile_content: &VolatileContent,
+        content_state: &ContentState,
+    ) -> Result<(), TemplateError> {
+        self.template
+            .render_with(&self.settings, volatile_content, content_state, |s, _| {
+                Ok(writer.write_str(s)?)
+            })
     }
 }
 
@@ -143,22 +551,566 @@ mod tests {
         #[cfg(feature = "dyn")]
         {
             // This example uses meta constants to substitute dynamic information on
-            // the current date in the template.
-            use chrono::Utc;
-            let now = Utc::now();
-            let month = now.format("%B");
-            let day = now.format("%d");
-            let day_name = now.format("%A");
-            helper::test_fill_out(
-                "This template was compiled on $Month $DayNum which is a $Day",
-                &format!("This template was compiled on {month} {day} which is a {day_name}"),
-                "Dynamic meta content example",
-                VolatileContent::new(),
-                ContentState::new(),
+            // the date in the template. The clock is fixed so the date is known.
+            use chrono::{TimeZone, Utc};
+            let settings = CompilationSettings {
+                clock: Clock::Fixed(Utc.with_ymd_and_hms(2023, 3, 14, 9, 26, 53).unwrap()),
+                time_zone: Some(chrono_tz::UTC),
+                ..Default::default()
+            };
+            let result = Template::parse("This template was compiled on $Month $DayNum which is a $Day")
+                .unwrap()
+                .with_settings(settings)
+                .fill_out(VolatileContent::new(), ContentState::new())
+                .unwrap();
+            assert_eq!(
+                result,
+                "This template was compiled on March 14 which is a Tuesday",
+                "Test case: Dynamic meta content example"
             );
         }
     }
 
+    #[test]
+    fn templates_are_rendered_many_times() {
+        let template = Template::parse("${greeting} {name:you}!").unwrap();
+        let mut cs = ContentState::new();
+        cs.map_option("greeting", choice!("hi", "Hi"));
+        cs.map_option("greeting", choice!("bye", "Bye"));
+
+        let recipients = [("hi", Some("Paul"), "Hi Paul!"), ("bye", None, "Bye you!")];
+        for (choice, name, expected) in recipients {
+            let mut vc = VolatileContent::new();
+            vc.map_choice("greeting", choice);
+            if let Some(name) = name {
+                vc.map_key("name", name);
+            }
+            assert_eq!(template.render(&vc, &cs).unwrap(), expected);
+        }
+        // Rendering did not change the requirements of the template.
+        assert_eq!(template, Template::parse("${greeting} {name:you}!").unwrap());
+    }
+
+    #[test]
+    fn templates_are_rendered_in_parallel() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Template>();
+        assert_send_sync::<TemplateWithSettings>();
+
+        let template = Template::parse("Dear {name}").unwrap();
+        let cs = ContentState::new();
+        std::thread::scope(|scope| {
+            for name in ["Paul", "Jessica", "Leto"] {
+                let (template, cs) = (&template, &cs);
+                scope.spawn(move || {
+                    let mut vc = VolatileContent::new();
+                    vc.map_key("name", name);
+                    assert_eq!(template.render(&vc, cs).unwrap(), format!("Dear {name}"));
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn templates_are_rendered_to_writers() {
+        let template = Template::parse("Dear {name}, $Me").unwrap();
+        let mut cs = ContentState::new();
+        cs.map_constant("Me", "Paul");
+        let mut vc = VolatileContent::new();
+        vc.map_key("name", "Jessica");
+
+        let mut bytes = vec![];
+        template.render_to(&mut bytes, &vc, &cs).unwrap();
+        assert_eq!(bytes, b"Dear Jessica, Paul");
+        let mut output = String::new();
+        template.render_fmt(&mut output, &vc, &cs).unwrap();
+        assert_eq!(output, "Dear Jessica, Paul");
+    }
+
+    #[test]
+    fn nothing_is_written_unless_streaming() {
+        // `$Me` is missing, so filling out fails after "Dear Jessica, " could be written.
+        let template = Template::parse("Dear {name}, $Me").unwrap();
+        let mut vc = VolatileContent::new();
+        vc.map_key("name", "Jessica");
+        let cs = ContentState::new();
+
+        let mut bytes = vec![];
+        let result = template.render_to(&mut bytes, &vc, &cs);
+        assert!(matches!(result, Err(TemplateError::FillOutError(_))));
+        assert!(bytes.is_empty());
+
+        let settings = CompilationSettings {
+            stream: true,
+            ..Default::default()
+        };
+        let template = template.with_settings(settings);
+        let result = template.render_to(&mut bytes, &vc, &cs);
+        assert!(matches!(result, Err(TemplateError::FillOutError(_))));
+        assert_eq!(bytes, b"Dear Jessica, ");
+    }
+
+    #[test]
+    fn write_errors_are_reported() {
+        struct Full;
+        impl io::Write for Full {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::WriteZero.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let template = Template::parse("text").unwrap();
+        let result = template.render_to(&mut Full, &VolatileContent::new(), &ContentState::new());
+        assert!(matches!(result, Err(TemplateError::IoError(_))));
+    }
+
+    #[test]
+    fn output_is_mapped_to_elements() {
+        use crate::source_map::Origin;
+
+        let template =
+            Template::parse("${greeting} {name}, {message:{fallback:Bye}} $Me").unwrap();
+        let mut cs = ContentState::new();
+        cs.map_constant("Me", "Paul");
+        cs.map_option("greeting", choice!("hi", "Hi"));
+        let mut vc = VolatileContent::new();
+        vc.map_choice("greeting", "hi");
+        vc.map_key("name", "Jessica");
+
+        let (output, source_map) = template.render_with_source_map(&vc, &cs).unwrap();
+        let idx = ContentIndex::new;
+        let expected = vec![
+            ("Hi", idx(ContentType::Option, "greeting"), idx(ContentType::Option, "greeting"), Origin::ContentState),
+            ("Jessica", idx(ContentType::Key, "name"), idx(ContentType::Key, "name"), Origin::UserInput),
+            ("Bye", idx(ContentType::Key, "message"), idx(ContentType::Key, "fallback"), Origin::Default),
+            ("Paul", idx(ContentType::Constant, "Me"), idx(ContentType::Constant, "Me"), Origin::ContentState),
+        ];
+        assert_eq!(source_map.ranges().len(), expected.len());
+        for (mapped, (content, element, source, origin)) in source_map.ranges().iter().zip(expected) {
+            assert_eq!(&output[mapped.range.clone()], content);
+            assert_eq!(mapped.element, element);
+            assert_eq!(mapped.source, source);
+            assert_eq!(mapped.origin, origin);
+        }
+
+        // "Hi Jessica, Bye Paul"
+        assert_eq!(source_map.at(4).unwrap().element, idx(ContentType::Key, "name"));
+        assert!(source_map.at(2).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "dyn")]
+    fn dynamic_output_is_mapped() {
+        use crate::source_map::Origin;
+
+        let template = Template::parse("Year: $Year").unwrap();
+        let (output, source_map) = template
+            .render_with_source_map(&VolatileContent::new(), &ContentState::new())
+            .unwrap();
+        let mapped = &source_map.ranges()[0];
+        assert_eq!(mapped.range, 6..output.len());
+        assert_eq!(mapped.origin, Origin::Dynamic);
+    }
+
+    #[test]
+    fn resolutions_are_traced() {
+        use crate::source_map::Origin;
+
+        let template = Template::parse("{name:Peter} {message:{fallback:Bye}} {a:{b}}").unwrap();
+        let mut vc = VolatileContent::new();
+        vc.map_key("name", "Jessica");
+        let trace = template.explain(&vc, &ContentState::new());
+        let idx = |ident| ContentIndex::new(ContentType::Key, ident);
+
+        let name = trace.get(&idx("name")).unwrap();
+        assert_eq!(name.chain, vec![idx("name")]);
+        assert_eq!(name.used.as_ref().unwrap().origin, Origin::UserInput);
+        assert_eq!(name.shadowed[0].content, "Peter");
+        assert_eq!(name.shadowed[0].origin, Origin::Default);
+
+        let message = trace.get(&idx("message")).unwrap();
+        assert_eq!(message.chain, vec![idx("message"), idx("fallback")]);
+        assert_eq!(message.used.as_ref().unwrap().content, "Bye");
+        assert!(message.shadowed.is_empty());
+
+        let a = trace.get(&idx("a")).unwrap();
+        assert_eq!(a.chain, vec![idx("a"), idx("b")]);
+        assert!(a.used.is_none());
+
+        assert_eq!(
+            trace.to_string(),
+            "{name}\n  chain: {name}\n  value: \"Jessica\" (key {name})\n  \
+            shadowed: \"Peter\" (default literal of {name})\n\
+            {message}\n  chain: {message} -> {fallback}\n  \
+            value: \"Bye\" (default literal of {fallback})\n\
+            {fallback}\n  chain: {fallback}\n  value: \"Bye\" (default literal of {fallback})\n\
+            {a}\n  chain: {a} -> {b}\n  value: missing\n\
+            {b}\n  chain: {b}\n  value: missing\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "dyn")]
+    fn shadowed_constants_are_traced() {
+        let template = Template::parse("$Year").unwrap();
+        let mut cs = ContentState::new();
+        cs.map_constant("Year", "1999");
+        let idx = ContentIndex::new(ContentType::Constant, "Year");
+
+        let trace = template.explain(&VolatileContent::new(), &cs);
+        assert_eq!(trace.get(&idx).unwrap().shadowed[0].content, "1999");
+
+        let settings = CompilationSettings {
+            ignore_dynamics: true,
+            ..Default::default()
+        };
+        let trace = template.with_settings(settings).explain(&VolatileContent::new(), &cs);
+        let year = trace.get(&idx).unwrap();
+        assert_eq!(year.used.as_ref().unwrap().content, "1999");
+        assert!(year.shadowed.is_empty());
+    }
+
+    #[derive(Debug)]
+    struct OnCall(&'static str);
+
+    impl DynamicProvider for OnCall {
+        fn resolve(&self, ident: &str, _: &DynContext) -> Option<String> {
+            (ident == "OnCallEngineer").then(|| self.0.to_owned())
+        }
+    }
+
+    #[test]
+    fn registered_providers_fill_out_elements() {
+        use crate::source_map::Origin;
+
+        let template = Template::parse("Call $OnCallEngineer, not $Me.").unwrap();
+        let mut cs = ContentState::new();
+        cs.map_constant("OnCallEngineer", "nobody");
+        cs.map_constant("Me", "Paul");
+        let mut settings = CompilationSettings::default();
+        settings.register(OnCall("Jessica"));
+        let template = template.with_settings(settings);
+
+        let output = template.render(&VolatileContent::new(), &cs).unwrap();
+        assert_eq!(output, "Call Jessica, not Paul.");
+        let idx = ContentIndex::new(ContentType::Constant, "OnCallEngineer");
+        let trace = template.explain(&VolatileContent::new(), &cs);
+        let resolution = trace.get(&idx).unwrap();
+        let used = resolution.used.as_ref().unwrap();
+        assert_eq!(used.origin, Origin::Dynamic);
+        assert_eq!(used.to_string(), "\"Jessica\" (dynamic element $OnCallEngineer)");
+        assert_eq!(resolution.shadowed[0].content, "nobody");
+    }
+
+    #[test]
+    fn constants_shadowed_by_registered_providers_are_reported() {
+        crate::test_log::init();
+        let source = "Call $OnCallEngineer or {backup:$OnCallEngineer}.";
+        let template = Template::parse(source).unwrap();
+        let mut cs = ContentState::new();
+        cs.map_constant("OnCallEngineer", "Ringo");
+        let mut settings = CompilationSettings::default();
+        settings.register(OnCall("Jessica"));
+        let template = template.with_settings(settings);
+        let warnings = || crate::test_log::warnings_containing("constant OnCallEngineer ");
+
+        template.render(&VolatileContent::new(), &cs).unwrap();
+        assert_eq!(warnings().len(), 1);
+        let partial = template.partially_fill(&VolatileContent::new(), &cs).unwrap();
+        assert_eq!(partial.to_source().unwrap(), format!("locale: en-US\n{source}"));
+        assert_eq!(warnings().len(), 2);
+        template.match_text("Call Jessica or Paul.", &cs).unwrap();
+        assert_eq!(warnings().len(), 3);
+    }
+
+    #[derive(Debug)]
+    struct Rooms;
+
+    impl DynamicProvider for Rooms {
+        fn resolve(&self, ident: &str, _: &DynContext) -> Option<String> {
+            ident.strip_prefix("Room.").map(str::to_uppercase)
+        }
+
+        fn namespaces(&self) -> &[&str] {
+            &["Room"]
+        }
+    }
+
+    #[test]
+    fn providers_declare_namespaces() {
+        let source = "Meet in $Room.b12.";
+        let mut settings = CompilationSettings::default();
+        settings.register(Rooms);
+        let template = Template::parse_with(source, &settings).unwrap();
+        assert_eq!(template.to_source().unwrap(), format!("locale: en-US\n{source}"));
+        let output = template
+            .with_settings(settings)
+            .render(&VolatileContent::new(), &ContentState::new())
+            .unwrap();
+        assert_eq!(output, "Meet in B12.");
+
+        // Templates parsed without the provider keep their meaning.
+        let template = Template::parse(source).unwrap();
+        let idx = ContentIndex::new(ContentType::Constant, "Room");
+        assert!(template.required().get(&idx).is_some());
+    }
+
+    #[derive(Debug, Default)]
+    struct Tickets(std::sync::atomic::AtomicUsize);
+
+    impl DynamicProvider for Tickets {
+        fn resolve(&self, ident: &str, _: &DynContext) -> Option<String> {
+            use std::sync::atomic::Ordering;
+
+            let count = self.0.fetch_add(1, Ordering::Relaxed) + 1;
+            (ident == "TicketNumber").then(|| format!("T-{count}"))
+        }
+
+        fn provides(&self, ident: &str, _: &DynContext) -> bool {
+            ident == "TicketNumber"
+        }
+    }
+
+    #[test]
+    fn dynamic_elements_are_only_evaluated_if_written() {
+        let template = Template::parse("{ticket:$TicketNumber}, {again:$TicketNumber}").unwrap();
+        let mut settings = CompilationSettings::default();
+        settings.register(Tickets::default());
+        let template = template.with_settings(settings);
+        let cs = ContentState::new();
+
+        let mut vc = VolatileContent::new();
+        vc.map_key("ticket", "T-0");
+        vc.map_key("again", "T-0");
+        assert_eq!(template.render(&vc, &cs).unwrap(), "T-0, T-0");
+        // A dynamic element used more than once is evaluated once.
+        let output = template.render(&VolatileContent::new(), &cs).unwrap();
+        assert_eq!(output, "T-1, T-1");
+    }
+
+    #[test]
+    fn shadowed_constants_are_reported_once_per_template() {
+        crate::test_log::init();
+        let mut cs = ContentState::new();
+        cs.map_constant("Room.c3", "Hall");
+        cs.map_option("greeting", choice!("casual", "Hi"));
+        cs.map_option("greeting", choice!("formal", "Dear"));
+        let mut settings = CompilationSettings::default();
+        settings.register(Rooms);
+        let source = "${greeting}, meet in $Room.c3 (not $Room.c3x) or $Room.c3.";
+        let template = Template::parse_with(source, &settings).unwrap();
+        let template = template.with_settings(settings);
+
+        let outputs: Vec<_> = template.variants(&cs).map(|v| v.output.unwrap()).collect();
+        assert_eq!(outputs.len(), 2);
+        let warnings = crate::test_log::warnings_containing("constant Room.c3 ");
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+        assert!(warnings[0].contains("Write $@Room.c3 for the dynamic element"));
+
+        // Another template reports it again.
+        let mut settings = CompilationSettings::default();
+        settings.register(Rooms);
+        let template = Template::parse_with(source, &settings).unwrap();
+        let mut vc = VolatileContent::new();
+        vc.map_choice("greeting", "casual");
+        template.with_settings(settings).render(&vc, &cs).unwrap();
+        assert_eq!(crate::test_log::warnings_containing("constant Room.c3 ").len(), 2);
+    }
+
+    #[test]
+    #[cfg(feature = "dyn")]
+    fn shadowed_bases_of_date_expressions_are_reported() {
+        crate::test_log::init();
+        let template = Template::parse("Due: $Today+14d").unwrap();
+        let mut cs = ContentState::new();
+        cs.map_constant("Today", "Monday");
+        template.render(&VolatileContent::new(), &cs).unwrap();
+        let warnings = crate::test_log::warnings_containing("constant Today ");
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+        assert!(warnings[0].contains("Write $@Today+14d for the dynamic element"));
+
+        // Namespaced templates keep the constant followed by text.
+        let settings = CompilationSettings {
+            dynamic_names: DynamicNames::Namespaced,
+            ..Default::default()
+        };
+        let template = Template::parse_with("Due: $Today+14d", &settings).unwrap();
+        let output = template.with_settings(settings).render(&VolatileContent::new(), &cs);
+        assert_eq!(output.unwrap(), "Due: Monday+14d");
+    }
+
+    #[test]
+    #[cfg(feature = "dyn")]
+    fn namespaced_dynamic_elements_do_not_shadow_constants() {
+        use chrono::{TimeZone, Utc};
+
+        let source = "$Week / $@Week {x:$@Year}";
+        let mut cs = ContentState::new();
+        cs.map_constant("Week", "Busy week");
+        let vc = VolatileContent::new();
+        let render = |dynamic_names| {
+            let settings = CompilationSettings {
+                clock: Clock::Fixed(Utc.with_ymd_and_hms(2023, 3, 14, 9, 26, 53).unwrap()),
+                time_zone: Some(chrono_tz::UTC),
+                dynamic_names,
+                ..Default::default()
+            };
+            let template = Template::parse(source).unwrap().with_settings(settings);
+            template.render(&vc, &cs).unwrap()
+        };
+
+        assert_eq!(render(DynamicNames::Namespaced), "Busy week / 11 2023");
+        // Bare names keep working for older templates.
+        assert_eq!(render(DynamicNames::Bare), "11 / 11 2023");
+    }
+
+    #[test]
+    #[cfg(feature = "env")]
+    fn only_allowed_env_elements_are_read() {
+        let template = Template::parse("$TemplateName by $User: $Env.PATH").unwrap();
+        let mut cs = ContentState::new();
+        cs.map_constant("User", "Paul");
+        cs.map_constant("Env.PATH", "none");
+        let settings = CompilationSettings {
+            env: EnvAccess {
+                allowed: vec!["TemplateName".into(), "Env.PATH".into()],
+                template_path: Some("letters/invoice.txtt".into()),
+            },
+            ..Default::default()
+        };
+        let output = template
+            .with_settings(settings)
+            .render(&VolatileContent::new(), &cs)
+            .unwrap();
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(output, format!("invoice.txtt by Paul: {path}"));
+    }
+
+    #[test]
+    fn templates_are_filled_out_partially() {
+        let template =
+            Template::parse("Project $Project: {task:${prio:normal}} for {name:$Me}!").unwrap();
+        let mut cs = ContentState::new();
+        cs.map_constant("Project", "Dune");
+        cs.map_constant("Me", "Paul");
+        cs.map_option("prio", choice!("high", "urgent"));
+        let mut vc = VolatileContent::new();
+        vc.map_key("task", "");
+
+        let partial = template.partially_fill(&vc, &cs).unwrap();
+        assert_eq!(
+            partial.to_source().unwrap(),
+            "locale: en-US\nProject Dune: {task:${prio:normal}} for {name:Paul}!"
+        );
+
+        // Filling out the rest gives the same result as filling out everything at once.
+        vc.map_key("task", "Find water");
+        vc.map_key("name", "Jessica");
+        assert_eq!(
+            partial.render(&vc, &cs).unwrap(),
+            template.render(&vc, &cs).unwrap()
+        );
+        assert_eq!(
+            partial.partially_fill(&vc, &cs).unwrap(),
+            Template::parse("Project Dune: Find water for Jessica!").unwrap()
+        );
+    }
+
+    #[test]
+    fn text_behind_kept_constants_is_printed_if_it_stays_text() {
+        let template = Template::parse("Dear $Title{name}").unwrap();
+        let cs = ContentState::new();
+        let mut vc = VolatileContent::new();
+        vc.map_key("name", " Smith");
+
+        let partial = template.partially_fill(&vc, &cs).unwrap();
+        let source = partial.to_source().unwrap();
+        assert_eq!(source, "locale: en-US\nDear $Title Smith");
+        assert_eq!(Template::parse(&source).unwrap(), partial);
+
+        // Text which would become part of the identifier can not be printed.
+        vc.map_key("name", "Smith");
+        let partial = template.partially_fill(&vc, &cs).unwrap();
+        let expected = PrintError::AmbiguousConstant("Title".into(), "Smith".into());
+        assert!(matches!(partial.to_source(), Err(TemplateError::PrintError(e)) if e == expected));
+    }
+
+    #[cfg(feature = "dyn")]
+    #[test]
+    fn dynamic_elements_are_kept_when_filling_out_partially() {
+        let template = Template::parse("{name} $Year").unwrap();
+        let mut cs = ContentState::new();
+        cs.map_constant("Year", "1984");
+        let mut vc = VolatileContent::new();
+        vc.map_key("name", "Paul");
+
+        let partial = template.partially_fill(&vc, &cs).unwrap();
+        assert_eq!(partial, Template::parse("Paul $Year").unwrap());
+
+        let settings = CompilationSettings {
+            ignore_dynamics: true,
+            ..Default::default()
+        };
+        let partial = template.with_settings(settings).partially_fill(&vc, &cs).unwrap();
+        assert_eq!(partial, Template::parse("Paul 1984").unwrap());
+    }
+
+    #[test]
+    fn settings_decide_which_elements_are_dynamic() {
+        use chrono::{TimeZone, Utc};
+
+        let source = "${greeting} {name}, call $@OnCallEngineer in $@Year.";
+        let template = Template::parse(source).unwrap();
+        let mut cs = ContentState::new();
+        cs.map_constant("@OnCallEngineer", "nobody");
+        cs.map_constant("@Year", "1984");
+        cs.map_option("greeting", choice!("casual", "Hi"));
+        let mut settings = CompilationSettings {
+            clock: Clock::Fixed(Utc.with_ymd_and_hms(2023, 3, 14, 9, 26, 53).unwrap()),
+            time_zone: Some(chrono_tz::UTC),
+            dynamic_names: DynamicNames::Namespaced,
+            ..Default::default()
+        };
+        settings.register(OnCall("Jessica"));
+        let template = template.with_settings(settings);
+        let mut vc = VolatileContent::new();
+        vc.map_key("name", "Paul");
+
+        // Elements of registered providers are kept, while `$@Year`
+        // is only dynamic with the "dyn" feature.
+        let partial = template.partially_fill(&vc, &cs).unwrap();
+        #[cfg(feature = "dyn")]
+        let (source, year) = ("${greeting} Paul, call $@OnCallEngineer in $@Year.", "2023");
+        #[cfg(not(feature = "dyn"))]
+        let (source, year) = ("${greeting} Paul, call $@OnCallEngineer in 1984.", "1984");
+        assert_eq!(partial.to_source().unwrap(), format!("locale: en-US\n{source}"));
+
+        // Dynamic elements match any text, even if the content state has their constant.
+        let text_match = template.match_text("Hi Paul, call Alia in 1984.", &cs).unwrap();
+        assert_eq!(text_match.content.keys.get("name").unwrap(), "Paul");
+
+        let outputs: Vec<_> = template.variants(&cs).map(|v| v.output.unwrap()).collect();
+        assert_eq!(outputs, vec![format!("Hi {{name}}, call Jessica in {year}.")]);
+
+        // Without the sigil, `$Year` is a regular constant.
+        let template = Template::parse("$Year").unwrap();
+        let settings = CompilationSettings {
+            dynamic_names: DynamicNames::Namespaced,
+            ..Default::default()
+        };
+        let template = template.with_settings(settings);
+        let mut cs = ContentState::new();
+        cs.map_constant("Year", "1984");
+        let partial = template.partially_fill(&VolatileContent::new(), &cs).unwrap();
+        assert_eq!(partial.to_source().unwrap(), "locale: en-US\n1984");
+        assert!(template.match_text("2023", &cs).is_err());
+        let outputs: Vec<_> = template.variants(&cs).map(|v| v.output.unwrap()).collect();
+        assert_eq!(outputs, vec!["1984"]);
+    }
+
     #[test]
     fn idents_do_not_collide_outside_of_types() {
         let ident = "name"; // Same ident used once for each variable-element type
@@ -408,7 +1360,7 @@ mod tests {
 pub(crate) mod helper {
     use super::*;
 
-    // Assert that filling out is correct
+    // Assert that rendering and filling out are correct
     pub fn test_fill_out(
         input: &str,
         expected: &str,
@@ -416,8 +1368,10 @@ pub(crate) mod helper {
         volatile_content: VolatileContent,
         content_state: ContentState,
     ) {
-        let result = Template::parse(input)
-            .unwrap()
+        let template = Template::parse(input).unwrap();
+        let rendered = template.render(&volatile_content, &content_state).unwrap();
+        assert_eq!(&rendered, expected, "Test case (render): {}", case);
+        let result = template
             .fill_out(volatile_content, content_state)
             .unwrap();
         assert_eq!(&result, expected, "Test case: {}", case);
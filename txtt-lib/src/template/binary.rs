@@ -0,0 +1,142 @@
+//! Versioned binary format of precompiled templates.
+//!
+//! A precompiled template starts with the magic bytes `TXTT` followed by the
+//! version of the format as a little endian `u16`. The rest is the template
+//! encoded with `bincode`. Only the tokens and friendly errors of the template
+//! are encoded; its requirements are derived from the tokens again when it is
+//! loaded. Loading a precompiled template skips parsing, but it does not keep
+//! the source of the template.
+use super::{Template, TemplateError};
+use bincode::Options;
+
+const MAGIC: [u8; 4] = *b"TXTT";
+
+/// Version of the binary format. It has to be increased whenever the
+/// serialised representation of `Template` changes and whenever the same
+/// source parses into different tokens, so templates precompiled by an older
+/// version are parsed again instead of keeping their old meaning.
+pub const FORMAT_VERSION: u16 = 7;
+
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+// Variable length integers make the format considerably smaller.
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+impl Template {
+    /// Encode the template in the binary format.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TemplateError> {
+        let mut bytes = Vec::from(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        options()
+            .serialize_into(&mut bytes, self)
+            .map_err(BinaryError::Encoding)?;
+        Ok(bytes)
+    }
+
+    /// Decode a template from the binary format. Templates written by
+    /// another version of the format are rejected.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TemplateError> {
+        if bytes.len() < HEADER_LEN || bytes[..MAGIC.len()] != MAGIC {
+            return Err(BinaryError::NotATemplate.into());
+        }
+        let version = u16::from_le_bytes([bytes[MAGIC.len()], bytes[MAGIC.len() + 1]]);
+        if version != FORMAT_VERSION {
+            return Err(BinaryError::UnsupportedVersion(version).into());
+        }
+
+        // Lengths in the data can not claim more bytes than there are,
+        // so corrupted data never allocates more memory than its size.
+        let data = &bytes[HEADER_LEN..];
+        let template = options()
+            .with_limit(data.len() as u64)
+            .deserialize(data)
+            .map_err(BinaryError::Encoding)?;
+        Ok(template)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum BinaryError {
+    #[error("The data is not a precompiled template")]
+    NotATemplate,
+    #[error(
+        "The template was precompiled with version {0} of the binary format \
+        but only version {FORMAT_VERSION} is supported"
+    )]
+    UnsupportedVersion(u16),
+    #[error("Failed to encode or decode the precompiled template: {0}")]
+    Encoding(#[from] bincode::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content::{choice, ContentState, VolatileContent};
+
+    #[test]
+    fn precompiled_templates_are_the_same() {
+        let templates = [
+            "Hello {name:Paul}, ${greeting}",
+            "locale: de-DE\n{key:${opt1:${opt2:{key:default-literal}}}}\n$Mfg",
+            "locale: nope\nThe locale is just text: {key:$default}",
+        ];
+        for source in templates {
+            let template = Template::parse(source).unwrap();
+            let bytes = template.to_bytes().unwrap();
+            assert_eq!(Template::from_bytes(&bytes).unwrap(), template, "{source}");
+        }
+    }
+
+    #[test]
+    fn precompiled_templates_are_filled_out() {
+        let template = Template::parse("${greeting} {name:you}!").unwrap();
+        let template = Template::from_bytes(&template.to_bytes().unwrap()).unwrap();
+        let mut cs = ContentState::new();
+        cs.map_option("greeting", choice!("hi", "Hi"));
+        let mut vc = VolatileContent::new();
+        vc.map_choice("greeting", "hi");
+        assert_eq!(template.render(&vc, &cs).unwrap(), "Hi you!");
+    }
+
+    #[test]
+    fn requirements_are_derived_from_the_tokens() {
+        // Renaming the default in the encoded tokens renames it in
+        // the requirements as well, as they are not encoded.
+        let mut bytes = Template::parse("{name:{other}}").unwrap().to_bytes().unwrap();
+        let at = bytes.iter().position(|byte| *byte == b'r').unwrap();
+        bytes[at] = b'x';
+        let template = Template::from_bytes(&bytes).unwrap();
+        assert_eq!(template, Template::parse("{name:{othex}}").unwrap());
+        assert_eq!(template.required(), &template.tokens.draft());
+    }
+
+    #[test]
+    fn invalid_data_is_rejected() {
+        let mut bytes = Template::parse("{name}").unwrap().to_bytes().unwrap();
+        let check =
+            |bytes: &[u8], expected: fn(&BinaryError) -> bool| match Template::from_bytes(bytes) {
+                Err(TemplateError::BinaryError(e)) => {
+                    assert!(expected(&e), "Unexpected error {e:?}")
+                }
+                other => panic!("Expected a binary error, got {other:?}"),
+            };
+
+        check(b"{name}", |e| matches!(e, BinaryError::NotATemplate));
+        check(&bytes[..bytes.len() - 1], |e| {
+            matches!(e, BinaryError::Encoding(_))
+        });
+        // A length which claims more bytes than there are is rejected
+        // before anything is allocated for it.
+        let mut huge = Template::parse("{name}").unwrap().to_bytes().unwrap();
+        let at = huge.iter().position(|byte| *byte == b'n').unwrap() - 1;
+        huge.splice(at..=at, [0xfc, 0xff, 0xff, 0xff, 0x7f]);
+        check(&huge, |e| matches!(e, BinaryError::Encoding(_)));
+        bytes[MAGIC.len()..HEADER_LEN].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        check(
+            &bytes,
+            |e| matches!(e, BinaryError::UnsupportedVersion(v) if *v == FORMAT_VERSION + 1),
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use super::{Template, TemplateError};
+use crate::content::{is_ident, ContentToken, ContentTokens, Symbol};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Build a template in code instead of parsing it from source.
+///
+/// Elements are added in the order in which they appear in the template.
+/// The `default_*` methods add a default to the last element. If this element
+/// already has a default, the default is added to the end of the chain of
+/// defaults instead, so `.key("a").default_key("b").default_text("c")`
+/// builds `{a:{b:c}}`.
+///
+/// Any invalid input is reported by `build`.
+#[derive(Debug)]
+pub struct TemplateBuilder {
+    tokens: ContentTokens,
+    error: Option<BuildError>,
+}
+
+impl TemplateBuilder {
+    pub fn new() -> Self {
+        Self {
+            tokens: ContentTokens::new(),
+            error: None,
+        }
+    }
+
+    /// Set the locale of the template. The default locale is `en-US`.
+    pub fn locale(mut self, locale: &str) -> Self {
+        match locale.parse() {
+            Ok(locale) => self.tokens.locale = locale,
+            Err(_) => self.fail(BuildError::InvalidLocale(locale.to_owned())),
+        }
+        self
+    }
+
+    /// Set the time zone in which dynamic elements are evaluated, e.g. `Europe/Berlin`.
+    pub fn time_zone(mut self, time_zone: &str) -> Self {
+        match time_zone.parse() {
+            Ok(time_zone) => self.tokens.time_zone = Some(time_zone),
+            Err(_) => self.fail(BuildError::InvalidTimeZone(time_zone.to_owned())),
+        }
+        self
+    }
+
+    /// Add a text literal. Adjacent text literals are joined into one.
+    pub fn text(mut self, text: &str) -> Self {
+        if let Err(e) = validate_text(text) {
+            self.fail(e);
+        } else if let Some(ContentToken::Text(last)) = self.tokens.last_mut() {
+            last.push_str(text);
+        } else {
+            self.tokens.push(ContentToken::Text(text.to_owned()));
+        }
+        self
+    }
+
+    pub fn key(self, ident: &str) -> Self {
+        self.element(ident, key)
+    }
+
+    pub fn option(self, ident: &str) -> Self {
+        self.element(ident, option)
+    }
+
+    pub fn constant(self, ident: &str) -> Self {
+        self.element(ident, constant)
+    }
+
+    pub fn default_text(mut self, text: &str) -> Self {
+        match validate_text(text) {
+            Ok(()) => self.default(ContentToken::Text(text.to_owned())),
+            Err(e) => {
+                self.fail(e);
+                self
+            }
+        }
+    }
+
+    pub fn default_key(self, ident: &str) -> Self {
+        self.default_element(ident, key)
+    }
+
+    pub fn default_option(self, ident: &str) -> Self {
+        self.default_element(ident, option)
+    }
+
+    pub fn default_constant(self, ident: &str) -> Self {
+        self.default_element(ident, constant)
+    }
+
+    /// Create the template and compute its requirements, just like `Template::parse` does.
+    pub fn build(self) -> Result<Template, TemplateError> {
+        if let Some(e) = self.error {
+            return Err(e.into());
+        }
+        if self.tokens.elements().next().is_none() {
+            return Err(BuildError::Empty.into());
+        }
+        Ok(Template::from_tokens(self.tokens))
+    }
+
+    // Remember the first error to report it when building.
+    fn fail(&mut self, e: BuildError) {
+        self.error.get_or_insert(e);
+    }
+
+    fn element(mut self, ident: &str, token: fn(&str) -> ContentToken) -> Self {
+        if is_ident(ident) {
+            self.tokens.push(token(ident));
+        } else {
+            self.fail(BuildError::InvalidIdent(ident.to_owned()));
+        }
+        self
+    }
+
+    fn default_element(mut self, ident: &str, token: fn(&str) -> ContentToken) -> Self {
+        if is_ident(ident) {
+            self.default(token(ident))
+        } else {
+            self.fail(BuildError::InvalidIdent(ident.to_owned()));
+            self
+        }
+    }
+
+    // Add `default` to the end of the chain of defaults of the last element.
+    fn default(mut self, default: ContentToken) -> Self {
+        // Find the first key or option in the chain which does not have a default yet.
+        fn free_default(token: &mut ContentToken) -> Option<&mut Option<Box<ContentToken>>> {
+            match token {
+                ContentToken::Key(_, default) => {
+                    if default.is_some() {
+                        free_default(default.as_mut().unwrap())
+                    } else {
+                        Some(default)
+                    }
+                }
+                ContentToken::Option(key) => free_default(key),
+                ContentToken::Text(_) | ContentToken::Constant(_) => None,
+            }
+        }
+
+        match self.tokens.last_mut().and_then(free_default) {
+            Some(free) => *free = Some(Box::new(default)),
+            None => self.fail(BuildError::NoElementForDefault),
+        }
+        self
+    }
+}
+
+impl Default for TemplateBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn key(ident: &str) -> ContentToken {
+    ContentToken::Key(ident.to_owned(), None)
+}
+
+fn option(ident: &str) -> ContentToken {
+    ContentToken::Option(Box::new(key(ident)))
+}
+
+fn constant(ident: &str) -> ContentToken {
+    ContentToken::Constant(ident.to_owned())
+}
+
+// Only accept text which could also be a text literal in source.
+fn validate_text(text: &str) -> Result<(), BuildError> {
+    if text.is_empty() {
+        Err(BuildError::EmptyText)
+    } else if text.contains(|c: char| c.is_terminal()) {
+        Err(BuildError::ForbiddenInText(text.to_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum BuildError {
+    #[error("The identifier {0:?} may only contain the characters 'A'-'Z', 'a'-'z' and '0'-'9'")]
+    InvalidIdent(String),
+    #[error("The text {0:?} contains one of the forbidden characters '{{', '}}' or '$'")]
+    ForbiddenInText(String),
+    #[error("A text literal is empty")]
+    EmptyText,
+    #[error("The locale {0:?} is not a valid locale")]
+    InvalidLocale(String),
+    #[error("The time zone {0:?} is not a known time zone")]
+    InvalidTimeZone(String),
+    #[error("A default can only be added to a key or an option whose defaults end in a key or an option")]
+    NoElementForDefault,
+    #[error("A template needs to contain at least one element")]
+    Empty,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_templates_match_parsed_templates() {
+        let cases = vec![
+            (
+                TemplateBuilder::new()
+                    .text("Hello ")
+                    .key("name")
+                    .default_text("Paul")
+                    .text(", ")
+                    .option("greeting")
+                    .build(),
+                "Hello {name:Paul}, ${greeting}",
+            ),
+            (
+                TemplateBuilder::new()
+                    .locale("de_DE")
+                    .time_zone("Europe/Berlin")
+                    .key("key")
+                    .default_option("opt1")
+                    .default_option("opt2")
+                    .default_key("key")
+                    .default_text("default-literal")
+                    .text("\n")
+                    .constant("Mfg")
+                    .text(" bye")
+                    .text("!")
+                    .build(),
+                "locale: de-DE\ntimezone: Europe/Berlin\n{key:${opt1:${opt2:{key:default-literal}}}}\n$Mfg bye!",
+            ),
+            (
+                TemplateBuilder::new()
+                    .option("email")
+                    .default_constant("workemail")
+                    .build(),
+                "${email:$workemail}",
+            ),
+        ];
+        for (built, source) in cases {
+            assert_eq!(built.unwrap(), Template::parse(source).unwrap(), "{source}");
+        }
+    }
+
+    #[test]
+    fn invalid_input_is_reported() {
+        let cases = vec![
+            (
+                TemplateBuilder::new().key("my-name").build(),
+                BuildError::InvalidIdent("my-name".into()),
+            ),
+            (
+                TemplateBuilder::new().constant("").build(),
+                BuildError::InvalidIdent("".into()),
+            ),
+            (
+                TemplateBuilder::new().text("a $ b").build(),
+                BuildError::ForbiddenInText("a $ b".into()),
+            ),
+            (
+                TemplateBuilder::new().key("a").default_text("").build(),
+                BuildError::EmptyText,
+            ),
+            (
+                TemplateBuilder::new().constant("a").default_text("b").build(),
+                BuildError::NoElementForDefault,
+            ),
+            (
+                TemplateBuilder::new()
+                    .key("a")
+                    .default_text("b")
+                    .default_text("c")
+                    .build(),
+                BuildError::NoElementForDefault,
+            ),
+            (
+                TemplateBuilder::new().locale("not a locale").text("x").build(),
+                BuildError::InvalidLocale("not a locale".into()),
+            ),
+            (
+                TemplateBuilder::new().time_zone("Mars/Olympus").text("x").build(),
+                BuildError::InvalidTimeZone("Mars/Olympus".into()),
+            ),
+            (TemplateBuilder::new().build(), BuildError::Empty),
+        ];
+        for (built, expected) in cases {
+            match built {
+                Err(TemplateError::BuildError(e)) => assert_eq!(e, expected),
+                other => panic!("Expected {expected:?}, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn built_templates_are_filled_out() {
+        use crate::content::{choice, ContentState, VolatileContent};
+
+        let mut cs = ContentState::new();
+        cs.map_option("greeting", choice!("hi", "Hi"));
+        let mut vc = VolatileContent::new();
+        vc.map_choice("greeting", "hi");
+        let result = TemplateBuilder::new()
+            .option("greeting")
+            .text(" ")
+            .key("name")
+            .default_text("Paul")
+            .build()
+            .unwrap()
+            .fill_out(vc, cs)
+            .unwrap();
+        assert_eq!(result, "Hi Paul");
+    }
+}
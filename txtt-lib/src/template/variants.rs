@@ -0,0 +1,260 @@
+use super::{CompilationSettings, Template, TemplateError};
+use crate::content::{ContentRequirement, ContentState, ContentType, VolatileContent};
+
+/// Iterator over all variants of a template, one for every combination of
+/// choices for the options of the template. See `Template::variants`.
+#[derive(Debug)]
+pub struct Variants<'a> {
+    template: &'a Template,
+    content_state: &'a ContentState,
+    // Settings the variants are filled out with, if they are not the default ones
+    settings: Option<&'a CompilationSettings>,
+    // Every option of the template with all of its alternatives, where
+    // `None` stands for the default of the option.
+    options: Vec<(&'a str, Vec<Option<&'a str>>)>,
+    // Keys without a default, which are filled out with placeholders
+    placeholders: Vec<&'a str>,
+    next: usize,
+    total: usize,
+    // Whether there are more variants than `usize::MAX`
+    saturated: bool,
+}
+
+/// A single variant of a template.
+#[derive(Debug)]
+pub struct Variant<'a> {
+    /// Choice for every option of the template in alphabetical order
+    /// of the options. `None` means that the default of the option is used.
+    pub choices: Vec<(&'a str, Option<&'a str>)>,
+    /// The filled out template, or the reason it could not be filled out.
+    pub output: Result<String, TemplateError>,
+}
+
+impl<'a> Variants<'a> {
+    pub(super) fn new(
+        template: &'a Template,
+        content_state: &'a ContentState,
+        settings: Option<&'a CompilationSettings>,
+    ) -> Self {
+        let required = template.required();
+        let mut options: Vec<_> = required
+            .entries(ContentType::Option)
+            .map(|(ident, requirement)| {
+                let mut alternatives: Vec<Option<&str>> = content_state
+                    .options
+                    .get(ident)
+                    .into_iter()
+                    .flat_map(|choices| choices.keys().map(|choice| Some(choice.as_str())))
+                    .collect();
+                alternatives.sort_unstable();
+                // Without any choices, the default is the only alternative,
+                // even if there is none and filling out fails.
+                if *requirement != ContentRequirement::None || alternatives.is_empty() {
+                    alternatives.push(None);
+                }
+                (ident, alternatives)
+            })
+            .collect();
+        options.sort_unstable_by_key(|(ident, _)| *ident);
+        let placeholders = required
+            .entries(ContentType::Key)
+            .filter(|(_, requirement)| **requirement == ContentRequirement::None)
+            .map(|(ident, _)| ident)
+            .collect();
+
+        let total = options.iter().try_fold(1usize, |total, (_, alternatives)| {
+            total.checked_mul(alternatives.len())
+        });
+
+        Self {
+            template,
+            content_state,
+            settings,
+            options,
+            placeholders,
+            next: 0,
+            total: total.unwrap_or(usize::MAX),
+            saturated: total.is_none(),
+        }
+    }
+
+    /// Number of variants of the template. This is `usize::MAX` if there
+    /// are more variants than that.
+    pub const fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Number of variants of the template or `None` if there are more
+    /// variants than `usize::MAX`.
+    pub const fn exact_total(&self) -> Option<usize> {
+        match self.saturated {
+            true => None,
+            false => Some(self.total),
+        }
+    }
+
+    /// Get the variant at `index` without iterating over all variants in front of it,
+    /// e.g. to look at a random sample of the variants. Variants are ordered as if
+    /// the choices were digits, the choice for the last option being the lowest digit.
+    pub fn variant(&self, index: usize) -> Option<Variant<'a>> {
+        if index >= self.total {
+            return None;
+        }
+
+        let mut rest = index;
+        let mut choices = Vec::with_capacity(self.options.len());
+        for (ident, alternatives) in self.options.iter().rev() {
+            choices.push((*ident, alternatives[rest % alternatives.len()]));
+            rest /= alternatives.len();
+        }
+        choices.reverse();
+
+        let mut volatile_content = VolatileContent::new();
+        for (ident, choice) in &choices {
+            if let Some(choice) = choice {
+                volatile_content.map_choice(ident, choice);
+            }
+        }
+        for ident in &self.placeholders {
+            volatile_content.map_key(ident, &format!("{{{ident}}}"));
+        }
+
+        let output = match self.settings {
+            Some(settings) => {
+                self.template
+                    .render_string(settings, &volatile_content, self.content_state)
+            }
+            None => self.template.render(&volatile_content, self.content_state),
+        };
+        Some(Variant { choices, output })
+    }
+}
+
+impl<'a> Iterator for Variants<'a> {
+    type Item = Variant<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let variant = self.variant(self.next)?;
+        self.next += 1;
+        Some(variant)
+    }
+
+    // The number of variants left is unknown once the total is saturated.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.total - self.next;
+        match self.saturated {
+            true => (left, None),
+            false => (left, Some(left)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::content::choice;
+
+    fn content_state() -> ContentState {
+        let mut cs = ContentState::new();
+        cs.map_option("greeting", choice!("formal", "Dear"));
+        cs.map_option("greeting", choice!("casual", "Hi"));
+        cs.map_option("bye", choice!("formal", "Regards"));
+        cs.map_option("bye", choice!("casual", "Cheers"));
+        cs.map_option("unused", choice!("a", "A"));
+        cs
+    }
+
+    #[test]
+    fn every_combination_is_rendered() {
+        let template = Template::parse("${greeting} {name},\n${bye:Bye}").unwrap();
+        let cs = content_state();
+        let variants: Vec<_> = template
+            .variants(&cs)
+            .map(|variant| (variant.choices, variant.output.unwrap()))
+            .collect();
+        let expected = vec![
+            (
+                vec![("bye", Some("casual")), ("greeting", Some("casual"))],
+                "Hi {name},\nCheers",
+            ),
+            (
+                vec![("bye", Some("casual")), ("greeting", Some("formal"))],
+                "Dear {name},\nCheers",
+            ),
+            (
+                vec![("bye", Some("formal")), ("greeting", Some("casual"))],
+                "Hi {name},\nRegards",
+            ),
+            (
+                vec![("bye", Some("formal")), ("greeting", Some("formal"))],
+                "Dear {name},\nRegards",
+            ),
+            (
+                vec![("bye", None), ("greeting", Some("casual"))],
+                "Hi {name},\nBye",
+            ),
+            (
+                vec![("bye", None), ("greeting", Some("formal"))],
+                "Dear {name},\nBye",
+            ),
+        ];
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(choices, output)| (choices, output.to_owned()))
+            .collect();
+        assert_eq!(variants, expected);
+    }
+
+    #[test]
+    fn variants_are_accessed_by_index() {
+        let template = Template::parse("${greeting} ${bye:Bye}").unwrap();
+        let cs = content_state();
+        let variants = template.variants(&cs);
+        assert_eq!(variants.total(), 6);
+        assert_eq!(variants.exact_total(), Some(6));
+        assert_eq!(variants.size_hint(), (6, Some(6)));
+        let outputs: Vec<_> = template.variants(&cs).map(|v| v.output.unwrap()).collect();
+        for (index, output) in outputs.iter().enumerate() {
+            assert_eq!(&variants.variant(index).unwrap().output.unwrap(), output);
+        }
+        assert!(variants.variant(6).is_none());
+    }
+
+    #[test]
+    fn templates_without_options_have_one_variant() {
+        let template = Template::parse("Hello {name:you}").unwrap();
+        let cs = content_state();
+        let variants: Vec<_> = template.variants(&cs).collect();
+        assert_eq!(variants.len(), 1);
+        assert!(variants[0].choices.is_empty());
+        assert_eq!(variants[0].output.as_ref().unwrap(), "Hello you");
+    }
+
+    #[test]
+    fn options_without_choices_fail_to_render() {
+        let template = Template::parse("${missing}").unwrap();
+        let cs = content_state();
+        let variants: Vec<_> = template.variants(&cs).collect();
+        assert_eq!(variants.len(), 1);
+        assert_eq!(variants[0].choices, vec![("missing", None)]);
+        assert!(variants[0].output.is_err());
+    }
+
+    #[test]
+    fn too_many_variants_saturate() {
+        let mut cs = ContentState::new();
+        let mut source = String::new();
+        for option in 0..20 {
+            source.push_str(&format!("${{o{option}}}"));
+            for choice in 0..10 {
+                cs.map_option(&format!("o{option}"), choice!(format!("c{choice}"), "x"));
+            }
+        }
+        let template = Template::parse(&source).unwrap();
+        let variants = template.variants(&cs);
+        assert_eq!(variants.total(), usize::MAX);
+        assert_eq!(variants.exact_total(), None);
+        assert_eq!(variants.size_hint(), (usize::MAX, None));
+        assert!(variants.variant(usize::MAX - 1).unwrap().output.is_ok());
+    }
+}
@@ -0,0 +1,55 @@
+//! Logger for tests which keeps the warnings it logs, so tests can check
+//! them. All messages are passed on to `env_logger` as well.
+use log::{Level, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+
+struct TestLogger {
+    inner: env_logger::Logger,
+    warnings: Mutex<Vec<String>>,
+}
+
+impl Log for TestLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() <= Level::Warn {
+            self.warnings.lock().unwrap().push(record.args().to_string());
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+static LOGGER: Lazy<&'static TestLogger> = Lazy::new(|| {
+    let inner = env_logger::Builder::from_default_env().build();
+    let max_level = inner.filter().max(log::LevelFilter::Warn);
+    let logger = Box::leak(Box::new(TestLogger {
+        inner,
+        warnings: Mutex::new(vec![]),
+    }));
+    log::set_logger(logger).unwrap();
+    log::set_max_level(max_level);
+    logger
+});
+
+/// Install the logger unless it is installed already.
+pub fn init() {
+    Lazy::force(&LOGGER);
+}
+
+/// Warnings logged by any test so far which contain `needle`. Tests run in
+/// parallel, so every test should look for warnings only it can cause.
+pub fn warnings_containing(needle: &str) -> Vec<String> {
+    let warnings = LOGGER.warnings.lock().unwrap();
+    warnings
+        .iter()
+        .filter(|warning| warning.contains(needle))
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,143 @@
+//! On-disk cache of precompiled templates.
+//!
+//! Templates are stored in the binary format of txtt-lib in the cache
+//! directory of the user and are named after a hash of their source, the
+//! namespaces and names of dynamic elements it is parsed with, the version
+//! of the binary format and the version of txttc. A new version of either
+//! never uses templates parsed by an older one, as the same source may parse
+//! differently. Every entry starts with everything its name is a hash of,
+//! so a template whose hash collides with that of another is not used
+//! for the other one.
+//! A template is only parsed if it is not in the cache yet or if the
+//! cached template can not be read (e.g. because it was written using
+//! another version of the binary format). The cache never causes a
+//! failure; any problem with it only results in parsing the template.
+use anyhow::Context;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use txtt_lib::template::{CompilationSettings, Template, FORMAT_VERSION};
+
+// Name of the directory in the user's cache directory
+const CACHE_DIR_NAME: &str = "txttc";
+
+pub struct TemplateCache {
+    dir: PathBuf,
+}
+
+impl TemplateCache {
+    // Use the default cache directory if there is one on this platform.
+    pub fn new() -> Option<Self> {
+        let dir = dirs::cache_dir()?.join(CACHE_DIR_NAME);
+        Some(Self { dir })
+    }
+
+    // Get the precompiled template for `source` or parse it using `settings` and cache it.
+    pub fn get_or_parse(
+        &self,
+        source: &str,
+        settings: &CompilationSettings,
+    ) -> anyhow::Result<Template> {
+        let key = Self::key(source, settings);
+        let path = self.path(&key);
+        match fs::read(&path) {
+            Ok(entry) => match Self::load(&key, &entry) {
+                Ok(template) => {
+                    log::trace!("Using cached template {}", path.display());
+                    return Ok(template);
+                }
+                Err(e) => log::debug!("Ignoring cached template {}: {e}", path.display()),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => log::debug!("Failed to read cached template {}: {e}", path.display()),
+        }
+
+        let template = Template::parse_with(source, settings).context("Parse error")?;
+        if let Err(e) = Self::store(&path, &key, &template) {
+            log::warn!("Failed to cache template: {e:#}");
+        }
+        Ok(template)
+    }
+
+    // Everything a template is parsed from, which identifies its entry
+    fn key(source: &str, settings: &CompilationSettings) -> Vec<u8> {
+        let version = format!(
+            "{FORMAT_VERSION}-{}-{}-{:?}\0",
+            env!("CARGO_PKG_VERSION"),
+            settings.namespaces().join("."),
+            settings.dynamic_names
+        );
+        [version.as_bytes(), source.as_bytes()].concat()
+    }
+
+    fn path(&self, key: &[u8]) -> PathBuf {
+        self.dir.join(format!("{:016x}.bin", fnv1a(key)))
+    }
+
+    // Get the template of an entry, which is the length of its key as a little
+    // endian `u64`, the key and the template in the binary format of txtt-lib.
+    // Entries stored for another key are rejected.
+    fn load(key: &[u8], entry: &[u8]) -> anyhow::Result<Template> {
+        let (len, rest) = entry
+            .split_first_chunk::<8>()
+            .context("The entry is truncated")?;
+        let len = usize::try_from(u64::from_le_bytes(*len))?;
+        match rest.split_at_checked(len) {
+            Some((stored, bytes)) if stored == key => Ok(Template::from_bytes(bytes)?),
+            _ => anyhow::bail!("The entry belongs to another template"),
+        }
+    }
+
+    // Write the template to a temporary file first and then move it
+    // into place, so no other process ever reads half of a template.
+    fn store(path: &Path, key: &[u8], template: &Template) -> anyhow::Result<()> {
+        let len = u64::try_from(key.len())?.to_le_bytes();
+        let bytes = [&len[..], key, &template.to_bytes()?].concat();
+        let dir = path.parent().context("Cache path without a directory")?;
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create cache directory {}", dir.display()))?;
+        let temp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&temp_path, bytes)
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+        fs::rename(&temp_path, path)
+            .with_context(|| format!("Failed to move template to {}", path.display()))?;
+        log::trace!("Cached template as {}", path.display());
+        Ok(())
+    }
+}
+
+// 64-bit FNV-1a hash, which unlike the hashers of the standard
+// library is guaranteed to stay the same across Rust versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_only_used_for_their_own_template() {
+        let dir = std::env::temp_dir().join(format!("txttc-{}-cache", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cache = TemplateCache { dir };
+        let settings = CompilationSettings::default();
+        let key = TemplateCache::key("Hello {name}", &settings);
+        let other = TemplateCache::key("Bye {name}", &settings);
+        let path = cache.path(&key);
+        TemplateCache::store(&path, &key, &Template::parse("Hello {name}").unwrap()).unwrap();
+
+        let entry = fs::read(&path).unwrap();
+        let template = TemplateCache::load(&key, &entry).unwrap();
+        assert_eq!(template, Template::parse("Hello {name}").unwrap());
+        // An entry found under the name of another template is not used for it.
+        assert!(TemplateCache::load(&other, &entry).is_err());
+        assert!(TemplateCache::load(&key, &entry[..4]).is_err());
+    }
+}
@@ -0,0 +1,348 @@
+//! Running numbers, e.g. for invoices, written as `$Counter.<name>`.
+//!
+//! The last value of every counter is stored in a YAML file next to the
+//! content state file. A counter can start over every year or month:
+//!
+//! ```yaml
+//! invoice:
+//!   value: 41
+//!   reset: yearly
+//!   period: "2023"
+//! ```
+//!
+//! A lock file next to the counter file is locked from the first time a
+//! counter is used until the used values are committed or the counters are
+//! dropped, so two instances can never use the same number. The lock file
+//! is left behind, as removing it while another instance waits for it would
+//! let a third instance lock a new file at the same time. Values are only
+//! stored when they are committed, i.e. after the template was filled out
+//! successfully. They are written to a temporary file first, which then
+//! replaces the counter file, so a failed write never loses any counters.
+use anyhow::Context;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use txtt_lib::{DynContext, DynamicProvider};
+
+// Namespace of the counters, e.g. `Counter` in `$Counter.invoice`
+const COUNTER_NAMESPACE: &str = "Counter";
+
+// When a counter starts over at 1
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Reset {
+    #[default]
+    Never,
+    Yearly,
+    Monthly,
+}
+
+impl Reset {
+    // Get the period the current time of `ctx` is in, e.g. `2023-11` for monthly counters
+    fn period(self, ctx: &DynContext) -> Option<String> {
+        let format = match self {
+            Reset::Never => return None,
+            Reset::Yearly => "%Y",
+            Reset::Monthly => "%Y-%m",
+        };
+        let period = match ctx.time_zone {
+            Some(time_zone) => ctx.now.with_timezone(&time_zone).format(format),
+            None => ctx.now.with_timezone(&Local).format(format),
+        };
+        Some(period.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Counter {
+    // The last value which was used
+    #[serde(default)]
+    value: u64,
+    #[serde(default)]
+    reset: Reset,
+    // The period of `value` if the counter is reset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    period: Option<String>,
+}
+
+impl Counter {
+    // Get the counter after using its next value at the time of `ctx`. The counter
+    // starts over if the time is in a later period than the last value, but never
+    // goes back to an earlier period, e.g. when filling out a template at an earlier time.
+    fn next(&self, ctx: &DynContext) -> Self {
+        let period = self.reset.period(ctx);
+        // A counter without a period yet continues, e.g. after adding a reset rule.
+        let order = match (&period, &self.period) {
+            (Some(current), Some(last)) => compare_periods(current, last),
+            _ => Ordering::Equal,
+        };
+        let (value, period) = match order {
+            Ordering::Greater => (1, period),
+            Ordering::Equal => (self.value + 1, period),
+            Ordering::Less => (self.value + 1, self.period.clone()),
+        };
+        Self {
+            value,
+            reset: self.reset,
+            period,
+        }
+    }
+}
+
+// Compare two periods as far as both go, so a month and a year compare by their
+// year after the reset rule of a counter changed, e.g. `2023-11` and `2023` are equal.
+fn compare_periods(current: &str, last: &str) -> Ordering {
+    let len = current.len().min(last.len());
+    current[..len].cmp(&last[..len])
+}
+
+type CounterMap = BTreeMap<String, Counter>;
+
+// Counters read while the lock file is locked
+#[derive(Debug)]
+struct Locked {
+    // The lock is released when the file is closed.
+    _lock: File,
+    counters: CounterMap,
+    // Counters whose next value was used
+    used: CounterMap,
+}
+
+/// Counters stored in a file. Clones share the same counters, so one can be
+/// registered as a provider and another one can commit the used values.
+#[derive(Debug, Clone)]
+pub struct Counters {
+    path: PathBuf,
+    locked: Arc<Mutex<Option<Locked>>>,
+}
+
+impl Counters {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            locked: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Get the next value of the counter `name`. A counter which is used more
+    // than once keeps its value.
+    fn next(&self, name: &str, ctx: &DynContext) -> anyhow::Result<u64> {
+        let mut locked = self.locked.lock().unwrap();
+        if locked.is_none() {
+            *locked = Some(self.lock()?);
+        }
+        let Locked { counters, used, .. } = locked.as_mut().unwrap();
+        let counter = used
+            .entry(name.to_owned())
+            .or_insert_with(|| counters.get(name).cloned().unwrap_or_default().next(ctx));
+        Ok(counter.value)
+    }
+
+    // Lock the lock file and read the counters. The counter file itself is
+    // replaced when the counters are committed, so it can not hold the lock.
+    fn lock(&self) -> anyhow::Result<Locked> {
+        let lock_path = self.path.with_extension("lock");
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .with_context(|| format!("Failed to open lock file {}", lock_path.display()))?;
+        log::debug!("Waiting for the lock of {}", lock_path.display());
+        lock.lock().context("Failed to lock the counter file")?;
+
+        let counters = match fs::read_to_string(&self.path) {
+            Ok(buf) if buf.trim().is_empty() => CounterMap::new(),
+            Ok(buf) => serde_yaml::from_str(&buf).context("Failed to parse the counter file")?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => CounterMap::new(),
+            Err(e) => return Err(e).context("Failed to read the counter file"),
+        };
+        Ok(Locked {
+            _lock: lock,
+            counters,
+            used: CounterMap::new(),
+        })
+    }
+
+    /// Store the values which were used and release the lock of the file.
+    /// Nothing is written if no counter was used.
+    pub fn commit(&self) -> anyhow::Result<()> {
+        let Some(mut locked) = self.locked.lock().unwrap().take() else {
+            return Ok(());
+        };
+        locked.counters.append(&mut locked.used);
+        let yaml = serde_yaml::to_string(&locked.counters)?;
+        write_replacing(&self.path, yaml.as_bytes()).context("Failed to write the counter file")?;
+        log::trace!("Stored counters in {}", self.path.display());
+        Ok(())
+    }
+}
+
+// Write `bytes` to a temporary file first and then move it into
+// place, so `path` keeps its old content if writing fails.
+fn write_replacing(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let temp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+    let result = File::create(&temp_path)
+        .and_then(|mut file| file.write_all(bytes).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+// Get the name of the counter `ident`, e.g. `invoice` for `Counter.invoice`
+fn counter_name(ident: &str) -> Option<&str> {
+    ident.strip_prefix(COUNTER_NAMESPACE)?.strip_prefix('.')
+}
+
+impl DynamicProvider for Counters {
+    fn resolve(&self, ident: &str, ctx: &DynContext) -> Option<String> {
+        let name = counter_name(ident)?;
+        match self.next(name, ctx) {
+            Ok(value) => Some(value.to_string()),
+            Err(e) => {
+                log::error!("Failed to get the next value of the counter {name}: {e:#}");
+                None
+            }
+        }
+    }
+
+    // Counters are not resolved here, as that would lock the counter file.
+    fn provides(&self, ident: &str, _: &DynContext) -> bool {
+        counter_name(ident).is_some()
+    }
+
+    fn namespaces(&self) -> &[&str] {
+        &[COUNTER_NAMESPACE]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use txtt_lib::template::{CompilationSettings, Template};
+    use txtt_lib::{Clock, ContentState, VolatileContent};
+
+    // Path of the counter file in a new, empty directory for the test `name`
+    fn counter_file(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("txttc-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(".template_counters.yaml")
+    }
+
+    // Fill out `source` at the time `now` (e.g. `2023-11-20T12:00:00Z`) using `counters`
+    fn fill_out(counters: &Counters, source: &str, now: &str) -> String {
+        let mut settings = CompilationSettings {
+            clock: Clock::Fixed(now.parse().unwrap()),
+            time_zone: Some(chrono_tz::UTC),
+            ..Default::default()
+        };
+        settings.register(counters.clone());
+        let template = Template::parse_with(source, &settings).unwrap();
+        template
+            .with_settings(settings)
+            .render(&VolatileContent::new(), &ContentState::new())
+            .unwrap()
+    }
+
+    fn read(path: &Path) -> CounterMap {
+        serde_yaml::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    fn counter(value: u64, reset: Reset, period: Option<&str>) -> Counter {
+        Counter {
+            value,
+            reset,
+            period: period.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn counters_are_increased() {
+        let path = counter_file("increased");
+        for expected in ["No. 1", "No. 2", "No. 3"] {
+            let counters = Counters::new(path.clone());
+            let output = fill_out(&counters, "No. $Counter.invoice", "2023-11-20T12:00:00Z");
+            assert_eq!(output, expected);
+            counters.commit().unwrap();
+        }
+        assert_eq!(read(&path)["invoice"], counter(3, Reset::Never, None));
+    }
+
+    #[test]
+    fn counters_start_over_in_a_new_period() {
+        let path = counter_file("periods");
+        fs::write(
+            &path,
+            "invoice: {value: 41, reset: yearly, period: '2023'}\n\
+             report: {value: 5, reset: monthly, period: '2023-11'}\n",
+        )
+        .unwrap();
+        let source = "$Counter.invoice/$Counter.report";
+        let cases = [
+            ("2023-11-20T12:00:00Z", "42/6"),
+            ("2023-12-01T12:00:00Z", "43/1"),
+            ("2024-01-01T12:00:00Z", "1/1"),
+            // An earlier time neither resets a counter nor moves its period back.
+            ("2023-12-24T12:00:00Z", "2/2"),
+        ];
+        for (now, expected) in cases {
+            let counters = Counters::new(path.clone());
+            assert_eq!(fill_out(&counters, source, now), expected, "At {now}");
+            counters.commit().unwrap();
+        }
+        let counters = read(&path);
+        assert_eq!(counters["invoice"], counter(2, Reset::Yearly, Some("2024")));
+        assert_eq!(counters["report"], counter(2, Reset::Monthly, Some("2024-01")));
+    }
+
+    #[test]
+    fn changing_the_reset_rule_keeps_the_value() {
+        let path = counter_file("rules");
+        // The rule of the stored counter is changed by hand every time.
+        let cases = [
+            ("{value: 7, reset: monthly, period: '2023'}", Reset::Monthly, Some("2023-11")),
+            ("{value: 8, reset: yearly, period: '2023-11'}", Reset::Yearly, Some("2023")),
+            ("{value: 9, reset: never, period: '2023'}", Reset::Never, None),
+        ];
+        for (value, (yaml, reset, period)) in (8..).zip(cases) {
+            fs::write(&path, format!("invoice: {yaml}\n")).unwrap();
+            let counters = Counters::new(path.clone());
+            let output = fill_out(&counters, "$Counter.invoice", "2023-11-20T12:00:00Z");
+            assert_eq!(output, value.to_string(), "Counter {yaml}");
+            counters.commit().unwrap();
+            assert_eq!(read(&path)["invoice"], counter(value, reset, period));
+        }
+    }
+
+    #[test]
+    fn uncommitted_counters_are_not_stored() {
+        let path = counter_file("dry-run");
+        for _ in 0..2 {
+            // Like with `--dry-run`, the counters are dropped without committing them.
+            let counters = Counters::new(path.clone());
+            assert_eq!(fill_out(&counters, "$Counter.invoice", "2023-11-20T12:00:00Z"), "1");
+        }
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn counters_used_twice_keep_their_value() {
+        let path = counter_file("twice");
+        let counters = Counters::new(path.clone());
+        let source = "$Counter.invoice, {again:$Counter.invoice}";
+        assert_eq!(fill_out(&counters, source, "2023-11-20T12:00:00Z"), "1, 1");
+        assert_eq!(fill_out(&counters, "$Counter.invoice", "2023-11-20T12:00:00Z"), "1");
+        counters.commit().unwrap();
+        assert_eq!(read(&path)["invoice"], counter(1, Reset::Never, None));
+    }
+}
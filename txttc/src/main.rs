@@ -1,15 +1,24 @@
+mod cache;
+mod counter;
+
 use anyhow::Context;
-use clap::Parser;
+use cache::TemplateCache;
+use counter::Counters;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use clap::{Parser, Subcommand};
 use giveup::Giveup;
+use rand::{rngs::StdRng, SeedableRng};
 use std::{
     env,
-    fs::File,
+    fs::{self, File},
     io::{self, Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::Command,
 };
+use txtt_lib::syntax::SyntaxTree;
 use txtt_lib::template::{CompilationSettings, Template};
-use txtt_lib::{VolatileContent, ContentState};
+use txtt_lib::{Clock, DynamicNames, EnvAccess, VolatileContent, ContentState};
 
 // The default path to the file which contains the configuration
 // for the ContentState
@@ -18,6 +27,8 @@ const CONTENT_STATE_DEFAULT: &str = ".template_content_state.yaml";
 // file which contains the configuration for the ContentState
 // If this environment variable is set, its path will overwrite the default one
 const CONTENT_STATE_FILE_ENV: &str = "TEMPLATE_CONTENT_STATE_FILE";
+// Name of the file next to the content state file which contains the counters
+const COUNTERS_FILE: &str = ".template_counters.yaml";
 // Name of the environment variable which contains the user's default editor name
 const EDITOR: &str = "EDITOR";
 // Name of a default editor assumed to be installed on most systems and
@@ -27,11 +38,12 @@ const EDITOR_DEFAULT: &str = "nano";
 const TEMP_FILE_NAME: &str = "content.yaml";
 
 #[derive(Parser, Debug)]
-#[command(about = "Fill out templates")]
+#[command(about = "Fill out templates", subcommand_negates_reqs = true)]
 struct Args {
-    /// Path to the template to be compiled. This argument is always required
-    #[arg(long = "template", short, value_name = "template file")]
-    template_file: PathBuf,
+    /// Path to the template to be compiled. This argument is required
+    /// unless a subcommand is used
+    #[arg(long = "template", short, value_name = "template file", required = true)]
+    template_file: Option<PathBuf>,
     /// Path to a content state file to be used instead of the default one
     #[arg(long = "content-state", short = 'C', value_name = "content state file")]
     content_state_file: Option<PathBuf>,
@@ -43,10 +55,156 @@ struct Args {
     /// and will irgnore the `content` flag
     #[arg(long, short)]
     draft: bool,
+    /// Only substitute the given content and write the rest of the
+    /// template as template source instead of compiling it
+    #[arg(long, short)]
+    partial: bool,
+    /// Always parse the template instead of using a precompiled
+    /// template from the cache and do not add it to the cache
+    #[arg(long, global = true)]
+    no_cache: bool,
+    /// Fill out counters like `$Counter.invoice` without storing their new
+    /// values, so the next run uses the same numbers. Implied by `--now`
+    #[arg(long)]
+    dry_run: bool,
+    #[command(flatten)]
+    dyn_args: DynArgs,
+    #[command(subcommand)]
+    command: Option<Subcommands>,
+}
+
+// Flags controlling how dynamic elements are evaluated
+#[derive(clap::Args, Debug)]
+struct DynArgs {
     /// Ignore dynamic elements and treat all elements with
     /// such special identifiers as regular elements
     #[arg(long, short)]
     ignore_dyn: bool,
+    /// Only evaluate dynamic elements written with their sigil, e.g. `$@Year`,
+    /// and treat `$Year` as a regular constant
+    #[arg(long)]
+    namespaced_dyn: bool,
+    /// Evaluate dynamic elements at this time instead of the current time,
+    /// e.g. `2023-03-14T09:26:53+01:00`. Counters are not stored then
+    #[arg(long, value_name = "RFC 3339 time", value_parser = parse_time)]
+    now: Option<DateTime<Utc>>,
+    /// Evaluate dynamic elements in this time zone, e.g. `Europe/Berlin`, instead
+    /// of the time zone set in the template or the local time zone
+    #[arg(long, value_name = "time zone")]
+    time_zone: Option<Tz>,
+    /// Allow the template to read this environment element, e.g. `Env.USER`
+    /// or `Hostname`. Can be given multiple times
+    #[arg(long, value_name = "element")]
+    allow_env: Vec<String>,
+}
+
+impl DynArgs {
+    // Get the settings for compiling the template at `template_file`
+    fn settings(self, template_file: &Path) -> CompilationSettings {
+        let dynamic_names = match self.namespaced_dyn {
+            true => DynamicNames::Namespaced,
+            false => DynamicNames::Bare,
+        };
+        CompilationSettings {
+            ignore_dynamics: self.ignore_dyn,
+            clock: self.now.map_or(Clock::System, Clock::Fixed),
+            time_zone: self.time_zone,
+            env: EnvAccess {
+                allowed: self.allow_env,
+                template_path: Some(template_file.to_path_buf()),
+            },
+            dynamic_names,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Subcommands {
+    /// Rewrite templates in their canonical formatting. Only the locale and time
+    /// zone headers are normalised; elements and text are kept as they are
+    Fmt {
+        /// Paths to the templates to be formatted in place
+        #[arg(value_name = "template files", required = true)]
+        template_files: Vec<PathBuf>,
+        /// Do not change any files but fail if a template is not formatted
+        /// and list the paths of all such templates
+        #[arg(long)]
+        check: bool,
+    },
+    /// Fill out a template once for every combination of choices for its options.
+    /// Keys without a default are filled out with placeholders
+    Variants {
+        /// Path to the template
+        #[arg(value_name = "template file")]
+        template_file: PathBuf,
+        /// Path to a content state file to be used instead of the default one
+        #[arg(long = "content-state", short = 'C', value_name = "content state file")]
+        content_state_file: Option<PathBuf>,
+        /// Maximum number of variants to write
+        #[arg(long, short)]
+        limit: Option<usize>,
+        /// Only write this many randomly chosen variants
+        #[arg(long, short)]
+        sample: Option<usize>,
+        /// Seed used to choose the variants for `--sample`, so the same variants
+        /// are chosen every time
+        #[arg(long, requires = "sample")]
+        seed: Option<u64>,
+    },
+    /// Fill out a template with generated content to check its layout
+    /// without writing any content
+    Preview {
+        /// Path to the template
+        #[arg(value_name = "template file")]
+        template_file: PathBuf,
+        /// Path to a content state file to be used instead of the default one
+        #[arg(long = "content-state", short = 'C', value_name = "content state file")]
+        content_state_file: Option<PathBuf>,
+        /// Seed for generating the content. The same seed always
+        /// generates the same content
+        #[arg(long, short, default_value_t = 0)]
+        seed: u64,
+    },
+    /// Show how every element of a template is resolved: the chain of defaults
+    /// followed, the content used and any content shadowed by it
+    Explain {
+        /// Path to the template
+        #[arg(value_name = "template file")]
+        template_file: PathBuf,
+        /// Path to a content state file to be used instead of the default one
+        #[arg(long = "content-state", short = 'C', value_name = "content state file")]
+        content_state_file: Option<PathBuf>,
+        /// Path to a content file. Without it no content is given
+        #[arg(long = "content", short, value_name = "content file")]
+        content_file: Option<PathBuf>,
+        #[command(flatten)]
+        dyn_args: DynArgs,
+    },
+}
+
+// Parse a time given in RFC 3339 format, e.g. `2023-03-14T09:26:53+01:00`
+fn parse_time(s: &str) -> Result<DateTime<Utc>, chrono::ParseError> {
+    DateTime::parse_from_rfc3339(s).map(|time| time.with_timezone(&Utc))
+}
+
+// Get the path of the content state file: the passed one, the one set in
+// the environment or the default one in the home directory
+fn content_state_path(cs_file: &Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    if let Some(path) = cs_file {
+        return Ok(path.clone());
+    }
+    match env::var(CONTENT_STATE_FILE_ENV) {
+        Ok(file_name) => Ok(file_name.into()),
+        Err(_) => Ok(dirs::home_dir()
+            .context("Failed to get $HOME directory")?
+            .join(CONTENT_STATE_DEFAULT)),
+    }
+}
+
+// Get the path of the counter file, which is next to the content state file
+fn counters_path(cs_file: &Option<PathBuf>) -> anyhow::Result<PathBuf> {
+    Ok(content_state_path(cs_file)?.with_file_name(COUNTERS_FILE))
 }
 
 struct WithVolatileContentDraft(VolatileContent);
@@ -62,6 +220,7 @@ struct Inputs<S: InputState> {
     template: Template,
     cs: ContentState,
     settings: CompilationSettings,
+    counters: Counters,
     // State to store the volatile content once it was entered during runtime.
     vc: S,
 }
@@ -69,16 +228,14 @@ struct Inputs<S: InputState> {
 // Operations performed before getting the volatile content
 impl Inputs<WithVolatileContentDraft> {
     fn new(args: Args) -> anyhow::Result<Self> {
-        let template = Self::get_template(&args.template_file)?;
+        let template_file = args
+            .template_file
+            .context("Missing path to the template file")?;
+        let counters = Counters::new(counters_path(&args.content_state_file)?);
+        let mut settings = args.dyn_args.settings(&template_file);
+        settings.register(counters.clone());
+        let template = Self::get_template(&template_file, !args.no_cache, &settings)?;
         let cs = Self::get_content_state(&args.content_state_file)?;
-        let settings = {
-            // Set all settings
-            let mut settings = CompilationSettings::default();
-            if args.ignore_dyn {
-                settings.ignore_dynamics = true;
-            }
-            settings
-        };
 
         // Compute the volatile content draft
         let vc_draft = template.required().draft_volatile_content();
@@ -88,12 +245,18 @@ impl Inputs<WithVolatileContentDraft> {
             template,
             cs,
             settings,
+            counters,
             vc: vc_draft,
         })
     }
 
-    // Read and parse the given template file
-    fn get_template(template_file: &PathBuf) -> anyhow::Result<Template> {
+    // Read the given template file and parse it using the namespaces of `settings`.
+    // If `use_cache` is set, the template is only parsed if it is not cached yet.
+    fn get_template(
+        template_file: &PathBuf,
+        use_cache: bool,
+        settings: &CompilationSettings,
+    ) -> anyhow::Result<Template> {
         // Read the template
         let mut file =
             File::open(template_file).context("Failed to open the template source file")?;
@@ -103,7 +266,10 @@ impl Inputs<WithVolatileContentDraft> {
 
         log::trace!("Successfully read content of template file:\n{}", &buf);
 
-        let template = Template::parse(&buf).context("Parse error")?;
+        let template = match TemplateCache::new().filter(|_| use_cache) {
+            Some(cache) => cache.get_or_parse(&buf, settings)?,
+            None => Template::parse_with(&buf, settings).context("Parse error")?,
+        };
         log::trace!(
             "Successfully parsed content of template file into a valid template:\n{:?}",
             &template
@@ -116,35 +282,13 @@ impl Inputs<WithVolatileContentDraft> {
     // or the file set in CONTENT_STATE_FILE_ENV
     fn get_content_state(cs_file: &Option<PathBuf>) -> anyhow::Result<ContentState> {
         // Read the ContentState file
-        let mut file = if let Some(path) = cs_file {
-            File::open(path).with_context(|| {
-                format!(
-                    "Failed to open passed content state file {}",
-                    path.display()
-                )
-            })?
-        } else {
-            // Use environment or default because the user did not specify a path.
-            match env::var(CONTENT_STATE_FILE_ENV) {
-                Ok(file_name) => File::open(&file_name).with_context(|| {
-                    format!(
-                        "Failed to open file {} containing the content state",
-                        &file_name
-                    )
-                })?,
-                Err(_) => {
-                    let path = dirs::home_dir()
-                        .context("Failed to get $HOME directory")?
-                        .join(CONTENT_STATE_DEFAULT);
-                    File::open(path).with_context(|| {
-                        format!(
-                            "Failed to open default file {CONTENT_STATE_DEFAULT} \
-                            containing the content state"
-                        )
-                    })?
-                }
-            }
-        };
+        let path = content_state_path(cs_file)?;
+        let mut file = File::open(&path).with_context(|| {
+            format!(
+                "Failed to open file {} containing the content state",
+                path.display()
+            )
+        })?;
 
         let mut buf = String::new();
         file.read_to_string(&mut buf)
@@ -238,7 +382,7 @@ impl Inputs<WithVolatileContentDraft> {
                 const MAX_PREVIEW_LEN: usize = 31; // Maximum length of content preview
                 for (option, choices) in self.cs.options.iter() {
                     // Check the option is found in the tempalte before adding it
-                    if self.vc.0.choices.get(option).is_some() {
+                    if self.vc.0.choices.contains_key(option) {
                         let mut max_len = usize::MIN;
                         for choice in choices.keys() {
                             if option.len() + choice.len() > max_len {
@@ -337,6 +481,7 @@ impl Inputs<WithVolatileContentDraft> {
             template: self.template,
             cs: self.cs,
             settings: self.settings,
+            counters: self.counters,
             vc: WithVolatileContent(vc),
         })
     }
@@ -353,10 +498,166 @@ impl Inputs<WithVolatileContent> {
         log::trace!("Successfully filled out tempalte:\n{}", &result);
         Ok(result)
     }
+
+    // Fill out the template as far as possible and print the rest as template source
+    fn compile_partially(self) -> anyhow::Result<String> {
+        let result = self
+            .template
+            .with_settings(self.settings)
+            .partially_fill(&self.vc.0, &self.cs)?
+            .to_source()
+            .context("The partially filled out template can not be written as source")?;
+        log::trace!("Successfully filled out tempalte partially:\n{}", &result);
+        Ok(result)
+    }
+}
+
+// Format all given templates in place. If `check` is set, no files are
+// changed and an error is returned if any of the templates is not formatted.
+fn format_templates(template_files: &[PathBuf], check: bool) -> anyhow::Result<()> {
+    let mut unformatted: Vec<&Path> = vec![];
+    for path in template_files {
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read template {}", path.display()))?;
+        let formatted = SyntaxTree::parse(&source)
+            .with_context(|| format!("Failed to parse template {}", path.display()))?
+            .format();
+
+        if formatted != source {
+            if check {
+                unformatted.push(path);
+            } else {
+                fs::write(path, formatted)
+                    .with_context(|| format!("Failed to write template {}", path.display()))?;
+                log::trace!("Formatted template {}", path.display());
+            }
+        }
+    }
+
+    if !unformatted.is_empty() {
+        for path in &unformatted {
+            println!("{}", path.display());
+        }
+        anyhow::bail!("{} template(s) are not formatted", unformatted.len());
+    }
+    Ok(())
+}
+
+// Default settings with the counters registered, so `$Counter.<name>` is read as
+// one element and filled out with the next value of the counter, which is never stored.
+fn counter_settings(content_state_file: &Option<PathBuf>) -> anyhow::Result<CompilationSettings> {
+    let mut settings = CompilationSettings::default();
+    settings.register(Counters::new(counters_path(content_state_file)?));
+    Ok(settings)
+}
+
+// Write variants of a template to stdout, each one preceded by a comment
+// listing the choices which were made for it.
+fn write_variants(
+    template_file: &PathBuf,
+    content_state_file: &Option<PathBuf>,
+    use_cache: bool,
+    limit: Option<usize>,
+    sample: Option<usize>,
+    seed: Option<u64>,
+) -> anyhow::Result<()> {
+    let settings = counter_settings(content_state_file)?;
+    let template = Inputs::get_template(template_file, use_cache, &settings)?;
+    let cs = Inputs::get_content_state(content_state_file)?;
+    let template = template.with_settings(settings);
+    let variants = template.variants(&cs);
+    let total = variants.total();
+    let count = limit.unwrap_or(usize::MAX).min(sample.unwrap_or(usize::MAX)).min(total);
+    let total_text = match variants.exact_total() {
+        Some(total) => total.to_string(),
+        None => format!("more than {}", usize::MAX),
+    };
+
+    // Indices of the variants to write in ascending order. Without a sample they
+    // are not collected, as there can be far too many to keep all of them in memory.
+    let indices: Box<dyn Iterator<Item = usize>> = match sample {
+        Some(_) => {
+            let mut rng = match seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            };
+            let mut indices = rand::seq::index::sample(&mut rng, total, count).into_vec();
+            indices.sort_unstable();
+            Box::new(indices.into_iter())
+        }
+        None => Box::new(0..count),
+    };
+    log::trace!("Writing {count} of {total_text} variants");
+
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+    for index in indices {
+        let variant = variants.variant(index).context("Variant out of range")?;
+        let choices: Vec<String> = variant
+            .choices
+            .iter()
+            .map(|(option, choice)| format!("{option}: {}", choice.unwrap_or("(default)")))
+            .collect();
+        writeln!(
+            handle,
+            "# Variant {} of {total_text}: {}",
+            index + 1,
+            choices.join(", ")
+        )?;
+        match variant.output {
+            Ok(output) => writeln!(handle, "{output}\n")?,
+            Err(e) => writeln!(handle, "# Failed to fill out this variant: {e}\n")?,
+        }
+    }
+    Ok(())
+}
+
+// Write a preview of a template filled out with generated content to stdout.
+fn write_preview(
+    template_file: &PathBuf,
+    content_state_file: &Option<PathBuf>,
+    use_cache: bool,
+    seed: u64,
+) -> anyhow::Result<()> {
+    let settings = counter_settings(content_state_file)?;
+    let template = Inputs::get_template(template_file, use_cache, &settings)?;
+    let cs = Inputs::get_content_state(content_state_file)?;
+    let preview = template.with_settings(settings).preview(&cs, seed)?;
+    print!("{preview}");
+    Ok(())
+}
+
+// Write how every element of a template is resolved to stdout.
+fn write_explanation(
+    template_file: &PathBuf,
+    content_state_file: &Option<PathBuf>,
+    use_cache: bool,
+    content_file: &Option<PathBuf>,
+    dyn_args: DynArgs,
+) -> anyhow::Result<()> {
+    let mut settings = dyn_args.settings(template_file);
+    // Counters are shown with their next values but never stored.
+    settings.register(Counters::new(counters_path(content_state_file)?));
+    let template = Inputs::get_template(template_file, use_cache, &settings)?;
+    let cs = Inputs::get_content_state(content_state_file)?;
+    let vc: VolatileContent = match content_file {
+        Some(path) => {
+            let buf = fs::read_to_string(path).with_context(|| {
+                format!("Failed to read passed content file {}", path.display())
+            })?;
+            serde_yaml::from_str(&buf)?
+        }
+        None => VolatileContent::new(),
+    };
+    let trace = template.with_settings(settings).explain(&vc, &cs);
+    print!("{trace}");
+    Ok(())
 }
 
 fn main() {
-    env_logger::init();
+    // Show warnings, e.g. about constants shadowed by dynamic elements, unless
+    // `RUST_LOG` asks for something else.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
 
     let args = Args::parse();
     log::trace!(
@@ -364,7 +665,56 @@ fn main() {
         &args
     );
 
+    if let Some(command) = args.command {
+        let use_cache = !args.no_cache;
+        match command {
+            Subcommands::Fmt {
+                template_files,
+                check,
+            } => format_templates(&template_files, check).giveup("Failed to format templates"),
+            Subcommands::Variants {
+                template_file,
+                content_state_file,
+                limit,
+                sample,
+                seed,
+            } => write_variants(
+                &template_file,
+                &content_state_file,
+                use_cache,
+                limit,
+                sample,
+                seed,
+            )
+            .giveup("Failed to write variants"),
+            Subcommands::Preview {
+                template_file,
+                content_state_file,
+                seed,
+            } => write_preview(&template_file, &content_state_file, use_cache, seed)
+                .giveup("Failed to preview template"),
+            Subcommands::Explain {
+                template_file,
+                content_state_file,
+                content_file,
+                dyn_args,
+            } => write_explanation(
+                &template_file,
+                &content_state_file,
+                use_cache,
+                &content_file,
+                dyn_args,
+            )
+            .giveup("Failed to explain template"),
+        }
+        return;
+    }
+
     let draft = args.draft; // Copy the value of the draft flag.
+    // Copy the value of the dry run flag. Filling out a template at another
+    // time reproduces earlier output, which must not use up new numbers.
+    let dry_run = args.dry_run || args.dyn_args.now.is_some();
+    let partial = args.partial; // Copy the value of the partial flag.
     let content_file = args.content_file.clone(); // Clone the content file path.
 
     // Consume the rest of the arguments.
@@ -378,12 +728,22 @@ fn main() {
             .prepare_volatile_content_file(handle)
             .giveup("Failed to draft content file");
     } else {
-        // Compile the template as usual
-        let result = inputs
+        let inputs = inputs
             .get_volatile_content(&content_file)
-            .giveup("Failed to get new content")
-            .compile()
-            .giveup("Failed to compile template");
+            .giveup("Failed to get new content");
+        let counters = inputs.counters.clone();
+        let result = if partial {
+            inputs
+                .compile_partially()
+                .giveup("Failed to fill out template partially")
+        } else {
+            // Compile the template as usual
+            inputs.compile().giveup("Failed to compile template")
+        };
+        // Only counters of templates which were filled out successfully count.
+        if !dry_run {
+            counters.commit().giveup("Failed to store counters");
+        }
 
         print!("{result}");
     }
@@ -0,0 +1,37 @@
+// Make assertions on the behaviour of the binary
+use assert_cmd::Command;
+use std::{fs, path::PathBuf};
+
+// New, empty directory for the test `name`
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("txttc-cli-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn counters_are_previewed_without_being_stored() {
+    let dir = test_dir("preview");
+    let template = dir.join("invoice.txtt");
+    let content_state = dir.join(".template_content_state.yaml");
+    fs::write(&template, "Invoice No. $Counter.invoice for {name}").unwrap();
+    fs::write(&content_state, "constants: {}\noptions: {}\n").unwrap();
+    fs::write(dir.join(".template_counters.yaml"), "invoice: {value: 41}\n").unwrap();
+
+    let output = Command::cargo_bin("txttc")
+        .unwrap()
+        .arg("preview")
+        .arg(&template)
+        .arg("--content-state")
+        .arg(&content_state)
+        .arg("--no-cache")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let preview = String::from_utf8(output.stdout).unwrap();
+    assert!(preview.starts_with("Invoice No. 42 for "), "{preview}");
+
+    let counters = fs::read_to_string(dir.join(".template_counters.yaml")).unwrap();
+    assert_eq!(counters, "invoice: {value: 41}\n");
+}